@@ -183,8 +183,8 @@ export class VideoPlayer extends LitElement {
     show: { type: Object },
     season: { type: Object },
     episode: { type: Object },
-    url: { type: String },
-    mimeType: { type: String },
+    parts: { type: Array },
+    partIndex: { state: true },
     duration: { type: Number },
   };
 
@@ -198,6 +198,8 @@ export class VideoPlayer extends LitElement {
     this.isCasting = false;
     this.videoElement = null;
     this.castSession = null;
+    this.parts = [];
+    this.partIndex = 0;
 
     this.seekBack30 = (event) => this.seekPressed(event, -30);
     this.seekBack10 = (event) => this.seekPressed(event, -10);
@@ -207,6 +209,23 @@ export class VideoPlayer extends LitElement {
     this.addEventListener("fullscreenchange", () => this.onFullscreenChanged());
   }
 
+  get part() {
+    return this.parts[this.partIndex];
+  }
+
+  partForTime(time) {
+    return Math.max(
+      this.parts.findLastIndex((part) => part.offset <= time),
+      0,
+    );
+  }
+
+  willUpdate(changed) {
+    if (changed.has("parts")) {
+      this.partIndex = this.partForTime(this.currentTime);
+    }
+  }
+
   initCast = () => {
     this.isCastAvailable = true;
 
@@ -226,7 +245,7 @@ export class VideoPlayer extends LitElement {
 
   castControllerEventListener = (event) => {
     this.isPlaying = !this.castPlayer.isPaused;
-    this.updateTime(this.castPlayer.currentTime);
+    this.updateTime(this.part.offset + this.castPlayer.currentTime);
   };
 
   async updateCastSession(session) {
@@ -272,7 +291,7 @@ export class VideoPlayer extends LitElement {
 
       let castMedia = session.getMediaSession();
       let mediaInfo = castMedia?.media;
-      let videoUrl = new URL(this.url, document.documentURI);
+      let videoUrl = new URL(this.part.url, document.documentURI);
 
       // Check if the current video is already playing
       if (mediaInfo?.contentUrl == videoUrl.toString()) {
@@ -285,13 +304,7 @@ export class VideoPlayer extends LitElement {
         );
       } else {
         // Otherwise load it
-        let loadRequest = new chrome.cast.media.LoadRequest(
-          this.castMediaInfo(),
-        );
-        loadRequest.currentTime = this.currentTime;
-        loadRequest.autoplay = this.isPlaying;
-
-        await this.castSession.loadMedia(loadRequest);
+        await this.loadCastMedia();
       }
     } else {
       this.castPlayer = null;
@@ -305,6 +318,14 @@ export class VideoPlayer extends LitElement {
     }
   }
 
+  async loadCastMedia() {
+    let loadRequest = new chrome.cast.media.LoadRequest(this.castMediaInfo());
+    loadRequest.currentTime = this.currentTime - this.part.offset;
+    loadRequest.autoplay = this.isPlaying;
+
+    await this.castSession.loadMedia(loadRequest);
+  }
+
   renderedVideo(element) {
     if (this.videoElement != element) {
       this.videoElement?.pause();
@@ -313,7 +334,7 @@ export class VideoPlayer extends LitElement {
     this.videoElement = element;
 
     if (this.videoElement) {
-      this.videoElement.currentTime = this.currentTime;
+      this.videoElement.currentTime = this.currentTime - this.part.offset;
       if (this.isPlaying) {
         this.videoElement.play();
       } else {
@@ -429,24 +450,30 @@ export class VideoPlayer extends LitElement {
     }
 
     this.isPlaying = !(this.videoElement.paused || this.videoElement.ended);
-    this.updateTime(this.videoElement.currentTime);
+    this.updateTime(this.part.offset + this.videoElement.currentTime);
   }
 
   onMediaEnded() {
     this.onMediaStateChanged();
+
+    if (this.partIndex < this.parts.length - 1) {
+      this.partIndex++;
+      this.currentTime = this.part.offset;
+      this.isPlaying = true;
+    }
   }
 
   castMediaInfo() {
     let videoUrl = new URL(document.documentURI);
-    let contentUrl = new URL(this.url, document.documentURI);
+    let contentUrl = new URL(this.part.url, document.documentURI);
 
     let mediaInfo = new chrome.cast.media.MediaInfo(
       videoUrl.pathname,
-      this.mimeType,
+      this.part.mimeType,
     );
 
     mediaInfo.contentUrl = contentUrl.toString();
-    mediaInfo.duration = this.duration / 1000;
+    mediaInfo.duration = this.part.duration;
 
     let metadata;
 
@@ -475,11 +502,19 @@ export class VideoPlayer extends LitElement {
 
     this.currentTime = targetTime;
 
-    if (this.isCasting) {
-      this.castPlayer.currentTime = targetTime;
+    let partIndex = this.partForTime(targetTime);
+    if (partIndex != this.partIndex) {
+      // Switching part re-renders the video at the new time.
+      this.partIndex = partIndex;
+
+      if (this.isCasting) {
+        await this.loadCastMedia();
+      }
+    } else if (this.isCasting) {
+      this.castPlayer.currentTime = targetTime - this.part.offset;
       this.castController.seek();
     } else {
-      this.videoElement.currentTime = targetTime;
+      this.videoElement.currentTime = targetTime - this.part.offset;
     }
   }
 
@@ -506,7 +541,7 @@ export class VideoPlayer extends LitElement {
     let templates = [];
     for (let i = 0; i < ranges.length; i++) {
       let width = (100 * (ranges.end(i) - ranges.start(i))) / this.duration;
-      let left = (100 * ranges.start(i)) / this.duration;
+      let left = (100 * (this.part.offset + ranges.start(i))) / this.duration;
 
       templates.push(
         html`<div
@@ -547,7 +582,7 @@ export class VideoPlayer extends LitElement {
       return nothing;
     }
 
-    let mediaUrl = new URL(this.url, document.documentURI);
+    let mediaUrl = new URL(this.part.url, document.documentURI);
     mediaUrl = new URL(mediaUrl.pathname, document.documentURI);
 
     return keyed(
@@ -563,7 +598,7 @@ export class VideoPlayer extends LitElement {
         @timeupdate="${this.onMediaStateChanged}"
         @seeked="${this.onMediaStateChanged}"
       >
-        <source type="${this.mimeType}" src="${mediaUrl.toString()}"></source>
+        <source type="${this.part.mimeType}" src="${mediaUrl.toString()}"></source>
       </video>`,
     );
   }
@@ -1,5 +1,5 @@
 use clap::{Args, builder::TypedValueParser};
-use flick_sync::{FlickSync, OutputStyle};
+use flick_sync::{FlickSync, OutputStyle, OutputTemplates};
 use tracing::instrument;
 
 use crate::{Result, Runnable, console::Console};
@@ -8,16 +8,45 @@ use crate::{Result, Runnable, console::Console};
 pub struct SetOutputStyle {
     /// The output style to use.
     #[arg(
-        value_parser = clap::builder::PossibleValuesParser::new(["minimal", "standardized"])
+        value_parser = clap::builder::PossibleValuesParser::new(["minimal", "standardized", "template"])
             .map(|s| s.parse::<OutputStyle>().unwrap()),
     )]
     style: OutputStyle,
+    /// The template for movie files, used with the template style.
+    #[arg(long)]
+    movie: Option<String>,
+    /// The template for episode files, used with the template style.
+    #[arg(long)]
+    episode: Option<String>,
+    /// The template for thumbnails, used with the template style.
+    #[arg(long)]
+    thumbnail: Option<String>,
+    /// The template for metadata files, used with the template style.
+    #[arg(long)]
+    metadata: Option<String>,
+    /// The template for playlists, used with the template style.
+    #[arg(long)]
+    playlist: Option<String>,
 }
 
 impl Runnable for SetOutputStyle {
     #[instrument(name = "SetOutputStyle", skip_all)]
     async fn run(self, flick_sync: FlickSync, _console: Console) -> Result {
-        flick_sync.update_output_style(self.style).await?;
+        let templates = if self.style == OutputStyle::Template {
+            Some(OutputTemplates {
+                movie: self.movie,
+                episode: self.episode,
+                thumbnail: self.thumbnail,
+                metadata: self.metadata,
+                playlist: self.playlist,
+            })
+        } else {
+            None
+        };
+
+        flick_sync
+            .update_output_style(self.style, templates)
+            .await?;
         flick_sync.prune_root().await;
 
         Ok(())
@@ -13,7 +13,7 @@ use dlna_server::{
 };
 use flick_sync::{
     Collection, FlickSync, Library, LockedFile, MovieCollection, MovieLibrary, Playlist, Season,
-    Server, Show, ShowCollection, ShowLibrary, Timeout, Video,
+    Server, Show, ShowCollection, ShowLibrary, Timeout, Video, VideoPart,
 };
 use image::ImageReader;
 use lazy_static::lazy_static;
@@ -29,7 +29,7 @@ use tracing::{Instrument, Level, Span, instrument, span};
 use crate::{Resources, shared::uniform_title};
 
 lazy_static! {
-    static ref RE_VIDEO: Regex = Regex::new("^video/(.+)/V:(.+)$").unwrap();
+    static ref RE_VIDEO: Regex = Regex::new("^video/(.+)/V:([^/]+)(?:/(\\d+))?$").unwrap();
 }
 
 #[pin_project(project = EitherReaderProj)]
@@ -58,17 +58,23 @@ struct ProgressReader<R> {
     span: Option<Span>,
     position: u64,
     video: Video,
+    /// The user whose profile the video is being played from.
+    user: Option<String>,
+    /// The position of the start of the part within the video.
+    offset: u64,
     secs_per_byte: f64,
     last_report: u64,
 }
 
 impl<R> ProgressReader<R> {
-    fn new(inner: R, video: Video, secs_per_byte: f64) -> Self {
+    fn new(inner: R, video: Video, user: Option<String>, offset: u64, secs_per_byte: f64) -> Self {
         Self {
             inner,
             span: None,
             position: 0,
             video,
+            user,
+            offset,
             secs_per_byte,
             last_report: 0,
         }
@@ -100,10 +106,12 @@ impl<R: AsyncRead> AsyncRead for ProgressReader<R> {
             if new_position.abs_diff(*this.last_report) > 15000 {
                 *this.last_report = new_position;
                 let video = this.video.clone();
+                let user = this.user.clone();
+                let position = *this.offset + new_position;
                 let span = span.clone();
                 spawn(async move {
                     let _ = video
-                        .set_playback_position(new_position)
+                        .set_playback_position(user.as_deref(), position)
                         .instrument(span)
                         .await;
                 });
@@ -124,15 +132,25 @@ impl<R: AsyncSeek> AsyncSeek for ProgressReader<R> {
     }
 }
 
-async fn video_from_id(flick_sync: &FlickSync, id: &str) -> Option<Video> {
+async fn part_from_id(flick_sync: &FlickSync, id: &str) -> Option<VideoPart> {
     let captures = RE_VIDEO.captures(id)?;
 
     let server_id = captures.get(1).unwrap().as_str();
     let video_id = captures.get(2).unwrap().as_str();
+    let index: usize = match captures.get(3) {
+        Some(index) => index.as_str().parse().ok()?,
+        None => 0,
+    };
 
     let server = flick_sync.server(server_id).await?;
 
-    server.video(video_id).await
+    server
+        .video(video_id)
+        .await?
+        .parts()
+        .await
+        .into_iter()
+        .nth(index)
 }
 
 // Object ID forms and hierarchy:
@@ -142,6 +160,7 @@ async fn video_from_id(flick_sync: &FlickSync, id: &str) -> Option<Video> {
 //   L                       - Libraries
 //     <server>/L:<id>       - Library
 //       <server>/V:<id>     - Movie
+//         <server>/T:<id>:<part> - Part of a multi-part movie
 //       <server>/S:<id>     - Show
 //         <server>/N:<id>   - Season
 //           <server>/V:<id> - Episode
@@ -152,6 +171,42 @@ async fn video_from_id(flick_sync: &FlickSync, id: &str) -> Option<Video> {
 //   P                       - Playlists
 //     <server>/P:<id>       - Playlist
 //       <server>/V:<id>     - Video
+//   U:<user>                - Profile of an additional user
+//     U:<user>/<id>         - Any of the above objects, played as the user
+//
+// Resource IDs are scoped to a profile in the same way.
+
+/// Splits the user's profile from an object or resource ID.
+fn split_user(id: &str) -> (Option<&str>, &str) {
+    if let Some(scoped) = id.strip_prefix("U:")
+        && let Some((user, id)) = scoped.split_once('/')
+    {
+        (Some(user), id)
+    } else {
+        (None, id)
+    }
+}
+
+/// Moves an object into a user's profile.
+fn scope_object(object: &mut Object, user: &str) {
+    let (id, parent_id) = match object {
+        Object::Container(container) => (&mut container.id, &mut container.parent_id),
+        Object::Item(item) => {
+            for resource in item.resources.iter_mut() {
+                resource.id = format!("U:{user}/{}", resource.id);
+            }
+
+            (&mut item.id, &mut item.parent_id)
+        }
+    };
+
+    *id = format!("U:{user}/{id}");
+    *parent_id = if parent_id == "0" {
+        format!("U:{user}")
+    } else {
+        format!("U:{user}/{parent_id}")
+    };
+}
 
 fn object_title(obj: &Object) -> String {
     match obj {
@@ -185,7 +240,7 @@ async fn icon_resource(id: &str, file: Result<Option<LockedFile>, Timeout>) -> O
 }
 
 async fn file_resource(
-    video: &Video,
+    part: &VideoPart,
     file: Result<Option<LockedFile>, Timeout>,
 ) -> Option<Resource> {
     let file = file.ok()??;
@@ -194,9 +249,14 @@ async fn file_resource(
     let mime_type = file.mime_type().await.ok()?;
 
     Some(Resource {
-        id: format!("video/{}/V:{}", video.server().id(), video.id()),
+        id: format!(
+            "video/{}/V:{}/{}",
+            part.server().id(),
+            part.id(),
+            part.index()
+        ),
         mime_type,
-        duration: Some(video.duration().await),
+        duration: Some(part.duration().await),
         size: Some(size),
         seekable: true,
     })
@@ -261,15 +321,29 @@ impl FromId for Video {
 }
 
 impl ToObject for Video {
-    type Children = Object;
+    type Children = VideoPart;
 
     async fn to_object(self) -> Object {
+        let id = format!("{}/V:{}", self.server().id(), self.id());
+        let parts = self.parts().await;
+
+        if parts.len() > 1 {
+            return Object::Container(Container {
+                thumbnail: icon_resource(&id, self.thumbnail().await).await,
+                id,
+                parent_id: video_parent(&self).await,
+                child_count: Some(parts.len()),
+                title: self.title().await,
+            });
+        }
+
         let mut resources = Vec::new();
-        if let Some(resource) = file_resource(&self, self.file().await).await {
-            resources.push(resource);
+        for part in parts {
+            if let Some(resource) = file_resource(&part, part.file().await).await {
+                resources.push(resource);
+            }
         }
 
-        let id = format!("{}/V:{}", self.server().id(), self.id());
         Object::Item(Item {
             thumbnail: icon_resource(&id, self.thumbnail().await).await,
             id,
@@ -279,6 +353,55 @@ impl ToObject for Video {
         })
     }
 
+    async fn to_children(self) -> Vec<Self::Children> {
+        let parts = self.parts().await;
+
+        if parts.len() > 1 { parts } else { Vec::new() }
+    }
+
+    fn sort_children(_: &mut Vec<Object>) {}
+}
+
+impl FromId for VideoPart {
+    async fn from_id(server: Server, id: &str) -> Result<Self, UpnpError> {
+        let (video_id, index) = id.split_once(':').ok_or(UpnpError::unknown_object())?;
+        let index: usize = index.parse().map_err(|_| UpnpError::unknown_object())?;
+
+        let video = server
+            .video(video_id)
+            .await
+            .ok_or(UpnpError::unknown_object())?;
+
+        video
+            .parts()
+            .await
+            .into_iter()
+            .nth(index)
+            .ok_or(UpnpError::unknown_object())
+    }
+}
+
+impl ToObject for VideoPart {
+    type Children = Object;
+
+    async fn to_object(self) -> Object {
+        let video = self.video().await;
+        let video_id = format!("{}/V:{}", video.server().id(), video.id());
+
+        let mut resources = Vec::new();
+        if let Some(resource) = file_resource(&self, self.file().await).await {
+            resources.push(resource);
+        }
+
+        Object::Item(Item {
+            thumbnail: icon_resource(&video_id, video.thumbnail().await).await,
+            id: format!("{}/T:{}:{}", video.server().id(), video.id(), self.index()),
+            parent_id: video_id,
+            title: format!("{} (Part {})", video.title().await, self.index() + 1),
+            resources,
+        })
+    }
+
     async fn to_children(self) -> Vec<Self::Children> {
         Vec::new()
     }
@@ -571,16 +694,85 @@ impl ToObject for Root {
         Object::Container(Container {
             id: "0".to_string(),
             parent_id: "-1".to_string(),
-            child_count: Some(3),
+            child_count: Some(4 + self.flick_sync.users().await.len()),
             title: "Flick Sync Synced Media".to_string(),
             thumbnail: None,
         })
     }
 
+    async fn to_children(self) -> Vec<Self::Children> {
+        let mut children = vec![
+            OnDeck {
+                flick_sync: self.flick_sync.clone(),
+                user: None,
+            }
+            .to_object()
+            .await,
+            Libraries {
+                flick_sync: self.flick_sync.clone(),
+            }
+            .to_object()
+            .await,
+            Collections {
+                flick_sync: self.flick_sync.clone(),
+            }
+            .to_object()
+            .await,
+            Playlists {
+                flick_sync: self.flick_sync.clone(),
+            }
+            .to_object()
+            .await,
+        ];
+
+        for user in self.flick_sync.users().await {
+            children.push(
+                Profile {
+                    flick_sync: self.flick_sync.clone(),
+                    user,
+                }
+                .to_object()
+                .await,
+            );
+        }
+
+        children
+    }
+
+    fn sort_children(_: &mut Vec<Object>) {}
+}
+
+/// The root of an additional user's profile. Its children are unscoped and
+/// must be moved into the profile with `scope_object`.
+struct Profile {
+    flick_sync: FlickSync,
+    user: String,
+}
+
+impl ToObject for Profile {
+    type Children = Object;
+
+    async fn to_object(self) -> Object {
+        Object::Container(Container {
+            id: format!("U:{}", self.user),
+            parent_id: "0".to_string(),
+            child_count: Some(4),
+            title: self.user,
+            thumbnail: Some(Icon {
+                id: "resource/logo-256.png".to_string(),
+                mime_type: mime::IMAGE_PNG,
+                width: 256,
+                height: 256,
+                depth: 32,
+            }),
+        })
+    }
+
     async fn to_children(self) -> Vec<Self::Children> {
         vec![
             OnDeck {
                 flick_sync: self.flick_sync.clone(),
+                user: Some(self.user),
             }
             .to_object()
             .await,
@@ -607,6 +799,7 @@ impl ToObject for Root {
 
 struct OnDeck {
     flick_sync: FlickSync,
+    user: Option<String>,
 }
 
 impl ToObject for OnDeck {
@@ -616,7 +809,7 @@ impl ToObject for OnDeck {
         Object::Container(Container {
             id: "O".to_string(),
             parent_id: "0".to_string(),
-            child_count: Some(self.flick_sync.on_deck().await.len()),
+            child_count: Some(self.flick_sync.on_deck(self.user.as_deref()).await.len()),
             title: "On Deck".to_string(),
             thumbnail: Some(Icon {
                 id: "resource/logo-256.png".to_string(),
@@ -629,7 +822,7 @@ impl ToObject for OnDeck {
     }
 
     async fn to_children(self) -> Vec<Self::Children> {
-        self.flick_sync.on_deck().await
+        self.flick_sync.on_deck(self.user.as_deref()).await
     }
 }
 
@@ -763,11 +956,36 @@ impl DlnaHandler {
 
         Some((server, item_type, item_id))
     }
-}
 
-#[async_trait]
-impl DlnaRequestHandler for DlnaHandler {
-    async fn get_object(&self, object_id: &str) -> Result<Object, UpnpError> {
+    /// Splits the profile from an ID, verifying that the user exists.
+    async fn extract_user<'a>(&self, id: &'a str) -> Result<(Option<&'a str>, &'a str), UpnpError> {
+        let (user, id) = split_user(id);
+
+        if let Some(user) = user
+            && !self.flick_sync.users().await.iter().any(|u| u == user)
+        {
+            return Err(UpnpError::unknown_object());
+        }
+
+        Ok((user, id))
+    }
+
+    async fn profile(&self, object_id: &str) -> Result<Option<Profile>, UpnpError> {
+        let Some(user) = object_id.strip_prefix("U:") else {
+            return Ok(None);
+        };
+
+        if !self.flick_sync.users().await.iter().any(|u| u == user) {
+            return Err(UpnpError::unknown_object());
+        }
+
+        Ok(Some(Profile {
+            flick_sync: self.flick_sync.clone(),
+            user: user.to_owned(),
+        }))
+    }
+
+    async fn object(&self, user: Option<&str>, object_id: &str) -> Result<Object, UpnpError> {
         if object_id == "0" {
             Ok(Root {
                 flick_sync: self.flick_sync.clone(),
@@ -777,6 +995,7 @@ impl DlnaRequestHandler for DlnaHandler {
         } else if object_id == "O" {
             Ok(OnDeck {
                 flick_sync: self.flick_sync.clone(),
+                user: user.map(str::to_owned),
             }
             .to_object()
             .await)
@@ -813,12 +1032,17 @@ impl DlnaRequestHandler for DlnaHandler {
                 "S" => Ok(Show::from_id(server, item_id).await?.to_object().await),
                 "N" => Ok(Season::from_id(server, item_id).await?.to_object().await),
                 "V" => Ok(Video::from_id(server, item_id).await?.to_object().await),
+                "T" => Ok(VideoPart::from_id(server, item_id).await?.to_object().await),
                 _ => Err(UpnpError::unknown_object()),
             }
         }
     }
 
-    async fn list_children(&self, object_id: &str) -> Result<Vec<Object>, UpnpError> {
+    async fn children(
+        &self,
+        user: Option<&str>,
+        object_id: &str,
+    ) -> Result<Vec<Object>, UpnpError> {
         if object_id == "0" {
             Ok(Root {
                 flick_sync: self.flick_sync.clone(),
@@ -828,6 +1052,7 @@ impl DlnaRequestHandler for DlnaHandler {
         } else if object_id == "O" {
             Ok(OnDeck {
                 flick_sync: self.flick_sync.clone(),
+                user: user.map(str::to_owned),
             }
             .collect_children()
             .await)
@@ -879,10 +1104,53 @@ impl DlnaRequestHandler for DlnaHandler {
                     .await?
                     .collect_children()
                     .await),
+                "T" => Ok(VideoPart::from_id(server, item_id)
+                    .await?
+                    .collect_children()
+                    .await),
                 _ => Err(UpnpError::unknown_object()),
             }
         }
     }
+}
+
+#[async_trait]
+impl DlnaRequestHandler for DlnaHandler {
+    async fn get_object(&self, object_id: &str) -> Result<Object, UpnpError> {
+        if let Some(profile) = self.profile(object_id).await? {
+            return Ok(profile.to_object().await);
+        }
+
+        let (user, object_id) = self.extract_user(object_id).await?;
+        let mut object = self.object(user, object_id).await?;
+
+        if let Some(user) = user {
+            scope_object(&mut object, user);
+        }
+
+        Ok(object)
+    }
+
+    async fn list_children(&self, object_id: &str) -> Result<Vec<Object>, UpnpError> {
+        let (user, mut children) = if let Some(profile) = self.profile(object_id).await? {
+            let user = profile.user.clone();
+            (Some(user), profile.collect_children().await)
+        } else {
+            let (user, object_id) = self.extract_user(object_id).await?;
+            (
+                user.map(str::to_owned),
+                self.children(user, object_id).await?,
+            )
+        };
+
+        if let Some(user) = user {
+            for child in children.iter_mut() {
+                scope_object(child, &user);
+            }
+        }
+
+        Ok(children)
+    }
 
     async fn stream_icon(
         &self,
@@ -941,11 +1209,12 @@ impl DlnaRequestHandler for DlnaHandler {
     }
 
     async fn get_resource(&self, resource_id: &str) -> Result<Resource, UpnpError> {
-        let Some(video) = video_from_id(&self.flick_sync, resource_id).await else {
+        let (_, part_id) = self.extract_user(resource_id).await?;
+        let Some(part) = part_from_id(&self.flick_sync, part_id).await else {
             return Err(UpnpError::unknown_object());
         };
 
-        let Ok(Some(file)) = video.file().await else {
+        let Ok(Some(file)) = part.file().await else {
             return Err(UpnpError::unknown_object());
         };
 
@@ -971,11 +1240,12 @@ impl DlnaRequestHandler for DlnaHandler {
         &self,
         resource_id: &str,
     ) -> Result<impl AsyncRead + AsyncSeek + Unpin + 'static, UpnpError> {
-        let Some(video) = video_from_id(&self.flick_sync, resource_id).await else {
+        let (user, part_id) = self.extract_user(resource_id).await?;
+        let Some(part) = part_from_id(&self.flick_sync, part_id).await else {
             return Err(UpnpError::unknown_object());
         };
 
-        let Ok(Some(file)) = video.file().await else {
+        let Ok(Some(file)) = part.file().await else {
             return Err(UpnpError::unknown_object());
         };
 
@@ -987,10 +1257,17 @@ impl DlnaRequestHandler for DlnaHandler {
             return Err(UpnpError::unknown_object());
         };
 
-        let part_duration = video.duration().await;
+        let part_duration = part.duration().await;
         let secs_per_byte = part_duration.as_millis() as f64 / size as f64;
+        let offset = part.offset().await.as_millis() as u64;
 
-        let progress_reader = ProgressReader::new(reader, video, secs_per_byte);
+        let progress_reader = ProgressReader::new(
+            reader,
+            part.video().await,
+            user.map(str::to_owned),
+            offset,
+            secs_per_byte,
+        );
 
         Ok(progress_reader)
     }
@@ -0,0 +1,143 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail};
+use clap::{Args, builder::TypedValueParser};
+use flick_sync::{FlickSync, OutputStyle, Server, Video};
+use tracing::instrument;
+
+use crate::{Result, Runnable, console::Console};
+
+#[derive(Args)]
+pub struct AddExport {
+    /// The directory to export downloaded videos to.
+    path: PathBuf,
+    /// The output style to lay out the exported files in.
+    #[arg(
+        long,
+        default_value = "standardized",
+        value_parser = clap::builder::PossibleValuesParser::new(["minimal", "standardized", "template"])
+            .map(|s| s.parse::<OutputStyle>().unwrap()),
+    )]
+    style: OutputStyle,
+}
+
+impl Runnable for AddExport {
+    #[instrument(name = "AddExport", skip_all)]
+    async fn run(self, flick_sync: FlickSync, _console: Console) -> Result {
+        flick_sync.add_export(&self.path, self.style).await
+    }
+}
+
+#[derive(Args)]
+pub struct RemoveExport {
+    /// The export directory to remove. Files previously exported there are
+    /// deleted.
+    path: PathBuf,
+}
+
+impl Runnable for RemoveExport {
+    #[instrument(name = "RemoveExport", skip_all)]
+    async fn run(self, flick_sync: FlickSync, _console: Console) -> Result {
+        if !flick_sync.remove_export(&self.path).await? {
+            bail!("Unknown export directory: {}", self.path.display());
+        }
+
+        Ok(())
+    }
+}
+
+/// Finds the videos for an item, which may be a video, season, show or
+/// playlist.
+async fn item_videos(server: &Server, id: &str) -> Option<Vec<Video>> {
+    if let Some(video) = server.video(id).await {
+        return Some(vec![video]);
+    }
+
+    if let Some(season) = server.season(id).await {
+        return Some(
+            season
+                .episodes()
+                .await
+                .into_iter()
+                .map(Video::Episode)
+                .collect(),
+        );
+    }
+
+    if let Some(show) = server.show(id).await {
+        let mut videos = Vec::new();
+        for season in show.seasons().await {
+            videos.extend(season.episodes().await.into_iter().map(Video::Episode));
+        }
+
+        return Some(videos);
+    }
+
+    if let Some(playlist) = server.playlist(id).await {
+        return Some(playlist.videos().await);
+    }
+
+    None
+}
+
+#[derive(Args)]
+pub struct ExportDrive {
+    /// The server the items are from.
+    server: String,
+    /// The removable drive to copy to.
+    path: PathBuf,
+    /// The ids of the videos, seasons, shows or playlists to copy. Anything
+    /// previously copied to the drive that is not listed is removed.
+    #[clap(required = true)]
+    ids: Vec<String>,
+    /// The output style to lay out the copied files in.
+    #[arg(
+        long,
+        default_value = "standardized",
+        value_parser = clap::builder::PossibleValuesParser::new(["minimal", "standardized", "template"])
+            .map(|s| s.parse::<OutputStyle>().unwrap()),
+    )]
+    style: OutputStyle,
+}
+
+impl Runnable for ExportDrive {
+    #[instrument(name = "ExportDrive", skip_all)]
+    async fn run(self, flick_sync: FlickSync, _console: Console) -> Result {
+        let server = flick_sync
+            .server(&self.server)
+            .await
+            .ok_or_else(|| anyhow!("Unknown server: {}", self.server))?;
+
+        let mut videos = Vec::new();
+        for id in &self.ids {
+            let Some(item_videos) = item_videos(&server, id).await else {
+                bail!("Unknown item: {id}");
+            };
+
+            videos.extend(item_videos);
+        }
+
+        flick_sync
+            .export_drive(&self.path, &videos, self.style)
+            .await
+    }
+}
+
+#[derive(Args)]
+pub struct ImportDrive {
+    /// The removable drive to merge playback progress from.
+    path: PathBuf,
+}
+
+impl Runnable for ImportDrive {
+    #[instrument(name = "ImportDrive", skip_all)]
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        if flick_sync.import_drive(&self.path).await? {
+            console.println("Merged playback progress from the drive.");
+        } else {
+            console.println("No playback progress to merge.");
+        }
+
+        Ok(())
+    }
+}
@@ -30,6 +30,8 @@ use tracing_subscriber::{
 mod config;
 mod console;
 mod dlna;
+mod export;
+mod playback;
 mod serve;
 mod server;
 pub(crate) mod shared;
@@ -37,10 +39,12 @@ mod sync;
 mod util;
 
 use config::SetOutputStyle;
+use export::{AddExport, ExportDrive, ImportDrive, RemoveExport};
+use playback::{AddPlayer, ExportPlayback, ImportPlayback, RemovePlayer, Scrobble};
 use serve::Serve;
-use server::{Add, Login, Recover, Remove};
+use server::{Add, AddFilter, AddSource, AddUser, Login, Recover, Remove, RemoveUser};
 use sync::BuildMetadata;
-use sync::{Prune, Sync};
+use sync::{Prune, Sync, Verify};
 use util::{List, Stats};
 
 pub type Result<T = ()> = anyhow::Result<T>;
@@ -84,8 +88,16 @@ pub enum Command {
     Login,
     /// Adds an item to sync.
     Add,
+    /// Adds a filter on a library to sync.
+    AddFilter,
+    /// Adds the Plex Watchlist or Continue Watching list to sync.
+    AddSource,
     /// Removes an item from the list to sync.
     Remove,
+    /// Adds a Plex Home user whose playback is tracked separately.
+    AddUser,
+    /// Removes a Plex Home user and their playback progress.
+    RemoveUser,
     /// Updates the lists of items to sync and then remove any local content no
     /// longer included.
     Prune,
@@ -99,10 +111,35 @@ pub enum Command {
     Recover,
     /// Rebuilds metadata files.
     BuildMetadata,
+    /// Checks that downloaded files are intact, resetting any that are corrupt.
+    Verify,
     /// Serves downloaded media over DLNA.
     Serve,
     /// Changes the output style.
     SetOutputStyle,
+    /// Mirrors downloaded videos into a directory outside of the store after
+    /// every sync.
+    AddExport,
+    /// Stops exporting to a directory and removes the exported files.
+    RemoveExport,
+    /// Copies videos to a removable drive.
+    ExportDrive,
+    /// Merges playback progress recorded on a removable drive.
+    ImportDrive,
+    /// Writes the playback state of every video as JSON.
+    #[command(after_long_help = playback::PLAYBACK_FORMAT)]
+    ExportPlayback,
+    /// Merges playback changes from other players, in the format written by
+    /// export-playback.
+    #[command(after_long_help = playback::PLAYBACK_FORMAT)]
+    ImportPlayback,
+    /// Adds a Kodi or Jellyfin player whose playback progress is merged back
+    /// before syncing.
+    AddPlayer,
+    /// Removes a player.
+    RemovePlayer,
+    /// Merges playback progress from the added players.
+    Scrobble,
 }
 
 #[enum_dispatch(Command)]
@@ -83,10 +83,10 @@ impl Thumbnail {
         }
     }
 
-    pub(crate) async fn from_video(video: Video) -> Self {
+    pub(crate) async fn from_video(video: Video, user: Option<&str>) -> Self {
         let server = video.server();
         let library = video.library().await;
-        let position = match video.playback_state().await {
+        let position = match video.playback_state(user).await {
             PlaybackState::Unplayed => 0,
             PlaybackState::InProgress { position } => position as u128,
             PlaybackState::Played => video.duration().await.as_millis(),
@@ -224,17 +224,28 @@ pub(super) enum Event {
         total_jobs: u64,
         bars: Vec<SyncProgressBar>,
     },
-    ThumbnailUpdate(Thumbnail),
+    ThumbnailUpdate {
+        user: Option<String>,
+        thumbnail: Thumbnail,
+    },
 }
 
 impl Event {
+    /// Whether this event should be sent to clients viewing the given profile.
+    pub(super) fn is_for_profile(&self, profile: Option<&str>) -> bool {
+        match self {
+            Self::ThumbnailUpdate { user, .. } => user.as_deref() == profile,
+            _ => true,
+        }
+    }
+
     fn event_name(&self) -> String {
         match self {
             Self::SyncStart | Self::SyncEnd => "sync-status".to_owned(),
             Self::Log(_) => "sync-log".to_owned(),
             Self::SyncChange => "sync-change".to_owned(),
             Self::Progress { .. } => "sync-progress".to_owned(),
-            Self::ThumbnailUpdate(thumb) => format!("thumbnail-{}", thumb.id),
+            Self::ThumbnailUpdate { thumbnail, .. } => format!("thumbnail-{}", thumbnail.id),
         }
     }
 
@@ -291,8 +302,8 @@ impl Event {
 
                 Ok(lines.join("\n"))
             }
-            Self::ThumbnailUpdate(thumb) => {
-                let template = ThumbnailTemplate { thumbnail: thumb };
+            Self::ThumbnailUpdate { thumbnail, .. } => {
+                let template = ThumbnailTemplate { thumbnail };
                 template.render()
             }
         }
@@ -325,6 +336,7 @@ pub(crate) enum SyncLogMessage {
     SyncStarted(String),
     SyncFailed((String, String)),
     SyncFinished((String, bool)),
+    DownloadsSkipped(String),
     DownloadStarted(Video),
     DownloadComplete(Video),
     DownloadFailed((Video, String)),
@@ -388,6 +400,13 @@ impl SyncLogItem {
                     }
                 }
             }
+            SyncLogMessage::DownloadsSkipped(server) => SyncLogTemplate {
+                timestamp,
+                message_type: "info",
+                message: format!(
+                    "Syncing finished for {server}, downloads resume in the next download window."
+                ),
+            },
             SyncLogMessage::DownloadStarted(video_part) => SyncLogTemplate {
                 timestamp,
                 message_type: "info",
@@ -14,7 +14,9 @@ use actix_web::{
     App, HttpServer, dev::Extensions, middleware::from_fn, rt::net::TcpStream, web::ThinData,
 };
 use clap::{Args, builder::FalseyValueParser};
-use flick_sync::{DownloadProgress, FlickSync, Progress, Server, SyncProgress, Video};
+use flick_sync::{
+    DownloadProgress, DownloadResult, FlickSync, Progress, Server, SyncProgress, Video,
+};
 use futures::{FutureExt, StreamExt, select};
 use rustls::{
     ServerConfig,
@@ -314,6 +316,10 @@ impl SyncTask {
         )));
     }
 
+    async fn sync_skipped(&self, server: Server) {
+        self.log(SyncLogMessage::DownloadsSkipped(server.name().await));
+    }
+
     async fn sync_finished(&self, server: Server, complete: bool) {
         self.log(SyncLogMessage::SyncFinished((
             server.name().await,
@@ -357,6 +363,7 @@ async fn background_task(
         let _ = event_sender.send(Event::SyncStart);
         let task = SyncTask::new(status.clone(), event_sender.clone());
 
+        flick_sync.scrobble().await;
         flick_sync.prune_root().await;
 
         for server in flick_sync.servers().await {
@@ -376,9 +383,14 @@ async fn background_task(
 
             if full_sync {
                 match server.download(task.clone()).await {
-                    Ok(complete) => {
+                    Ok(DownloadResult::Skipped) => {
+                        server.write_playlists().await;
+                        task.sync_skipped(server).await;
+                    }
+                    Ok(result) => {
                         server.write_playlists().await;
-                        task.sync_finished(server, complete).await;
+                        task.sync_finished(server, result == DownloadResult::Complete)
+                            .await;
                     }
                     Err(e) => task.sync_failed(server, e).await,
                 }
@@ -387,12 +399,22 @@ async fn background_task(
             }
         }
 
+        if full_sync {
+            flick_sync.update_exports().await;
+        }
+
         status.lock().unwrap().is_syncing = false;
         task.send_event(Event::SyncChange);
         let _ = event_sender.send(Event::SyncEnd);
 
+        // Wake up when the next download window opens if that is sooner.
+        let mut delay = Duration::from_secs(30 * 60);
+        if full_sync && let Some(wait) = flick_sync.download_wait() {
+            delay = delay.min(wait);
+        }
+
         select! {
-            _ = time::sleep(Duration::from_secs(30 * 60)).fuse() => {},
+            _ = time::sleep(delay).fuse() => {},
             _ = wakeup.notified().fuse() => {},
         }
     }
@@ -452,6 +474,15 @@ impl Runnable for Serve {
             None
         };
 
+        let playback_task = if !self.disable_syncing {
+            let flick_sync = flick_sync.clone();
+            Some(tokio::spawn(
+                async move { flick_sync.sync_playback().await },
+            ))
+        } else {
+            None
+        };
+
         let service_data = ServiceData {
             flick_sync,
             http_port: port,
@@ -466,6 +497,8 @@ impl Runnable for Serve {
                 .service(service_factory.clone())
                 .wrap(from_fn(middleware::middleware))
                 .service(services::state)
+                .service(services::export_playback)
+                .service(services::import_playback)
                 .service(services::events)
                 .service(services::resources)
                 .service(services::thumbnail_image)
@@ -475,6 +508,7 @@ impl Runnable for Serve {
                 .service(services::show_contents)
                 .service(services::season_contents)
                 .service(services::video_stream)
+                .service(services::video_part_stream)
                 .service(services::update_playback_position)
                 .service(services::video_page)
                 .service(services::library_contents)
@@ -483,6 +517,7 @@ impl Runnable for Serve {
                 .service(services::delete_sync)
                 .service(services::delete_server)
                 .service(services::create_sync)
+                .service(services::select_profile)
                 .service(services::index_page)
         })
         .on_connect(on_connect)
@@ -520,6 +555,10 @@ impl Runnable for Serve {
             background_task.abort();
         }
 
+        if let Some(playback_task) = playback_task {
+            playback_task.abort();
+        }
+
         http_handle.stop(false).await;
         dlna_server.shutdown().await;
 
@@ -13,14 +13,17 @@ use actix_web::{
 };
 use askama::Template;
 use bytes::Bytes;
-use flick_sync::{Collection, Library, LibraryType, PlaybackState, Video, plex_api::library::Item};
-use futures::TryStreamExt;
-use serde::Deserialize;
+use flick_sync::{
+    Collection, Library, LibraryType, PlaybackState, PlaybackUpdates, Video,
+    plex_api::library::Item,
+};
+use futures::{FutureExt, TryStreamExt, future::LocalBoxFuture};
+use serde::{Deserialize, Serialize};
 use tokio::io::BufReader;
 use tokio_stream::wrappers::BroadcastStream;
 use tokio_util::io::ReaderStream;
 use tracing::error;
-use url::Url;
+use url::{Url, form_urlencoded};
 
 use crate::{
     EmbeddedFileStream, Resources,
@@ -59,6 +62,56 @@ impl FromRequest for HxTarget {
     }
 }
 
+/// The user profile selected in the browser, `None` for the server owner.
+/// Profiles for users that are no longer configured fall back to the owner.
+struct Profile(Option<String>);
+
+impl Profile {
+    const COOKIE: &str = "profile";
+}
+
+impl FromRequest for Profile {
+    type Error = actix_web::Error;
+
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        let profile = req
+            .headers()
+            .get_all(header::COOKIE)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(name, _)| *name == Profile::COOKIE)
+            .and_then(|(_, value)| {
+                form_urlencoded::parse(format!("u={value}").as_bytes())
+                    .next()
+                    .map(|(_, user)| user.into_owned())
+            })
+            .filter(|user| !user.is_empty());
+        let service_data = req
+            .app_data::<ThinData<ServiceData>>()
+            .map(|ThinData(service_data)| service_data.clone());
+
+        async move {
+            let profile = match (profile, service_data) {
+                (Some(user), Some(service_data))
+                    if service_data.flick_sync.users().await.contains(&user) =>
+                {
+                    Some(user)
+                }
+                _ => None,
+            };
+
+            Ok(Profile(profile))
+        }
+        .boxed_local()
+    }
+}
+
 async fn async_sort<T, F, R, C>(list: &mut Vec<T>, mut mapper: F, mut comparator: C)
 where
     F: AsyncFnMut(&T) -> R,
@@ -93,11 +146,12 @@ fn render<T: Template>(template: T) -> HttpResponse {
 pub(super) async fn status_page(
     ThinData(service_data): ThinData<ServiceData>,
     HxTarget(target): HxTarget,
+    Profile(profile): Profile,
 ) -> HttpResponse {
     let sidebar = if target.is_some() {
         None
     } else {
-        Some(Sidebar::build(&service_data).await)
+        Some(Sidebar::build(&service_data, profile).await)
     };
 
     #[derive(Template)]
@@ -253,15 +307,40 @@ pub(super) async fn video_stream(
 ) -> HttpResponse {
     let (server_id, video_id) = path.into_inner();
 
-    let Some(server) = service_data.flick_sync.server(&server_id).await else {
+    stream_part(&service_data, &req, &server_id, &video_id, 0).await
+}
+
+#[get("/stream/{server}/{video_id}/{part}")]
+pub(super) async fn video_part_stream(
+    ThinData(service_data): ThinData<ServiceData>,
+    req: HttpRequest,
+    path: Path<(String, String, usize)>,
+) -> HttpResponse {
+    let (server_id, video_id, part) = path.into_inner();
+
+    stream_part(&service_data, &req, &server_id, &video_id, part).await
+}
+
+async fn stream_part(
+    service_data: &ServiceData,
+    req: &HttpRequest,
+    server_id: &str,
+    video_id: &str,
+    index: usize,
+) -> HttpResponse {
+    let Some(server) = service_data.flick_sync.server(server_id).await else {
         return HttpResponse::NotFound().finish();
     };
 
-    let Some(video) = server.video(&video_id).await else {
+    let Some(video) = server.video(video_id).await else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let Some(part) = video.parts().await.into_iter().nth(index) else {
         return HttpResponse::NotFound().finish();
     };
 
-    let Ok(Some(file)) = video.file().await else {
+    let Ok(Some(file)) = part.file().await else {
         return HttpResponse::NotFound().finish();
     };
 
@@ -289,7 +368,7 @@ pub(super) async fn video_stream(
             .unwrap(),
     );
 
-    ByteRangeResponse::build(&req, size, reader, headers).await
+    ByteRangeResponse::build(req, size, reader, headers).await
 }
 
 #[derive(Debug)]
@@ -312,10 +391,12 @@ struct Sidebar {
     is_syncing: bool,
     libraries: Vec<SidebarLibrary>,
     playlists: Vec<SidebarPlaylist>,
+    users: Vec<String>,
+    profile: Option<String>,
 }
 
 impl Sidebar {
-    async fn build(service_data: &ServiceData) -> Self {
+    async fn build(service_data: &ServiceData, profile: Option<String>) -> Self {
         let is_syncing = service_data.status.lock().unwrap().is_syncing;
 
         let mut libraries = Vec::new();
@@ -344,6 +425,8 @@ impl Sidebar {
             is_syncing,
             libraries,
             playlists,
+            users: service_data.flick_sync.users().await,
+            profile,
         }
     }
 }
@@ -371,6 +454,7 @@ struct ListTemplate {
 pub(super) async fn library_contents(
     ThinData(service_data): ThinData<ServiceData>,
     HxTarget(target): HxTarget,
+    Profile(profile): Profile,
     path: Path<(String, String)>,
 ) -> HttpResponse {
     let (server_id, library_id) = path.into_inner();
@@ -386,7 +470,7 @@ pub(super) async fn library_contents(
     let sidebar = if target.is_some() {
         None
     } else {
-        Some(Sidebar::build(&service_data).await)
+        Some(Sidebar::build(&service_data, profile.clone()).await)
     };
 
     let browse_url = format!("/library/{}/{}", server.id(), library.id());
@@ -397,7 +481,8 @@ pub(super) async fn library_contents(
             let mut thumbs = Vec::new();
             for movie in lib.movies().await {
                 if movie.is_downloaded().await {
-                    thumbs.push(Thumbnail::from_video(Video::Movie(movie)).await);
+                    thumbs
+                        .push(Thumbnail::from_video(Video::Movie(movie), profile.as_deref()).await);
                 }
             }
             thumbs.sort();
@@ -438,6 +523,7 @@ pub(super) async fn library_contents(
 pub(super) async fn library_collections(
     ThinData(service_data): ThinData<ServiceData>,
     HxTarget(target): HxTarget,
+    Profile(profile): Profile,
     path: Path<(String, String)>,
 ) -> HttpResponse {
     let (server_id, library_id) = path.into_inner();
@@ -453,7 +539,7 @@ pub(super) async fn library_collections(
     let sidebar = if target.is_some() {
         None
     } else {
-        Some(Sidebar::build(&service_data).await)
+        Some(Sidebar::build(&service_data, profile).await)
     };
 
     let browse_url = format!("/library/{}/{}", server.id(), library.id());
@@ -481,6 +567,7 @@ pub(super) async fn library_collections(
 pub(super) async fn collection_contents(
     ThinData(service_data): ThinData<ServiceData>,
     HxTarget(target): HxTarget,
+    Profile(profile): Profile,
     path: Path<(String, String, String)>,
 ) -> HttpResponse {
     let (server_id, _, collection_id) = path.into_inner();
@@ -496,7 +583,7 @@ pub(super) async fn collection_contents(
     let sidebar = if target.is_some() {
         None
     } else {
-        Some(Sidebar::build(&service_data).await)
+        Some(Sidebar::build(&service_data, profile.clone()).await)
     };
 
     let mut thumbs = Vec::new();
@@ -518,7 +605,7 @@ pub(super) async fn collection_contents(
             .await;
 
             for movie in movies {
-                thumbs.push(Thumbnail::from_video(Video::Movie(movie)).await);
+                thumbs.push(Thumbnail::from_video(Video::Movie(movie), profile.as_deref()).await);
             }
         }
         Collection::Show(ref c) => {
@@ -543,6 +630,7 @@ pub(super) async fn collection_contents(
 pub(super) async fn show_contents(
     ThinData(service_data): ThinData<ServiceData>,
     HxTarget(target): HxTarget,
+    Profile(profile): Profile,
     path: Path<(String, String, String)>,
 ) -> HttpResponse {
     let (server_id, _, show_id) = path.into_inner();
@@ -558,7 +646,7 @@ pub(super) async fn show_contents(
     let sidebar = if target.is_some() {
         None
     } else {
-        Some(Sidebar::build(&service_data).await)
+        Some(Sidebar::build(&service_data, profile).await)
     };
 
     let mut thumbs = Vec::new();
@@ -579,6 +667,7 @@ pub(super) async fn show_contents(
 pub(super) async fn season_contents(
     ThinData(service_data): ThinData<ServiceData>,
     HxTarget(target): HxTarget,
+    Profile(profile): Profile,
     path: Path<(String, String, String)>,
 ) -> HttpResponse {
     let (server_id, _, season_id) = path.into_inner();
@@ -594,13 +683,13 @@ pub(super) async fn season_contents(
     let sidebar = if target.is_some() {
         None
     } else {
-        Some(Sidebar::build(&service_data).await)
+        Some(Sidebar::build(&service_data, profile.clone()).await)
     };
 
     let mut thumbs = Vec::new();
     for episode in season.episodes().await {
         if episode.is_downloaded().await {
-            thumbs.push(Thumbnail::from_video(Video::Episode(episode)).await);
+            thumbs.push(Thumbnail::from_video(Video::Episode(episode), profile.as_deref()).await);
         }
     }
 
@@ -621,6 +710,7 @@ pub(super) async fn season_contents(
 pub(super) async fn playlist_contents(
     ThinData(service_data): ThinData<ServiceData>,
     HxTarget(target): HxTarget,
+    Profile(profile): Profile,
     path: Path<(String, String)>,
 ) -> HttpResponse {
     let (server_id, playlist_id) = path.into_inner();
@@ -636,7 +726,7 @@ pub(super) async fn playlist_contents(
     let sidebar = if target.is_some() {
         None
     } else {
-        Some(Sidebar::build(&service_data).await)
+        Some(Sidebar::build(&service_data, profile.clone()).await)
     };
 
     #[derive(Template)]
@@ -650,7 +740,7 @@ pub(super) async fn playlist_contents(
     let mut items = Vec::new();
     for video in playlist.videos().await {
         if video.is_downloaded().await {
-            items.push(Thumbnail::from_video(video).await);
+            items.push(Thumbnail::from_video(video, profile.as_deref()).await);
         }
     }
 
@@ -671,6 +761,7 @@ pub struct PlaybackPosition {
 #[post("/playback/{server}/{library_id}/video/{video_id}")]
 pub(super) async fn update_playback_position(
     ThinData(service_data): ThinData<ServiceData>,
+    Profile(profile): Profile,
     path: Path<(String, String, String)>,
     query: Query<PlaybackPosition>,
 ) -> HttpResponse {
@@ -685,12 +776,15 @@ pub(super) async fn update_playback_position(
     };
 
     let position = (query.position * 1000.0).round() as u64;
-    let _ = video.set_playback_position(position).await;
+    let _ = video
+        .set_playback_position(profile.as_deref(), position)
+        .await;
 
-    let thumbnail = Thumbnail::from_video(video).await;
-    let _ = service_data
-        .event_sender
-        .send(Event::ThumbnailUpdate(thumbnail));
+    let thumbnail = Thumbnail::from_video(video, profile.as_deref()).await;
+    let _ = service_data.event_sender.send(Event::ThumbnailUpdate {
+        user: profile,
+        thumbnail,
+    });
 
     HttpResponse::Ok().finish()
 }
@@ -699,6 +793,7 @@ pub(super) async fn update_playback_position(
 pub(super) async fn video_page(
     ThinData(service_data): ThinData<ServiceData>,
     HxTarget(target): HxTarget,
+    Profile(profile): Profile,
     req: HttpRequest,
     path: Path<(String, String, String)>,
 ) -> HttpResponse {
@@ -715,7 +810,7 @@ pub(super) async fn video_page(
     let sidebar = if target.is_some() {
         None
     } else {
-        Some(Sidebar::build(&service_data).await)
+        Some(Sidebar::build(&service_data, profile.clone()).await)
     };
 
     let url_base = if let Some(conn_info) = req.conn_data::<ConnectionInfo>() {
@@ -732,13 +827,36 @@ pub(super) async fn video_page(
         "".to_string()
     };
 
-    let Ok(Some(file)) = video.file().await else {
-        return HttpResponse::NotFound().finish();
-    };
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct PartInfo {
+        url: String,
+        mime_type: String,
+        offset: f64,
+        duration: f64,
+    }
 
-    let Ok(mime_type) = file.mime_type().await else {
-        return HttpResponse::NotFound().finish();
-    };
+    let mut parts = Vec::new();
+    for part in video.parts().await {
+        let Ok(Some(file)) = part.file().await else {
+            return HttpResponse::NotFound().finish();
+        };
+
+        let Ok(mime_type) = file.mime_type().await else {
+            return HttpResponse::NotFound().finish();
+        };
+
+        parts.push(PartInfo {
+            url: format!(
+                "{url_base}stream/{server_id}/{}/{}",
+                video.id(),
+                part.index()
+            ),
+            mime_type: mime_type.to_string(),
+            offset: part.offset().await.as_millis() as f64 / 1000.0,
+            duration: part.duration().await.as_millis() as f64 / 1000.0,
+        });
+    }
 
     #[derive(Template)]
     #[template(path = "video.html")]
@@ -751,12 +869,11 @@ pub(super) async fn video_page(
         show: Option<String>,
         season: Option<usize>,
         episode: Option<usize>,
-        url: String,
-        mime_type: String,
+        parts: Vec<PartInfo>,
         total_duration: f64,
     }
 
-    let playback_state = video.playback_state().await;
+    let playback_state = video.playback_state(profile.as_deref()).await;
     let playback_position = match playback_state {
         PlaybackState::Unplayed | PlaybackState::Played => 0.0,
         PlaybackState::InProgress { position } => position as f64 / 1000.0,
@@ -790,8 +907,7 @@ pub(super) async fn video_page(
         show,
         season,
         episode,
-        url: format!("{url_base}stream/{server_id}/{}", video.id()),
-        mime_type: mime_type.to_string(),
+        parts,
         total_duration: video.duration().await.as_millis() as f64 / 1000.0,
     };
 
@@ -799,14 +915,24 @@ pub(super) async fn video_page(
 }
 
 #[get("/events")]
-pub(super) async fn events(ThinData(service_data): ThinData<ServiceData>) -> HttpResponse {
+pub(super) async fn events(
+    ThinData(service_data): ThinData<ServiceData>,
+    Profile(profile): Profile,
+) -> HttpResponse {
     let receiver = service_data.event_sender.subscribe();
 
     let flick_sync = service_data.flick_sync;
     let event_stream = BroadcastStream::new(receiver)
         .try_filter_map(move |event| {
             let flick_sync = flick_sync.clone();
-            async move { Ok(event.to_string(&flick_sync).await) }
+            let for_profile = event.is_for_profile(profile.as_deref());
+            async move {
+                if for_profile {
+                    Ok(event.to_string(&flick_sync).await)
+                } else {
+                    Ok(None)
+                }
+            }
         })
         .map_ok(Bytes::from_owner);
 
@@ -937,7 +1063,11 @@ pub(super) async fn create_sync(
         return HttpResponse::NotFound().finish();
     }
 
-    if server.add_sync(&rating_key, None, false).await.is_err() {
+    if server
+        .add_sync(&rating_key, Default::default())
+        .await
+        .is_err()
+    {
         HttpResponse::InternalServerError().finish()
     } else {
         let _ = service_data.event_sender.send(Event::SyncChange);
@@ -950,11 +1080,12 @@ pub(super) async fn create_sync(
 pub(super) async fn sync_list(
     ThinData(service_data): ThinData<ServiceData>,
     HxTarget(target): HxTarget,
+    Profile(profile): Profile,
 ) -> HttpResponse {
     let sidebar = if target.is_some() {
         None
     } else {
-        Some(Sidebar::build(&service_data).await)
+        Some(Sidebar::build(&service_data, profile).await)
     };
 
     let disk_info = disk_info_for_path(service_data.flick_sync.root());
@@ -980,6 +1111,40 @@ pub(super) async fn sync_list(
     render(template)
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ProfileSelection {
+    user: Option<String>,
+}
+
+#[post("/profile")]
+pub(super) async fn select_profile(
+    ThinData(service_data): ThinData<ServiceData>,
+    form: Form<ProfileSelection>,
+) -> HttpResponse {
+    let user = form.into_inner().user.filter(|user| !user.is_empty());
+
+    if let Some(ref user) = user
+        && !service_data.flick_sync.users().await.contains(user)
+    {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let value: String =
+        form_urlencoded::byte_serialize(user.unwrap_or_default().as_bytes()).collect();
+
+    HttpResponse::SeeOther()
+        .append_header((header::LOCATION, "/"))
+        .append_header(("HX-Redirect", "/"))
+        .append_header((
+            header::SET_COOKIE,
+            format!(
+                "{}={value}; Path=/; Max-Age=31536000; SameSite=Lax",
+                Profile::COOKIE
+            ),
+        ))
+        .finish()
+}
+
 #[get("/state.json")]
 pub(super) async fn state(ThinData(service_data): ThinData<ServiceData>) -> HttpResponse {
     match service_data.flick_sync.state_json().await {
@@ -993,20 +1158,61 @@ pub(super) async fn state(ThinData(service_data): ThinData<ServiceData>) -> Http
     }
 }
 
+/// Returns the playback state of every video, in the format described by
+/// `flick-sync help export-playback`.
+#[get("/playback.json")]
+pub(super) async fn export_playback(ThinData(service_data): ThinData<ServiceData>) -> HttpResponse {
+    match service_data.flick_sync.playback_json().await {
+        Ok(json) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json),
+        Err(e) => {
+            error!(error=%e, "Failed to generate playback JSON");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Merges playback changes posted in the same format as `/playback.json`
+/// returns, responding with a 400 error if they cannot be parsed.
+#[post("/playback.json")]
+pub(super) async fn import_playback(
+    ThinData(service_data): ThinData<ServiceData>,
+    body: String,
+) -> HttpResponse {
+    let updates: PlaybackUpdates = match body.parse() {
+        Ok(updates) => updates,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+
+    match service_data
+        .flick_sync
+        .apply_playback_updates(updates)
+        .await
+    {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            error!(error=%e, "Failed to apply playback updates");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
 #[get("/")]
 pub(super) async fn index_page(
     ThinData(service_data): ThinData<ServiceData>,
     HxTarget(target): HxTarget,
+    Profile(profile): Profile,
 ) -> HttpResponse {
     let sidebar = if target.is_some() {
         None
     } else {
-        Some(Sidebar::build(&service_data).await)
+        Some(Sidebar::build(&service_data, profile.clone()).await)
     };
 
     let mut thumbs: Vec<Thumbnail> = Vec::new();
-    for video in service_data.flick_sync.on_deck().await {
-        thumbs.push(Thumbnail::from_video(video).await);
+    for video in service_data.flick_sync.on_deck(profile.as_deref()).await {
+        thumbs.push(Thumbnail::from_video(video, profile.as_deref()).await);
     }
 
     thumbs.sort();
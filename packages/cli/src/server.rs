@@ -1,11 +1,11 @@
 use anyhow::{anyhow, bail};
-use clap::Args;
+use clap::{Args, builder::TypedValueParser};
 use flick_sync::{
-    FlickSync, Server, ServerConnection,
+    FlickSync, Server, ServerConnection, SubtitlePreferences, SyncOptions, SyncSource,
     plex_api::{
         self, HttpClient, MyPlex, MyPlexBuilder, Server as PlexServer,
         device::{Device, DeviceConnection},
-        library::{Item, MetadataItem},
+        library::{Item, Library, MetadataItem},
     },
 };
 use tracing::{error, instrument, warn};
@@ -241,15 +241,60 @@ impl Runnable for Login {
 }
 
 #[derive(Args)]
-pub struct Add {
-    /// The web url of the item to add to the list to sync.
-    url: String,
+pub struct SyncArgs {
     /// The transcode profile to use for this item.
     #[clap(short, long)]
     profile: Option<String>,
     /// Only sync unplayed items
     #[clap(short, long)]
     only_unplayed: bool,
+    /// For shows, only sync this many episodes after the last played episode
+    #[clap(long)]
+    next_episodes: Option<u32>,
+    /// Delete downloads this many days after they have been played
+    #[clap(short, long)]
+    retain_days: Option<u32>,
+    /// Items with a higher priority are downloaded first
+    #[clap(long)]
+    priority: Option<i32>,
+    /// Subtitle languages to include, e.g. "en,fr"
+    #[clap(long, value_delimiter = ',')]
+    subtitles: Vec<String>,
+    /// Only include forced subtitles
+    #[clap(long, requires = "subtitles")]
+    forced_subtitles: bool,
+    /// Burn subtitles into the video instead of storing separate files
+    #[clap(long, requires = "subtitles")]
+    burn_subtitles: bool,
+    /// Preferred audio languages in order, e.g. "ja,en"
+    #[clap(long, value_delimiter = ',')]
+    audio_languages: Vec<String>,
+}
+
+impl From<SyncArgs> for SyncOptions {
+    fn from(args: SyncArgs) -> Self {
+        Self {
+            transcode_profile: args.profile,
+            only_unplayed: args.only_unplayed,
+            next_episodes: args.next_episodes,
+            retain_days: args.retain_days,
+            priority: args.priority,
+            subtitles: (!args.subtitles.is_empty()).then_some(SubtitlePreferences {
+                languages: args.subtitles,
+                forced_only: args.forced_subtitles,
+                burn: args.burn_subtitles,
+            }),
+            audio_languages: (!args.audio_languages.is_empty()).then_some(args.audio_languages),
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct Add {
+    /// The web url of the item to add to the list to sync.
+    url: String,
+    #[clap(flatten)]
+    options: SyncArgs,
 }
 
 impl Runnable for Add {
@@ -317,9 +362,7 @@ impl Runnable for Add {
                 server.id(),
             ));
 
-            server
-                .add_sync(&rating_key, self.profile, self.only_unplayed)
-                .await?;
+            server.add_sync(&rating_key, self.options.into()).await?;
 
             return Ok(());
         }
@@ -328,6 +371,85 @@ impl Runnable for Add {
     }
 }
 
+#[derive(Args)]
+pub struct AddFilter {
+    /// The server to add to.
+    server: String,
+    /// The ID or name of the library to filter.
+    library: String,
+    /// The Plex filter to apply, e.g. "unwatched=1&addedAt>>=-30d".
+    filter: String,
+    #[clap(flatten)]
+    options: SyncArgs,
+}
+
+impl Runnable for AddFilter {
+    #[instrument(name = "AddFilter", skip_all)]
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let server = flick_sync
+            .server(&self.server)
+            .await
+            .ok_or_else(|| anyhow!("Unknown server: {}", self.server))?;
+
+        let plex_server = server.connect().await?;
+        let library = plex_server
+            .libraries()
+            .into_iter()
+            .find(|library| {
+                library.id() == self.library || library.title().eq_ignore_ascii_case(&self.library)
+            })
+            .ok_or_else(|| anyhow!("Unknown library: {}", self.library))?;
+
+        if !matches!(library, Library::Movie(_) | Library::TV(_)) {
+            bail!("Unsupported library type: {}", library.title());
+        }
+
+        let id = server
+            .add_filter_sync(library.id(), &self.filter, self.options.into())
+            .await?;
+
+        console.println(format!(
+            "Added filter {id} on '{}' to the sync list for {}",
+            library.title(),
+            server.id(),
+        ));
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct AddSource {
+    /// The server to add to.
+    server: String,
+    /// The list to sync.
+    #[arg(
+        value_parser = clap::builder::PossibleValuesParser::new(["watchlist", "ondeck"])
+            .map(|s| s.parse::<SyncSource>().unwrap()),
+    )]
+    source: SyncSource,
+    #[clap(flatten)]
+    options: SyncArgs,
+}
+
+impl Runnable for AddSource {
+    #[instrument(name = "AddSource", skip_all)]
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let server = flick_sync
+            .server(&self.server)
+            .await
+            .ok_or_else(|| anyhow!("Unknown server: {}", self.server))?;
+
+        let id = server
+            .add_source_sync(self.source, self.options.into())
+            .await?;
+
+        console.println(format!("Added {id} to the sync list for {}", server.id()));
+
+        Ok(())
+    }
+}
+
 #[derive(Args)]
 pub struct Remove {
     /// The server to remove from.
@@ -362,6 +484,58 @@ impl Runnable for Remove {
     }
 }
 
+#[derive(Args)]
+pub struct AddUser {
+    /// The server to add the user to.
+    server: String,
+    /// A name for the user, used to pick their profile in DLNA and web clients.
+    name: String,
+    /// The user's name in Plex Home, if different.
+    #[clap(long)]
+    plex_user: Option<String>,
+}
+
+impl Runnable for AddUser {
+    #[instrument(name = "AddUser", skip_all)]
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let server = flick_sync
+            .server(&self.server)
+            .await
+            .ok_or_else(|| anyhow!("Unknown server: {}", self.server))?;
+
+        let plex_user = self.plex_user.as_deref().unwrap_or(&self.name);
+        server.add_user(&self.name, plex_user).await?;
+
+        console.println(format!("Added user {} to {}", self.name, server.id()));
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct RemoveUser {
+    /// The server to remove the user from.
+    server: String,
+    /// The name of the user.
+    name: String,
+}
+
+impl Runnable for RemoveUser {
+    #[instrument(name = "RemoveUser", skip_all)]
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let server = flick_sync
+            .server(&self.server)
+            .await
+            .ok_or_else(|| anyhow!("Unknown server: {}", self.server))?;
+
+        if !server.remove_user(&self.name).await? {
+            console.println(format!("{} is not a known user.", self.name));
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Args)]
 pub struct Recover {}
 
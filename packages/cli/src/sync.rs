@@ -1,6 +1,6 @@
 use clap::Args;
-use flick_sync::{DownloadProgress, FlickSync, Progress, SyncProgress, Video};
-use tracing::{debug, error, instrument, warn};
+use flick_sync::{DownloadProgress, DownloadResult, FlickSync, Progress, SyncProgress, Video};
+use tracing::{debug, error, info, instrument, warn};
 
 use crate::{
     Console, Result, Runnable,
@@ -39,6 +39,36 @@ impl Runnable for Prune {
     }
 }
 
+#[derive(Args)]
+pub struct Verify {
+    /// The servers to verify. Can be repeated. When not passed all servers are
+    /// verified.
+    #[clap(short = 's', long = "server")]
+    ids: Vec<String>,
+}
+
+impl Runnable for Verify {
+    #[instrument(name = "Verify", skip_all)]
+    async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
+        let servers = select_servers(&flick_sync, &self.ids).await?;
+
+        for server in servers {
+            let corrupt = server.verify().await;
+
+            if corrupt > 0 {
+                console.println(format!(
+                    "Server {}: {corrupt} corrupt downloads will be downloaded again.",
+                    server.id()
+                ));
+            } else {
+                console.println(format!("Server {}: All downloads are intact.", server.id()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Args)]
 pub struct BuildMetadata {
     /// The servers to rebuild. Can be repeated. When not passed all servers are
@@ -162,6 +192,7 @@ impl Runnable for Sync {
     async fn run(self, flick_sync: FlickSync, console: Console) -> Result {
         let servers = select_servers(&flick_sync, &self.ids).await?;
 
+        flick_sync.scrobble().await;
         flick_sync.prune_root().await;
 
         let progress = ConsoleProgress::new(console);
@@ -179,13 +210,19 @@ impl Runnable for Sync {
 
             debug!(server = server.id(), "Starting transfer jobs");
 
-            if let Ok(false) = server.download(progress.clone()).await {
-                warn!("Some items are not yet downloaded");
+            match server.download(progress.clone()).await {
+                Ok(DownloadResult::Incomplete) => warn!("Some items are not yet downloaded"),
+                Ok(DownloadResult::Skipped) => {
+                    info!("Downloads will resume in the next download window")
+                }
+                _ => {}
             }
 
             server.write_playlists().await;
         }
 
+        flick_sync.update_exports().await;
+
         Ok(())
     }
 }
@@ -105,20 +105,29 @@ impl Runnable for List {
                     ItemType::Season => "Season",
                     ItemType::Episode => "Episode",
                     ItemType::Movie => "Movie",
+                    ItemType::Filter => "Filter",
+                    ItemType::Source => "Source",
                     ItemType::Unknown => "Unknown",
                 };
 
-                let selected = if item.only_unplayed {
-                    "unplayed"
+                let title = match item.filter {
+                    Some(ref filter) => format!("{} ({filter})", item.title),
+                    None => item.title.clone(),
+                };
+
+                let selected = if let Some(count) = item.next_episodes {
+                    format!("next {count}")
+                } else if item.only_unplayed {
+                    "unplayed".to_string()
                 } else {
-                    "all"
+                    "all".to_string()
                 };
 
                 console.println(format!(
                     "{:10} {:8} {type_name:16}  {:20} {selected:3} {:10}",
                     server.id(),
                     item.id,
-                    item.title,
+                    title,
                     item.transcode_profile.unwrap_or_default(),
                 ));
             }
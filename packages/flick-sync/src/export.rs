@@ -0,0 +1,410 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::ErrorKind,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs::{
+    File, copy, create_dir_all, hard_link, metadata, read_to_string, remove_dir, remove_file,
+    rename, symlink_metadata,
+};
+use tracing::{debug, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    PlaybackUpdates, Result, Server, ServerPlaybackUpdates, Video, config::OutputLayout,
+    sync::OpReadGuard, util::safe_write,
+};
+
+/// Lists the files written to an export directory so that they can be removed
+/// when no longer needed without touching anything else in the directory.
+pub(crate) const EXPORT_MANIFEST: &str = ".flicksync.export.json";
+
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ExportManifest {
+    /// Exported files, relative to the export directory.
+    #[serde(default)]
+    files: BTreeSet<PathBuf>,
+    /// For removable drives, the store that wrote the drive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) client_id: Option<Uuid>,
+    /// For removable drives, the IDs of the videos on the drive by server.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) videos: HashMap<String, BTreeSet<String>>,
+}
+
+impl ExportManifest {
+    pub(crate) async fn read(target: &Path) -> Result<Self> {
+        match read_to_string(target.join(EXPORT_MANIFEST)).await {
+            Ok(str) => Ok(serde_json::from_str(&str)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn write(&self, target: &Path) -> Result {
+        safe_write(target.join(EXPORT_MANIFEST), self).await
+    }
+
+    /// Filters playback updates down to the videos on the drive.
+    pub(crate) fn filter_updates(&self, updates: PlaybackUpdates) -> PlaybackUpdates {
+        PlaybackUpdates {
+            servers: self.filter_servers(updates.servers),
+            users: updates
+                .users
+                .into_iter()
+                .map(|(user, servers)| (user, self.filter_servers(servers)))
+                .collect(),
+        }
+    }
+
+    fn filter_servers(&self, servers: ServerPlaybackUpdates) -> ServerPlaybackUpdates {
+        servers
+            .into_iter()
+            .filter_map(|(server_id, videos)| {
+                let known = self.videos.get(&server_id)?;
+                let videos: HashMap<_, _> = videos
+                    .into_iter()
+                    .filter(|(video_id, _)| known.contains(video_id))
+                    .collect();
+
+                Some((server_id, videos))
+            })
+            .collect()
+    }
+}
+
+/// Something with files to export, locked while they are exported.
+trait ExportSource {
+    type Guard;
+
+    async fn try_lock(&self) -> Option<Self::Guard>;
+}
+
+impl ExportSource for Video {
+    type Guard = OpReadGuard;
+
+    async fn try_lock(&self) -> Option<OpReadGuard> {
+        self.server().try_lock_read_key(self.id()).await.ok()
+    }
+}
+
+/// Whether the target is already a hardlink to, or a copy of, the source.
+async fn is_current(source: &Path, target: &Path) -> bool {
+    let (Ok(source), Ok(target)) = (metadata(source).await, metadata(target).await) else {
+        return false;
+    };
+
+    if source.dev() == target.dev() && source.ino() == target.ino() {
+        return true;
+    }
+
+    source.len() == target.len() && target.mtime() >= source.mtime()
+}
+
+/// Copies the source file into place. The copy is flushed to disk before it
+/// is renamed so an interrupted copy never looks complete.
+async fn copy_file(source: &Path, target: &Path) -> Result {
+    let mut temp = target.as_os_str().to_owned();
+    temp.push(".temp");
+
+    let result = async {
+        copy(source, &temp).await?;
+        File::open(&temp).await?.sync_all().await
+    }
+    .await;
+
+    if let Err(e) = result {
+        let _ = remove_file(&temp).await;
+        return Err(e.into());
+    }
+
+    rename(&temp, target).await?;
+
+    Ok(())
+}
+
+/// Links the source file into place if allowed, falling back to copying it
+/// when the target is on a different filesystem or hardlinks are not
+/// supported.
+async fn export_file(source: &Path, target: &Path, link: bool) -> Result {
+    if let Some(parent) = target.parent() {
+        create_dir_all(parent).await?;
+    }
+
+    if let Err(e) = remove_file(target).await
+        && e.kind() != ErrorKind::NotFound
+    {
+        return Err(e.into());
+    }
+
+    if link {
+        match hard_link(source, target).await {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                debug!(error=?e, path=%target.display(), "Unable to hardlink, copying instead");
+            }
+        }
+    }
+
+    copy_file(source, target).await
+}
+
+/// Removes a file and then any directories left empty by its removal, up to
+/// the export directory.
+async fn unexport_file(target: &Path, path: &Path) -> Result {
+    if let Err(e) = remove_file(target.join(path)).await
+        && e.kind() != ErrorKind::NotFound
+    {
+        return Err(e.into());
+    }
+
+    for parent in path.ancestors().skip(1) {
+        if parent.as_os_str().is_empty() || remove_dir(target.join(parent)).await.is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists the files of the given videos by their path in the layout, along with
+/// the video and the full path of the source file.
+async fn video_files<'a>(
+    root: &Path,
+    videos: &'a [Video],
+    layout: &OutputLayout,
+) -> BTreeMap<PathBuf, (&'a Video, PathBuf)> {
+    let mut files = BTreeMap::new();
+    for video in videos {
+        for (source, path) in video.export_files(layout).await {
+            files.insert(path, (video, root.join(source)));
+        }
+    }
+
+    files
+}
+
+/// Writes the given files to the target directory and removes anything
+/// previously exported that is no longer included. Files that already exist
+/// but were not previously exported are left alone. Returns the files now in
+/// the target directory.
+async fn sync_files<S: ExportSource>(
+    target: &Path,
+    files: BTreeMap<PathBuf, (&S, PathBuf)>,
+    link: bool,
+    previous: &BTreeSet<PathBuf>,
+) -> BTreeSet<PathBuf> {
+    let mut exported = BTreeSet::new();
+
+    for (path, (owner, source)) in files {
+        let file = target.join(&path);
+
+        if !previous.contains(&path) && symlink_metadata(&file).await.is_ok() {
+            warn!(path=%path.display(), "Skipping export over a file that was not exported");
+            continue;
+        }
+
+        if !is_current(&source, &file).await {
+            let Some(_guard) = owner.try_lock().await else {
+                warn!(path=%path.display(), "Skipping export of locked file");
+                if previous.contains(&path) {
+                    exported.insert(path);
+                }
+                continue;
+            };
+
+            if let Err(e) = export_file(&source, &file, link).await {
+                warn!(error=?e, path=%path.display(), "Failed to export file");
+                continue;
+            }
+
+            debug!(path=%path.display(), "Exported file");
+        }
+
+        exported.insert(path);
+    }
+
+    let removed: Vec<PathBuf> = previous.difference(&exported).cloned().collect();
+
+    for path in removed {
+        if let Err(e) = unexport_file(target, &path).await {
+            warn!(error=?e, path=%path.display(), "Failed to remove exported file");
+            // Try again next time.
+            exported.insert(path);
+            continue;
+        }
+
+        debug!(path=%path.display(), "Removed exported file");
+    }
+
+    exported
+}
+
+/// Mirrors the downloaded videos of the given servers into the target
+/// directory laid out according to the layout. Anything previously exported
+/// that is no longer downloaded is removed.
+#[instrument(level = "debug", skip(servers, root, layout))]
+pub(crate) async fn export(
+    servers: &[Server],
+    root: &Path,
+    target: &Path,
+    layout: &OutputLayout,
+) -> Result {
+    let mut manifest = ExportManifest::read(target).await?;
+
+    let mut videos = Vec::new();
+    for server in servers {
+        videos.extend(server.videos().await);
+    }
+
+    let files = video_files(root, &videos, layout).await;
+    manifest.files = sync_files(target, files, true, &manifest.files).await;
+
+    manifest.write(target).await
+}
+
+/// Copies the given videos to a removable drive, replacing whatever was
+/// previously copied there. The manifest is written last, once everything
+/// else is on the drive.
+#[instrument(level = "debug", skip(videos, root, layout))]
+pub(crate) async fn export_drive(
+    client_id: Uuid,
+    videos: &[Video],
+    root: &Path,
+    target: &Path,
+    layout: &OutputLayout,
+) -> Result {
+    let mut manifest = ExportManifest::read(target).await?;
+
+    let files = video_files(root, videos, layout).await;
+    manifest.files = sync_files(target, files, false, &manifest.files).await;
+    manifest.client_id = Some(client_id);
+    manifest.videos.clear();
+
+    for video in videos {
+        if video.is_downloaded().await {
+            manifest
+                .videos
+                .entry(video.server().id().to_owned())
+                .or_default()
+                .insert(video.id().to_owned());
+        }
+    }
+
+    manifest.write(target).await
+}
+
+/// Removes everything previously exported to the target directory.
+#[instrument(level = "debug")]
+pub(crate) async fn clear_export(target: &Path) -> Result {
+    let manifest = ExportManifest::read(target).await?;
+
+    for path in &manifest.files {
+        unexport_file(target, path).await?;
+    }
+
+    if let Err(e) = remove_file(target.join(EXPORT_MANIFEST)).await
+        && e.kind() != ErrorKind::NotFound
+    {
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        fs::{create_dir_all, read_to_string, write},
+        path::{Path, PathBuf},
+    };
+
+    use tempfile::TempDir;
+
+    use crate::export::{ExportSource, sync_files};
+
+    struct Unlocked;
+
+    impl ExportSource for Unlocked {
+        type Guard = ();
+
+        async fn try_lock(&self) -> Option<()> {
+            Some(())
+        }
+    }
+
+    fn files<'a>(source: &Path, paths: &[&str]) -> BTreeMap<PathBuf, (&'a Unlocked, PathBuf)> {
+        paths
+            .iter()
+            .map(|path| (PathBuf::from(path), (&Unlocked, source.join(path))))
+            .collect()
+    }
+
+    fn paths(paths: &[&str]) -> BTreeSet<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[tokio::test]
+    async fn sync_export_files() {
+        let source = TempDir::new().unwrap();
+        let target = TempDir::new().unwrap();
+
+        create_dir_all(source.path().join("Movie")).unwrap();
+        write(source.path().join("Movie/movie.mkv"), "video").unwrap();
+        write(source.path().join("Movie/movie.nfo"), "metadata").unwrap();
+        write(source.path().join("notes.txt"), "exported").unwrap();
+        write(target.path().join("notes.txt"), "unmanaged").unwrap();
+
+        let all = ["Movie/movie.mkv", "Movie/movie.nfo", "notes.txt"];
+
+        // Files are added but an existing file that was never exported is not
+        // replaced.
+        let exported = sync_files(
+            target.path(),
+            files(source.path(), &all),
+            false,
+            &paths(&[]),
+        )
+        .await;
+        assert_eq!(exported, paths(&["Movie/movie.mkv", "Movie/movie.nfo"]));
+        assert_eq!(
+            read_to_string(target.path().join("Movie/movie.mkv")).unwrap(),
+            "video"
+        );
+        assert_eq!(
+            read_to_string(target.path().join("notes.txt")).unwrap(),
+            "unmanaged"
+        );
+
+        // Changed files are updated.
+        write(source.path().join("Movie/movie.nfo"), "new metadata").unwrap();
+        let exported =
+            sync_files(target.path(), files(source.path(), &all), false, &exported).await;
+        assert_eq!(exported, paths(&["Movie/movie.mkv", "Movie/movie.nfo"]));
+        assert_eq!(
+            read_to_string(target.path().join("Movie/movie.nfo")).unwrap(),
+            "new metadata"
+        );
+
+        // Files no longer included are pruned along with empty directories,
+        // unmanaged files are left alone.
+        let exported = sync_files(
+            target.path(),
+            files(source.path(), &["notes.txt"]),
+            false,
+            &exported,
+        )
+        .await;
+        assert!(exported.is_empty());
+        assert!(!target.path().join("Movie").exists());
+        assert_eq!(
+            read_to_string(target.path().join("notes.txt")).unwrap(),
+            "unmanaged"
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    io::{ErrorKind, SeekFrom},
+    path::Path,
+    process::Stdio,
+    str::FromStr,
+    time::Duration,
+};
+
+use file_format::FileFormat;
+use mime::Mime;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::{
+    fs::{File, metadata},
+    io::{AsyncReadExt, AsyncSeekExt},
+    process::Command,
+};
+use tracing::{instrument, trace};
+
+use crate::Result;
+
+/// Durations are allowed to differ by this much, or by 1% if that is more.
+const DURATION_TOLERANCE: Duration = Duration::from_secs(5);
+
+/// The reason a downloaded file failed an integrity check.
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub(crate) struct Corrupt(String);
+
+macro_rules! corrupt {
+    ($($arg:tt)*) => {
+        return Err(Corrupt(format!($($arg)*)).into())
+    };
+}
+
+/// The number of bytes from the start of a download used to fingerprint it.
+pub(crate) const FINGERPRINT_LENGTH: u64 = 64 * 1024;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Fingerprints the start of some content.
+pub(crate) fn fingerprint(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+/// Fingerprints the first `length` bytes of a file. Returns `None` if the file
+/// is missing or shorter than that.
+pub(crate) async fn file_fingerprint(path: &Path, length: usize) -> Result<Option<String>> {
+    let file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut data = Vec::with_capacity(length);
+    file.take(length as u64).read_to_end(&mut data).await?;
+
+    if data.len() < length {
+        return Ok(None);
+    }
+
+    Ok(Some(fingerprint(&data)))
+}
+
+/// Computes the hex encoded SHA-256 checksum of a file.
+pub(crate) async fn checksum(path: &Path) -> Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+
+    loop {
+        let count = file.read(&mut buffer).await?;
+        if count == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..count]);
+    }
+
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Reads a box header at the given offset, returning the box type, the size of
+/// the header and the size of the box.
+async fn read_box_header(file: &mut File, offset: u64, end: u64) -> Result<([u8; 4], u64, u64)> {
+    if end - offset < 8 {
+        corrupt!("Truncated box header at {offset}");
+    }
+
+    file.seek(SeekFrom::Start(offset)).await?;
+    let size = file.read_u32().await?;
+    let mut box_type = [0; 4];
+    file.read_exact(&mut box_type).await?;
+
+    let (header, size) = match size {
+        0 => (8, end - offset),
+        1 => {
+            if end - offset < 16 {
+                corrupt!("Truncated box header at {offset}");
+            }
+
+            (16, file.read_u64().await?)
+        }
+        size => (8, size as u64),
+    };
+
+    if size < header || size > end - offset {
+        corrupt!(
+            "Box {} at {offset} extends past the end of its parent",
+            String::from_utf8_lossy(&box_type)
+        );
+    }
+
+    Ok((box_type, header, size))
+}
+
+/// Reads the duration from a movie header box.
+async fn read_mvhd(file: &mut File, offset: u64) -> Result<Option<Duration>> {
+    file.seek(SeekFrom::Start(offset)).await?;
+    let version = file.read_u32().await? >> 24;
+
+    let (timescale, duration) = if version == 1 {
+        file.seek(SeekFrom::Current(16)).await?;
+        (file.read_u32().await?, file.read_u64().await?)
+    } else {
+        file.seek(SeekFrom::Current(8)).await?;
+        (file.read_u32().await?, file.read_u32().await? as u64)
+    };
+
+    // Fragmented files list no duration here.
+    if timescale == 0 || duration == 0 || duration == u32::MAX as u64 || duration == u64::MAX {
+        return Ok(None);
+    }
+
+    match Duration::try_from_secs_f64(duration as f64 / timescale as f64) {
+        Ok(duration) => Ok(Some(duration)),
+        Err(_) => corrupt!("Invalid movie duration {duration} with timescale {timescale}"),
+    }
+}
+
+/// Checks that the top level boxes of an MP4 file exactly fill the file and
+/// that it has a movie header, returning the duration it lists.
+async fn mp4_duration(path: &Path) -> Result<Option<Duration>> {
+    let mut file = File::open(path).await?;
+    let len = file.metadata().await?.len();
+
+    let mut offset = 0;
+    let mut duration = None;
+    let mut has_moov = false;
+
+    while offset < len {
+        let (box_type, header, size) = read_box_header(&mut file, offset, len).await?;
+
+        if &box_type == b"moov" {
+            has_moov = true;
+
+            let end = offset + size;
+            let mut child = offset + header;
+            while child < end {
+                let (child_type, child_header, child_size) =
+                    read_box_header(&mut file, child, end).await?;
+
+                if &child_type == b"mvhd" {
+                    duration = read_mvhd(&mut file, child + child_header).await?;
+                }
+
+                child += child_size;
+            }
+        }
+
+        offset += size;
+    }
+
+    if !has_moov {
+        corrupt!("Missing movie box");
+    }
+
+    Ok(duration)
+}
+
+/// Parses a duration in the `HH:MM:SS.cc` form ffmpeg prints.
+fn parse_duration(str: &str) -> Option<Duration> {
+    let mut parts = str.splitn(3, ':');
+    let hours = parts.next()?.parse::<u64>().ok()?;
+    let minutes = parts.next()?.parse::<u64>().ok()?;
+    let seconds = parts.next()?.parse::<f64>().ok()?;
+
+    let whole = hours
+        .checked_mul(3600)?
+        .checked_add(minutes.checked_mul(60)?)?;
+    Duration::from_secs(whole).checked_add(Duration::try_from_secs_f64(seconds).ok()?)
+}
+
+/// Opens the file with ffmpeg and returns the duration it reports, failing if
+/// ffmpeg cannot read the file.
+async fn probe_duration(ffmpeg: &Path, path: &Path) -> Result<Option<Duration>> {
+    // Without an output ffmpeg only prints the input's details before exiting.
+    let output = Command::new(ffmpeg)
+        .arg("-hide_banner")
+        .arg("-i")
+        .arg(path)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    let Some(line) = stderr
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Duration: "))
+    else {
+        corrupt!(
+            "ffmpeg could not read the file: {}",
+            stderr.lines().last().unwrap_or_default().trim()
+        );
+    };
+
+    let duration = line.split(',').next().unwrap_or_default().trim();
+    if duration == "N/A" {
+        return Ok(None);
+    }
+
+    match parse_duration(duration) {
+        Some(duration) => Ok(Some(duration)),
+        None => corrupt!("ffmpeg reported an invalid duration: {duration}"),
+    }
+}
+
+/// Checks that a downloaded video file is intact. The container must be a
+/// recognised video format, MP4 files must be structurally complete, the
+/// duration must roughly match the expected duration when it can be
+/// determined and the checksum must match if one is known.
+///
+/// Returns a `Corrupt` error if the file is damaged, any other error means the
+/// check could not be completed.
+#[instrument(level = "trace", skip(ffmpeg, expected_checksum))]
+pub(crate) async fn check(
+    path: &Path,
+    ffmpeg: Option<&Path>,
+    expected_duration: Duration,
+    expected_checksum: Option<&str>,
+) -> Result {
+    match metadata(path).await {
+        Ok(stats) if stats.len() == 0 => corrupt!("File is empty"),
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => corrupt!("File is missing"),
+        Err(e) => return Err(e.into()),
+    }
+
+    let format = FileFormat::from_file(path)?;
+    let mime_type = Mime::from_str(format.media_type())?;
+    if mime_type.type_() != mime::VIDEO {
+        corrupt!("Unexpected file type {mime_type}");
+    }
+
+    let is_mp4 = matches!(
+        format,
+        FileFormat::Mpeg4Part14Video | FileFormat::AppleItunesVideo | FileFormat::AppleQuicktime
+    );
+
+    let mut duration = if is_mp4 {
+        mp4_duration(path).await?
+    } else {
+        None
+    };
+
+    if let Some(ffmpeg) = ffmpeg {
+        let probed = probe_duration(ffmpeg, path).await?;
+        duration = duration.or(probed);
+    }
+
+    if let Some(duration) = duration
+        && !expected_duration.is_zero()
+    {
+        let tolerance = DURATION_TOLERANCE.max(expected_duration / 100);
+        let difference = duration.abs_diff(expected_duration);
+
+        trace!(?duration, ?expected_duration, "Checking duration");
+        if difference > tolerance {
+            corrupt!(
+                "Duration of {}s does not match the expected {}s",
+                duration.as_secs(),
+                expected_duration.as_secs()
+            );
+        }
+    }
+
+    if let Some(expected_checksum) = expected_checksum {
+        let checksum = checksum(path).await?;
+        if checksum != expected_checksum {
+            corrupt!("Checksum does not match");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::integrity::parse_duration;
+
+    #[test]
+    fn durations() {
+        assert_eq!(
+            parse_duration("01:02:03.50"),
+            Some(Duration::from_millis(3_723_500))
+        );
+        assert_eq!(parse_duration("N/A"), None);
+        assert_eq!(parse_duration("00:00:-1.00"), None);
+        assert_eq!(parse_duration("00:00:inf"), None);
+        assert_eq!(parse_duration("00:00:NaN"), None);
+        assert_eq!(parse_duration(&format!("{}:00:00.00", u64::MAX)), None);
+    }
+}
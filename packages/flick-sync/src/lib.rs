@@ -5,18 +5,25 @@ use std::{
     ops::Deref,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
 mod config;
+mod export;
+mod integrity;
 mod schema;
+mod scrobble;
 mod server;
 mod state;
 mod sync;
+mod transcode;
 mod util;
 mod wrappers;
 
 use anyhow::bail;
-use config::{Config, ServerConfig, TranscodeProfile};
+use config::{
+    Artwork, Config, ExportConfig, OutputLayout, PlayerConfig, ServerConfig, TranscodeProfile,
+};
 use lazy_static::lazy_static;
 pub use plex_api;
 use plex_api::{
@@ -26,17 +33,26 @@ use plex_api::{
 use state::{ServerState, State};
 use time::OffsetDateTime;
 use tokio::{
-    fs::{read_dir, read_to_string, remove_dir_all, remove_file},
-    sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, Semaphore},
+    fs::{canonicalize, create_dir_all, read_dir, remove_dir_all, remove_file},
+    sync::{Mutex, Notify, RwLock, RwLockReadGuard, RwLockWriteGuard, Semaphore},
+    time::{Instant, sleep, timeout_at},
 };
 use tracing::{debug, error, info, instrument, warn};
 use uuid::Uuid;
 
-use crate::{config::H264Profile, schema::MigratableStore, util::safe_write};
+use crate::{
+    config::H264Profile, export::ExportManifest, schema::MigratableStore, sync::Throttle,
+    transcode::LocalStreams, util::safe_write,
+};
 pub use crate::{
-    config::{OutputStyle, ServerConnection},
-    server::{DownloadProgress, ItemType, Progress, Server, SyncItemInfo, SyncProgress},
-    state::{LibraryType, PlaybackState, PlaybackUpdates},
+    config::{
+        OutputStyle, OutputTemplates, PlayerApi, ServerConnection, SubtitlePreferences, SyncSource,
+    },
+    server::{
+        DownloadProgress, DownloadResult, ItemType, Progress, Server, SyncItemInfo, SyncOptions,
+        SyncProgress,
+    },
+    state::{LibraryType, PlaybackState, PlaybackUpdate, PlaybackUpdates, ServerPlaybackUpdates},
     sync::{LockedFile, LockedFileAsyncRead, LockedFileRead, Timeout},
     wrappers::*,
 };
@@ -48,6 +64,14 @@ pub const CONFIG_FILE: &str = "flicksync.json";
 pub const PLAYBACK_FILE: &str = ".flicksync.playback.json";
 
 pub(crate) const DEFAULT_PROFILE: &str = "720p";
+pub(crate) const DEFAULT_MAX_DOWNLOADS: usize = 4;
+/// How long playback must be left alone before changes are sent to servers.
+const PLAYBACK_DEBOUNCE: Duration = Duration::from_secs(10);
+/// The longest that continuous playback delays sending changes to servers.
+const PLAYBACK_MAX_DELAY: Duration = Duration::from_secs(60);
+/// The delays between attempts to send changes to unreachable servers.
+const PLAYBACK_MIN_BACKOFF: Duration = Duration::from_secs(30);
+const PLAYBACK_MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
 
 lazy_static! {
     static ref DEFAULT_PROFILES: HashMap<String, TranscodeProfile> = {
@@ -84,6 +108,13 @@ lazy_static! {
                 ..Default::default()
             },
         );
+        map.insert(
+            "original".to_string(),
+            TranscodeProfile {
+                direct_play: Some(true),
+                ..Default::default()
+            },
+        );
         map
     };
 }
@@ -94,11 +125,33 @@ struct Inner {
     path: PathBuf,
     servers: Mutex<HashMap<String, Server>>,
     download_permits: Arc<Semaphore>,
+    /// Local transcodes are CPU heavy so only run one at a time.
+    transcode_permits: Semaphore,
+    throttle: Throttle,
+    /// Notified when local playback state changes.
+    playback_changed: Notify,
 }
 
 impl Inner {
-    async fn output_style(&self) -> OutputStyle {
-        self.config.read().await.output_style
+    async fn output_layout(&self) -> OutputLayout {
+        let config = self.config.read().await;
+
+        OutputLayout {
+            style: config.output_style,
+            templates: config.output_templates.clone(),
+        }
+    }
+
+    /// The size to store artwork at. Posters are only used in the
+    /// standardized output style, otherwise they are stored as thumbnails.
+    async fn artwork_size(&self, artwork: Artwork) -> (u32, u32) {
+        let config = self.config.read().await;
+
+        if artwork == Artwork::Poster && config.output_style != OutputStyle::Standardized {
+            config.artwork.size(Artwork::Thumbnail)
+        } else {
+            config.artwork.size(artwork)
+        }
     }
 
     async fn transcode_options(&self, profile: &str) -> VideoTranscodeOptions {
@@ -116,6 +169,61 @@ impl Inner {
         }
     }
 
+    async fn subtitle_preferences(&self, profile: &str) -> Option<SubtitlePreferences> {
+        let config = self.config.read().await;
+        config
+            .profiles
+            .get(profile)
+            .or_else(|| DEFAULT_PROFILES.get(profile))
+            .and_then(|profile| profile.subtitles.clone())
+    }
+
+    async fn audio_languages(&self, profile: &str) -> Option<Vec<String>> {
+        let config = self.config.read().await;
+        config
+            .profiles
+            .get(profile)
+            .or_else(|| DEFAULT_PROFILES.get(profile))
+            .and_then(|profile| profile.audio_languages.clone())
+    }
+
+    /// The profile to check the original media against if direct play is
+    /// enabled for it.
+    async fn direct_play_profile(&self, profile: &str) -> Option<TranscodeProfile> {
+        let config = self.config.read().await;
+        config
+            .profiles
+            .get(profile)
+            .or_else(|| DEFAULT_PROFILES.get(profile))
+            .filter(|profile| profile.direct_play == Some(true))
+            .cloned()
+    }
+
+    /// Whether to record checksums of downloads.
+    async fn checksums(&self) -> bool {
+        self.config.read().await.checksums
+    }
+
+    /// The ffmpeg binary to use for local transcodes and tagging downloads, if
+    /// configured.
+    async fn ffmpeg(&self) -> Option<PathBuf> {
+        self.config.read().await.ffmpeg.clone()
+    }
+
+    /// The ffmpeg arguments that transcode a file locally to match the given
+    /// profile.
+    async fn ffmpeg_args(&self, profile: &str, streams: &LocalStreams) -> Result<Vec<String>> {
+        let config = self.config.read().await;
+        match config
+            .profiles
+            .get(profile)
+            .or_else(|| DEFAULT_PROFILES.get(profile))
+        {
+            Some(profile) => profile.ffmpeg_args(streams),
+            None => TranscodeProfile::default().ffmpeg_args(streams),
+        }
+    }
+
     async fn persist_config(&self, config: &RwLockWriteGuard<'_, Config>) -> Result {
         safe_write(self.path.join(CONFIG_FILE), &config.deref()).await?;
 
@@ -161,44 +269,34 @@ impl FlickSync {
 
         // Merge any pending playback updates written by Android.
         let playback_path = path.join(PLAYBACK_FILE);
-        match read_to_string(&playback_path).await {
-            Ok(str) => match serde_json::from_str::<PlaybackUpdates>(&str) {
-                Ok(updates) => {
-                    let mut changed = false;
-                    for (server_id, videos) in updates.servers {
-                        if let Some(server_state) = state.servers.get_mut(&server_id) {
-                            for (video_id, playback_state) in videos {
-                                if let Some(video) = server_state.videos.get_mut(&video_id) {
-                                    video.playback_state = playback_state;
-                                    changed = true;
-                                }
-                            }
-                        }
-                    }
-
-                    if changed {
-                        safe_write(path.join(STATE_FILE), &state).await?;
-                    }
+        match PlaybackUpdates::read(&playback_path).await {
+            Ok(Some((updates, at))) => {
+                if state.merge_playback_updates(&config, updates, at) {
+                    safe_write(path.join(STATE_FILE), &state).await?;
+                }
 
-                    if let Err(e) = remove_file(&playback_path).await
-                        && e.kind() != ErrorKind::NotFound
-                    {
-                        warn!(error = ?e, "Failed to delete playback file");
-                    }
+                if let Err(e) = remove_file(&playback_path).await
+                    && e.kind() != ErrorKind::NotFound
+                {
+                    warn!(error = ?e, "Failed to delete playback file");
                 }
-                Err(e) => warn!(error = ?e, "Failed to parse playback file"),
-            },
-            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            }
+            Ok(None) => {}
             Err(e) => warn!(error = ?e, "Failed to read playback file"),
         }
 
         Ok(Self {
             inner: Arc::new(Inner {
-                download_permits: Arc::new(Semaphore::new(config.max_downloads.unwrap_or(4))),
+                download_permits: Arc::new(Semaphore::new(
+                    config.max_downloads.unwrap_or(DEFAULT_MAX_DOWNLOADS),
+                )),
+                transcode_permits: Semaphore::new(1),
+                throttle: Throttle::new(&config),
                 config: RwLock::new(config),
                 state: RwLock::new(state),
                 path: path.to_owned(),
                 servers: Default::default(),
+                playback_changed: Notify::new(),
             }),
         })
     }
@@ -207,6 +305,12 @@ impl FlickSync {
         &self.inner.path
     }
 
+    /// Returns how long until downloads are allowed by the configured download
+    /// windows, or `None` if downloads are allowed now.
+    pub fn download_wait(&self) -> Option<Duration> {
+        self.inner.throttle.wait_time()
+    }
+
     pub async fn transcode_profiles(&self) -> Vec<String> {
         let config = self.inner.config.read().await;
         DEFAULT_PROFILES
@@ -247,6 +351,9 @@ impl FlickSync {
                 connection,
                 syncs: Default::default(),
                 transcode_profile,
+                max_bytes: None,
+                retain_days: None,
+                users: Default::default(),
             },
         );
 
@@ -256,25 +363,57 @@ impl FlickSync {
         Ok(())
     }
 
-    pub async fn on_deck(&self) -> Vec<Video> {
-        #[allow(clippy::mutable_key_type)]
+    /// The names of the additional users across all servers. Playback for
+    /// these users is tracked separately from the main user's.
+    pub async fn users(&self) -> Vec<String> {
+        let mut users = Vec::new();
+
+        for server in self.servers().await {
+            users.extend(server.users().await);
+        }
+
+        users.sort();
+        users.dedup();
+        users
+    }
+
+    /// The videos on deck for a user, or the main user if `None`.
+    pub async fn on_deck(&self, user: Option<&str>) -> Vec<Video> {
+        self.next_up(user, true).await.into_iter().collect()
+    }
+
+    /// Finds videos that are in progress or follow a recently played video for
+    /// a user, optionally only including those that are already downloaded.
+    #[allow(clippy::mutable_key_type)]
+    pub(crate) async fn next_up(
+        &self,
+        user: Option<&str>,
+        downloaded_only: bool,
+    ) -> HashSet<Video> {
         let mut items: HashSet<Video> = HashSet::new();
         let now = OffsetDateTime::now_utc();
 
         for server in self.servers().await {
+            // Users are configured per server.
+            if !server.has_user(user).await {
+                continue;
+            }
+
             for video in server.videos().await {
-                match video.playback_state().await {
+                match video.playback_state(user).await {
                     PlaybackState::Played => {
-                        if let Some(last_played) = video.last_played().await
+                        if let Some(last_played) = video.last_played(user).await
                             && (now - last_played).whole_days() <= 7
                             && let Some(next) = video.next_video().await
-                            && next.playback_state().await == PlaybackState::Unplayed
-                            && next.is_downloaded().await
+                            && next.playback_state(user).await == PlaybackState::Unplayed
+                            && (!downloaded_only || next.is_downloaded().await)
                         {
                             items.insert(next);
                         }
                     }
-                    PlaybackState::InProgress { .. } if video.is_downloaded().await => {
+                    PlaybackState::InProgress { .. }
+                        if !downloaded_only || video.is_downloaded().await =>
+                    {
                         items.insert(video);
                     }
                     _ => {}
@@ -282,10 +421,17 @@ impl FlickSync {
             }
         }
 
-        items.into_iter().collect()
+        items
     }
 
-    pub async fn update_output_style(&self, style: OutputStyle) -> Result {
+    /// Changes the output style, moving existing files to match. The
+    /// templates that are set in `templates` replace those used by the
+    /// template style, others are left unchanged.
+    pub async fn update_output_style(
+        &self,
+        style: OutputStyle,
+        templates: Option<OutputTemplates>,
+    ) -> Result {
         {
             let mut config = self.inner.config.write().await;
 
@@ -293,7 +439,17 @@ impl FlickSync {
             //     return Ok(());
             // }
 
+            let mut output_templates = config.output_templates.clone();
+            if let Some(templates) = templates {
+                output_templates.merge(templates);
+            }
+
+            if style == OutputStyle::Template {
+                output_templates.validate()?;
+            }
+
             config.output_style = style;
+            config.output_templates = output_templates;
             self.inner.persist_config(&config).await?;
         }
 
@@ -312,6 +468,249 @@ impl FlickSync {
         Ok(())
     }
 
+    /// Lists the directories that downloaded videos are exported to.
+    pub async fn exports(&self) -> Vec<(PathBuf, OutputStyle)> {
+        let config = self.inner.config.read().await;
+
+        config
+            .exports
+            .iter()
+            .map(|export| (export.path.clone(), export.output_style))
+            .collect()
+    }
+
+    /// Adds a directory to mirror downloaded videos into, laid out in the given
+    /// style, and performs the initial export. Files are hardlinked from the
+    /// store where possible and copied otherwise.
+    pub async fn add_export(&self, path: &Path, style: OutputStyle) -> Result {
+        create_dir_all(path).await?;
+        let path = canonicalize(path).await?;
+        let root = canonicalize(&self.inner.path).await?;
+
+        if path.starts_with(&root) || root.starts_with(&path) {
+            bail!("The export directory cannot overlap with the store");
+        }
+
+        {
+            let mut config = self.inner.config.write().await;
+
+            config.exports.retain(|export| export.path != path);
+            config.exports.push(ExportConfig {
+                path: path.clone(),
+                output_style: style,
+            });
+
+            self.inner.persist_config(&config).await?;
+        }
+
+        self.update_export(&path, style).await
+    }
+
+    /// Stops exporting to a directory, removing the files previously exported
+    /// there.
+    pub async fn remove_export(&self, path: &Path) -> Result<bool> {
+        let path = canonicalize(path).await.unwrap_or_else(|_| path.to_owned());
+
+        {
+            let mut config = self.inner.config.write().await;
+
+            let count = config.exports.len();
+            config.exports.retain(|export| export.path != path);
+            if config.exports.len() == count {
+                return Ok(false);
+            }
+
+            self.inner.persist_config(&config).await?;
+        }
+
+        export::clear_export(&path).await?;
+
+        Ok(true)
+    }
+
+    /// Brings every export directory in step with the downloaded videos.
+    #[instrument(skip_all)]
+    pub async fn update_exports(&self) {
+        for (path, style) in self.exports().await {
+            if let Err(e) = self.update_export(&path, style).await {
+                error!(error=?e, path=%path.display(), "Failed to update export");
+            }
+        }
+    }
+
+    /// Copies the given videos, along with their sidecar files, to a removable
+    /// drive laid out in the given style. Anything previously copied to the
+    /// drive that is not in the list is removed, after merging any playback
+    /// progress recorded on the drive. Videos not yet downloaded are skipped.
+    pub async fn export_drive(&self, path: &Path, videos: &[Video], style: OutputStyle) -> Result {
+        create_dir_all(path).await?;
+        self.import_drive(path).await?;
+
+        let layout = OutputLayout {
+            style,
+            templates: self.inner.output_layout().await.templates,
+        };
+
+        export::export_drive(
+            self.client_id().await,
+            videos,
+            &self.inner.path,
+            path,
+            &layout,
+        )
+        .await
+    }
+
+    /// Merges the playback progress recorded on a removable drive written by
+    /// `export_drive`, in the same way as pending updates in the store are
+    /// merged on startup. Returns whether anything changed.
+    pub async fn import_drive(&self, path: &Path) -> Result<bool> {
+        let manifest = ExportManifest::read(path).await?;
+
+        let Some(client_id) = manifest.client_id else {
+            return Ok(false);
+        };
+
+        if client_id != self.client_id().await {
+            bail!("The drive was written by a different store");
+        }
+
+        let Some((updates, at)) = PlaybackUpdates::read(&path.join(PLAYBACK_FILE)).await? else {
+            return Ok(false);
+        };
+
+        let changed = self
+            .merge_playback_updates(manifest.filter_updates(updates), at)
+            .await?;
+
+        if let Err(e) = remove_file(path.join(PLAYBACK_FILE)).await
+            && e.kind() != ErrorKind::NotFound
+        {
+            warn!(error = ?e, "Failed to delete playback file from drive");
+        }
+
+        Ok(changed)
+    }
+
+    /// Lists the media players whose playback progress is merged back.
+    pub async fn players(&self) -> Vec<String> {
+        let config = self.inner.config.read().await;
+
+        config
+            .players
+            .iter()
+            .map(|player| player.name.clone())
+            .collect()
+    }
+
+    /// Adds a media player that plays videos from the store or its exports,
+    /// replacing any player with the same name. Its playback progress is
+    /// recorded for the given additional user, or the main user if `None`.
+    pub async fn add_player(&self, name: &str, api: PlayerApi, user: Option<&str>) -> Result {
+        let mut config = self.inner.config.write().await;
+
+        if let Some(user) = user
+            && !config
+                .servers
+                .values()
+                .any(|server| server.users.contains_key(user))
+        {
+            bail!("Unknown user {user}");
+        }
+
+        config.players.retain(|player| player.name != name);
+        config.players.push(PlayerConfig {
+            name: name.to_owned(),
+            api,
+            user: user.map(str::to_owned),
+        });
+
+        self.inner.persist_config(&config).await
+    }
+
+    /// Removes a media player. Returns false if there was no such player.
+    pub async fn remove_player(&self, name: &str) -> Result<bool> {
+        let mut config = self.inner.config.write().await;
+
+        let count = config.players.len();
+        config.players.retain(|player| player.name != name);
+        if config.players.len() == count {
+            return Ok(false);
+        }
+
+        self.inner.persist_config(&config).await?;
+
+        Ok(true)
+    }
+
+    /// Merges the playback progress recorded by media players into the store,
+    /// from where it is pushed to the servers. Players are matched against
+    /// the files in the store and every export. Returns whether anything
+    /// changed.
+    #[instrument(skip_all)]
+    pub async fn scrobble(&self) -> bool {
+        let (players, mut layouts) = {
+            let config = self.inner.config.read().await;
+
+            let layouts: Vec<OutputLayout> = config
+                .exports
+                .iter()
+                .map(|export| OutputLayout {
+                    style: export.output_style,
+                    templates: config.output_templates.clone(),
+                })
+                .collect();
+
+            (config.players.clone(), layouts)
+        };
+
+        if players.is_empty() {
+            return false;
+        }
+
+        layouts.push(self.inner.output_layout().await);
+
+        let mut videos = Vec::new();
+        for server in self.servers().await {
+            videos.extend(server.videos().await);
+        }
+
+        let index = scrobble::FileIndex::build(&videos, &layouts).await;
+        let client = self.client().await;
+        let mut changed = false;
+
+        for player in players {
+            let updates = match scrobble::player_updates(&client, &player, &index).await {
+                Ok(updates) => updates,
+                Err(e) => {
+                    error!(player=player.name, error=?e, "Failed to read playback from player");
+                    continue;
+                }
+            };
+
+            match self
+                .merge_playback_updates(updates, OffsetDateTime::now_utc())
+                .await
+            {
+                Ok(merged) => changed |= merged,
+                Err(e) => error!(player=player.name, error=?e, "Failed to merge playback"),
+            }
+        }
+
+        changed
+    }
+
+    async fn update_export(&self, path: &Path, style: OutputStyle) -> Result {
+        info!(path=%path.display(), "Updating export");
+
+        let layout = OutputLayout {
+            style,
+            templates: self.inner.output_layout().await.templates,
+        };
+
+        export::export(&self.servers().await, &self.inner.path, path, &layout).await
+    }
+
     pub async fn server(&self, id: &str) -> Option<Server> {
         let mut servers = self.inner.servers.lock().await;
         if let Some(server) = servers.get(id) {
@@ -437,21 +836,83 @@ impl FlickSync {
         Ok(serde_json::to_string_pretty(&value)?)
     }
 
-    /// Applies playback state updates, merging them into the current state and persisting.
-    pub async fn apply_playback_updates(&self, updates: PlaybackUpdates) -> Result {
+    /// The current playback state of every video, in the format accepted by
+    /// `apply_playback_updates`.
+    pub async fn playback_json(&self) -> Result<String> {
+        let state = self.inner.state.read().await;
+
+        Ok(serde_json::to_string_pretty(&state.playback_updates())?)
+    }
+
+    /// Applies playback state updates from another player, merging them into
+    /// the current state. Updates without a time are assumed to have happened
+    /// now. Returns whether anything changed.
+    pub async fn apply_playback_updates(&self, updates: PlaybackUpdates) -> Result<bool> {
+        self.merge_playback_updates(updates, OffsetDateTime::now_utc())
+            .await
+    }
+
+    async fn merge_playback_updates(
+        &self,
+        updates: PlaybackUpdates,
+        at: OffsetDateTime,
+    ) -> Result<bool> {
+        let config = self.inner.config.read().await;
         let mut state = self.inner.state.write().await;
 
-        for (server_id, videos) in updates.servers {
-            if let Some(server_state) = state.servers.get_mut(&server_id) {
-                for (video_id, playback_state) in videos {
-                    if let Some(video) = server_state.videos.get_mut(&video_id) {
-                        video.playback_state = playback_state;
-                    }
+        let changed = state.merge_playback_updates(&config, updates, at);
+        if changed {
+            self.inner.persist_state(&state).await?;
+            self.inner.playback_changed.notify_one();
+        }
+
+        Ok(changed)
+    }
+
+    /// Sends local playback changes to the servers shortly after they happen
+    /// rather than waiting for the next sync. Changes are batched until
+    /// playback has settled and retried with an increasing delay while servers
+    /// are unreachable. Runs until dropped.
+    pub async fn sync_playback(&self) {
+        let mut backoff: Option<Duration> = None;
+
+        // Send anything left over from before startup.
+        self.inner.playback_changed.notify_one();
+
+        loop {
+            match backoff {
+                Some(delay) => sleep(delay).await,
+                None => self.inner.playback_changed.notified().await,
+            }
+
+            let deadline = Instant::now() + PLAYBACK_MAX_DELAY;
+            loop {
+                let wait = deadline.min(Instant::now() + PLAYBACK_DEBOUNCE);
+                if timeout_at(wait, self.inner.playback_changed.notified())
+                    .await
+                    .is_err()
+                {
+                    break;
                 }
             }
-        }
 
-        self.inner.persist_state(&state).await?;
-        Ok(())
+            let mut failed = false;
+            for server in self.servers().await {
+                if let Err(e) = server.sync_playback().await {
+                    warn!(server=server.id(), error=?e, "Failed to send playback changes");
+                    failed = true;
+                }
+            }
+
+            backoff = if failed {
+                let delay = backoff.map_or(PLAYBACK_MIN_BACKOFF, |delay| {
+                    (delay * 2).min(PLAYBACK_MAX_BACKOFF)
+                });
+                debug!(delay = delay.as_secs(), "Retrying playback changes later");
+                Some(delay)
+            } else {
+                None
+            };
+        }
     }
 }
@@ -0,0 +1,560 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail};
+use plex_api::{HttpClient, HttpClientBuilder};
+use serde::Deserialize;
+use serde_json::json;
+use time::{OffsetDateTime, PrimitiveDateTime, format_description};
+use tracing::{debug, trace};
+
+use crate::{
+    PlaybackState, PlaybackUpdate, PlaybackUpdates, Result, ServerPlaybackUpdates, Video,
+    config::{OutputLayout, PlayerApi, PlayerConfig},
+    util::{assume_local, encode_query},
+};
+
+/// Jellyfin measures positions in ticks of 100 nanoseconds.
+const TICKS_PER_MILLISECOND: u64 = 10_000;
+
+/// A video file as reported by a player.
+#[derive(Debug, PartialEq)]
+struct PlayerItem {
+    file: String,
+    played: bool,
+    /// The resume position in milliseconds.
+    position: u64,
+    last_played: Option<OffsetDateTime>,
+}
+
+/// Where a video part is stored.
+#[derive(Clone, Debug, PartialEq)]
+struct IndexedPart {
+    server: String,
+    video: String,
+    /// The position in the video at which the part starts, in milliseconds.
+    offset: u64,
+}
+
+/// Finds the video parts that a player's files refer to. Players may see the
+/// files at a different location, over a network share for instance, so files
+/// are matched by the longest trailing portion of their path that is known.
+#[derive(Default)]
+pub(crate) struct FileIndex {
+    parts: HashMap<String, IndexedPart>,
+}
+
+fn path_components(path: &str) -> Vec<&str> {
+    path.split(['/', '\\'])
+        .filter(|component| !component.is_empty())
+        .collect()
+}
+
+impl FileIndex {
+    /// Indexes the files of the downloaded videos as they would be stored in
+    /// each of the layouts.
+    pub(crate) async fn build(videos: &[Video], layouts: &[OutputLayout]) -> Self {
+        let mut index = Self::default();
+
+        for video in videos {
+            if !video.is_downloaded().await {
+                continue;
+            }
+
+            for part in video.parts().await {
+                let Some(extension) = part
+                    .download_state()
+                    .await
+                    .path()
+                    .and_then(|path| path.extension()?.to_str().map(str::to_owned))
+                else {
+                    continue;
+                };
+
+                let indexed = IndexedPart {
+                    server: video.server().id().to_owned(),
+                    video: video.id().to_owned(),
+                    offset: part.offset().await.as_millis() as u64,
+                };
+
+                for layout in layouts {
+                    if let Some(path) = part.layout_path(layout, &extension).await
+                        && let Some(path) = path.to_str()
+                    {
+                        index.insert(path, indexed.clone());
+                    }
+                }
+            }
+        }
+
+        index
+    }
+
+    fn insert(&mut self, path: &str, part: IndexedPart) {
+        self.parts.insert(path_components(path).join("/"), part);
+    }
+
+    fn find(&self, file: &str) -> Option<&IndexedPart> {
+        let components = path_components(file);
+
+        (0..components.len()).find_map(|start| self.parts.get(&components[start..].join("/")))
+    }
+}
+
+#[derive(Deserialize)]
+struct KodiResponse<T> {
+    result: Option<T>,
+    error: Option<KodiError>,
+}
+
+#[derive(Deserialize)]
+struct KodiError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct KodiMovies {
+    #[serde(default)]
+    movies: Vec<KodiItem>,
+}
+
+#[derive(Deserialize)]
+struct KodiEpisodes {
+    #[serde(default)]
+    episodes: Vec<KodiItem>,
+}
+
+#[derive(Deserialize)]
+struct KodiItem {
+    file: String,
+    #[serde(default)]
+    playcount: u32,
+    resume: Option<KodiResume>,
+    /// Local time, empty if never played.
+    #[serde(default)]
+    lastplayed: String,
+}
+
+#[derive(Deserialize)]
+struct KodiResume {
+    /// In seconds.
+    position: f64,
+}
+
+fn parse_kodi_time(time: &str) -> Option<OffsetDateTime> {
+    let format = format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").ok()?;
+    let time = PrimitiveDateTime::parse(time, &format).ok()?;
+
+    // Kodi reports times in its local time zone.
+    Some(assume_local(time))
+}
+
+impl From<KodiItem> for PlayerItem {
+    fn from(item: KodiItem) -> Self {
+        // Kodi joins multi-part videos together as a single stacked file.
+        let file = match item.file.strip_prefix("stack://") {
+            Some(stack) => stack.split(" , ").next().unwrap_or_default().to_owned(),
+            None => item.file,
+        };
+
+        Self {
+            file,
+            played: item.playcount > 0,
+            position: item
+                .resume
+                .map(|resume| (resume.position * 1000.0) as u64)
+                .unwrap_or_default(),
+            last_played: parse_kodi_time(&item.lastplayed),
+        }
+    }
+}
+
+impl<T> KodiResponse<T> {
+    fn into_result(self) -> Result<T> {
+        if let Some(error) = self.error {
+            bail!("Kodi returned error {}: {}", error.code, error.message);
+        }
+
+        self.result
+            .ok_or_else(|| anyhow!("Kodi returned an empty response"))
+    }
+}
+
+async fn kodi_request<T>(client: &HttpClient, method: &str) -> Result<T>
+where
+    T: for<'de> Deserialize<'de> + Unpin,
+{
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": {
+            "properties": ["file", "playcount", "resume", "lastplayed"],
+        },
+    });
+
+    let path = format!("/jsonrpc?request={}", encode_query(&request.to_string()));
+    client
+        .get(path)
+        .json::<KodiResponse<T>>()
+        .await?
+        .into_result()
+}
+
+async fn kodi_items(client: &HttpClient) -> Result<Vec<PlayerItem>> {
+    let movies: KodiMovies = kodi_request(client, "VideoLibrary.GetMovies").await?;
+    let episodes: KodiEpisodes = kodi_request(client, "VideoLibrary.GetEpisodes").await?;
+
+    Ok(movies
+        .movies
+        .into_iter()
+        .chain(episodes.episodes)
+        .map(PlayerItem::from)
+        .collect())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JellyfinItems {
+    #[serde(default)]
+    items: Vec<JellyfinItem>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JellyfinItem {
+    path: Option<String>,
+    user_data: Option<JellyfinUserData>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JellyfinUserData {
+    #[serde(default)]
+    played: bool,
+    #[serde(default)]
+    playback_position_ticks: u64,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    last_played_date: Option<OffsetDateTime>,
+}
+
+impl JellyfinItems {
+    fn into_items(self) -> Vec<PlayerItem> {
+        self.items
+            .into_iter()
+            .filter_map(|item| {
+                let user_data = item.user_data?;
+
+                Some(PlayerItem {
+                    file: item.path?,
+                    played: user_data.played,
+                    position: user_data.playback_position_ticks / TICKS_PER_MILLISECOND,
+                    last_played: user_data.last_played_date,
+                })
+            })
+            .collect()
+    }
+}
+
+async fn jellyfin_items(
+    client: &HttpClient,
+    api_key: &str,
+    user_id: &str,
+) -> Result<Vec<PlayerItem>> {
+    let path = format!(
+        "/Users/{}/Items?Recursive=true&IncludeItemTypes=Movie,Episode&Fields=Path&EnableUserData=true&api_key={}",
+        encode_query(user_id),
+        encode_query(api_key)
+    );
+
+    Ok(client.get(path).json::<JellyfinItems>().await?.into_items())
+}
+
+/// Converts the items a player reported into playback updates. Items the
+/// player has never played, or that aren't from the store, are skipped.
+fn updates_from_items(index: &FileIndex, items: Vec<PlayerItem>) -> ServerPlaybackUpdates {
+    let mut updates = ServerPlaybackUpdates::new();
+
+    for item in items {
+        let Some(last_played) = item.last_played else {
+            continue;
+        };
+
+        let Some(part) = index.find(&item.file) else {
+            trace!(file = item.file, "Ignoring unknown file");
+            continue;
+        };
+
+        let state = if item.position > 0 {
+            PlaybackState::InProgress {
+                position: part.offset + item.position,
+            }
+        } else if item.played {
+            PlaybackState::Played
+        } else {
+            PlaybackState::Unplayed
+        };
+
+        updates.entry(part.server.clone()).or_default().insert(
+            part.video.clone(),
+            PlaybackUpdate {
+                state,
+                at: Some(last_played),
+            },
+        );
+    }
+
+    updates
+}
+
+/// Reads the playback progress recorded by a player for the videos in the
+/// index.
+pub(crate) async fn player_updates(
+    client: &HttpClient,
+    player: &PlayerConfig,
+    index: &FileIndex,
+) -> Result<PlaybackUpdates> {
+    let items = match &player.api {
+        PlayerApi::Kodi { url } => {
+            let client = HttpClientBuilder::from(client.clone())
+                .set_api_url(url.as_str())
+                .build()?;
+            kodi_items(&client).await?
+        }
+        PlayerApi::Jellyfin {
+            url,
+            api_key,
+            user_id,
+        } => {
+            let client = HttpClientBuilder::from(client.clone())
+                .set_api_url(url.as_str())
+                .build()?;
+            jellyfin_items(&client, api_key, user_id).await?
+        }
+    };
+
+    debug!(
+        player = player.name,
+        count = items.len(),
+        "Read player items"
+    );
+    let servers = updates_from_items(index, items);
+
+    Ok(match &player.user {
+        Some(user) => PlaybackUpdates {
+            servers: Default::default(),
+            users: HashMap::from([(user.clone(), servers)]),
+        },
+        None => PlaybackUpdates {
+            servers,
+            users: Default::default(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use time::OffsetDateTime;
+
+    use crate::{
+        PlaybackState, PlaybackUpdate,
+        scrobble::{
+            FileIndex, IndexedPart, JellyfinItems, KodiEpisodes, KodiMovies, KodiResponse,
+            PlayerItem, updates_from_items,
+        },
+    };
+
+    fn index() -> FileIndex {
+        let mut index = FileIndex::default();
+
+        index.insert(
+            "Movies/Heat (1995)/Heat (1995).mkv",
+            IndexedPart {
+                server: "server".to_owned(),
+                video: "1".to_owned(),
+                offset: 0,
+            },
+        );
+        index.insert(
+            "TV Shows/Frasier/Season 01/Frasier - S01E01 - pt1.mkv",
+            IndexedPart {
+                server: "server".to_owned(),
+                video: "2".to_owned(),
+                offset: 0,
+            },
+        );
+        index.insert(
+            "TV Shows/Frasier/Season 01/Frasier - S01E01 - pt2.mkv",
+            IndexedPart {
+                server: "server".to_owned(),
+                video: "2".to_owned(),
+                offset: 600000,
+            },
+        );
+
+        index
+    }
+
+    #[test]
+    fn file_matching() {
+        let index = index();
+
+        assert_eq!(
+            index
+                .find("smb://nas/media/Movies/Heat (1995)/Heat (1995).mkv")
+                .map(|part| part.video.as_str()),
+            Some("1")
+        );
+        assert_eq!(
+            index
+                .find(r"D:\Export\TV Shows\Frasier\Season 01\Frasier - S01E01 - pt2.mkv")
+                .map(|part| part.offset),
+            Some(600000)
+        );
+        assert_eq!(index.find("/media/Movies/Heat (1995)/Other.mkv"), None);
+    }
+
+    #[test]
+    fn kodi() {
+        let movies: KodiResponse<KodiMovies> = serde_json::from_value(json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "result": {
+                "limits": { "end": 2, "start": 0, "total": 2 },
+                "movies": [
+                    {
+                        "movieid": 1,
+                        "label": "Heat",
+                        "file": "/media/Movies/Heat (1995)/Heat (1995).mkv",
+                        "playcount": 1,
+                        "resume": { "position": 0.0, "total": 0.0 },
+                        "lastplayed": "2024-03-01 20:15:00",
+                    },
+                    {
+                        "movieid": 2,
+                        "label": "Ronin",
+                        "file": "/media/Movies/Ronin (1998)/Ronin (1998).mkv",
+                        "playcount": 0,
+                        "resume": { "position": 0.0, "total": 0.0 },
+                        "lastplayed": "",
+                    },
+                ],
+            },
+        }))
+        .unwrap();
+        let episodes: KodiResponse<KodiEpisodes> = serde_json::from_value(json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "result": {
+                "episodes": [
+                    {
+                        "episodeid": 1,
+                        "label": "The Good Son",
+                        "file": "stack:///media/TV Shows/Frasier/Season 01/Frasier - S01E01 - pt1.mkv , /media/TV Shows/Frasier/Season 01/Frasier - S01E01 - pt2.mkv",
+                        "playcount": 0,
+                        "resume": { "position": 754.5, "total": 1320.0 },
+                        "lastplayed": "2024-03-02 21:00:00",
+                    },
+                ],
+            },
+        }))
+        .unwrap();
+
+        let items: Vec<PlayerItem> = movies
+            .into_result()
+            .unwrap()
+            .movies
+            .into_iter()
+            .chain(episodes.into_result().unwrap().episodes)
+            .map(PlayerItem::from)
+            .collect();
+
+        assert_eq!(items.len(), 3);
+        assert!(items[0].last_played.is_some());
+        assert_eq!(items[1].last_played, None);
+        assert_eq!(
+            items[2].file,
+            "/media/TV Shows/Frasier/Season 01/Frasier - S01E01 - pt1.mkv"
+        );
+
+        let updates = updates_from_items(&index(), items);
+        let videos = updates.get("server").unwrap();
+        assert_eq!(videos.len(), 2);
+        assert_eq!(videos.get("1").unwrap().state, PlaybackState::Played);
+        assert_eq!(
+            videos.get("2").unwrap().state,
+            PlaybackState::InProgress { position: 754500 }
+        );
+
+        let error: KodiResponse<KodiMovies> = serde_json::from_value(json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "error": { "code": -32601, "message": "Method not found." },
+        }))
+        .unwrap();
+        assert!(error.into_result().is_err());
+    }
+
+    #[test]
+    fn jellyfin() {
+        let items: JellyfinItems = serde_json::from_value(json!({
+            "Items": [
+                {
+                    "Name": "Heat",
+                    "Id": "a1",
+                    "Path": "/data/Movies/Heat (1995)/Heat (1995).mkv",
+                    "UserData": {
+                        "PlaybackPositionTicks": 0,
+                        "PlayCount": 2,
+                        "IsFavorite": false,
+                        "Played": true,
+                        "LastPlayedDate": "2024-03-01T20:15:00.0000000Z",
+                    },
+                },
+                {
+                    "Name": "The Good Son",
+                    "Id": "b2",
+                    "Path": "/data/TV Shows/Frasier/Season 01/Frasier - S01E01 - pt2.mkv",
+                    "UserData": {
+                        "PlaybackPositionTicks": 1200000000,
+                        "PlayCount": 0,
+                        "Played": false,
+                        "LastPlayedDate": "2024-03-02T21:00:00.0000000Z",
+                    },
+                },
+                {
+                    "Name": "Not Played",
+                    "Id": "c3",
+                    "Path": "/data/Movies/Ronin (1998)/Ronin (1998).mkv",
+                    "UserData": {
+                        "PlaybackPositionTicks": 0,
+                        "PlayCount": 0,
+                        "Played": false,
+                    },
+                },
+            ],
+            "TotalRecordCount": 3,
+            "StartIndex": 0,
+        }))
+        .unwrap();
+
+        let updates = updates_from_items(&index(), items.into_items());
+        let videos = updates.get("server").unwrap();
+        assert_eq!(videos.len(), 2);
+        assert_eq!(
+            videos.get("1"),
+            Some(&PlaybackUpdate {
+                state: PlaybackState::Played,
+                at: Some(OffsetDateTime::from_unix_timestamp(1709324100).unwrap()),
+            })
+        );
+        assert_eq!(
+            videos.get("2"),
+            Some(&PlaybackUpdate {
+                state: PlaybackState::InProgress { position: 720000 },
+                at: Some(OffsetDateTime::from_unix_timestamp(1709413200).unwrap()),
+            })
+        );
+    }
+}
@@ -1,9 +1,10 @@
 use std::{
-    cmp::Ordering,
+    cmp::{Ordering, Reverse},
     collections::{HashMap, HashSet},
     fmt,
     future::ready,
     io::ErrorKind,
+    iter,
     path::{Path, PathBuf},
     result,
     sync::Arc,
@@ -16,34 +17,49 @@ use futures::{
     future::{BoxFuture, join_all},
 };
 use plex_api::{
-    MyPlexBuilder,
+    HttpClient, HttpClientBuilder, MyPlexBuilder,
     device::DeviceConnection,
     library::{
         Episode, FromMetadata, Item, Library as PlexLibrary, MediaItem, MetadataItem, Movie,
         Playlist, Season, Show, Video,
     },
-    media_container::server::library::MetadataType,
+    media_container::server::library::{
+        AudioStream, Guid, Metadata, MetadataMediaContainer, MetadataType,
+    },
 };
 use scoped_futures::{ScopedBoxFuture, ScopedFutureExt};
+use serde::Deserialize;
+use time::OffsetDateTime;
 use tokio::{
     fs::{read_dir, remove_dir, remove_dir_all, remove_file},
     sync::{Mutex, RwLockMappedWriteGuard, RwLockWriteGuard},
 };
 use tracing::{debug, error, info, instrument, trace, warn};
+use uuid::Uuid;
 
 use crate::{
-    Collection, DEFAULT_PROFILE, DEFAULT_PROFILES, FileType, Inner, Library, Result,
+    Collection, DEFAULT_PROFILE, DEFAULT_PROFILES, FileType, FlickSync, Inner, Library, Result,
     ServerConnection, TransferState, VideoStats,
-    config::{Config, ServerConfig, SyncItem, TranscodeProfile},
+    config::{
+        Config, LibraryFilter, ServerConfig, SubtitlePreferences, SyncItem, SyncSource,
+        TranscodeProfile, preferred_audio_stream,
+    },
     state::{
-        CollectionState, DownloadState, LibraryState, LibraryType, PlaylistState, SeasonState,
-        ServerState, ShowState, VideoState,
+        CollectionState, DownloadState, LibraryState, LibraryType, PlaybackState, PlaylistState,
+        SeasonState, ServerState, ShowState, VideoState,
     },
     sync::{OpMutex, OpReadGuard, OpWriteGuard, Timeout},
-    util::{parallelize, safe},
+    util::{encode_query, parallelize, safe},
     wrappers,
 };
 
+/// Plex's API for an account's watchlist.
+const WATCHLIST_API_URL: &str = "https://metadata.provider.plex.tv/";
+/// The number of watchlist entries to request at a time.
+const WATCHLIST_PAGE_SIZE: usize = 50;
+/// The most videos fetched at once when syncing playback.
+const PLAYBACK_BATCH_SIZE: usize = 50;
+
 pub enum ItemType {
     Playlist,
     MovieCollection,
@@ -52,9 +68,31 @@ pub enum ItemType {
     Season,
     Episode,
     Movie,
+    Filter,
+    Source,
     Unknown,
 }
 
+/// Options controlling how a sync item is synced.
+#[derive(Default, Clone, Debug)]
+pub struct SyncOptions {
+    /// The transcode profile to use instead of the server default.
+    pub transcode_profile: Option<String>,
+    /// Only sync unplayed videos.
+    pub only_unplayed: bool,
+    /// For shows, only sync this many episodes following the last played
+    /// episode.
+    pub next_episodes: Option<u32>,
+    /// Delete downloads this many days after they were played.
+    pub retain_days: Option<u32>,
+    /// Videos from higher priority items are downloaded first.
+    pub priority: Option<i32>,
+    /// Overrides the transcode profile's subtitle preferences.
+    pub subtitles: Option<SubtitlePreferences>,
+    /// Overrides the transcode profile's preferred audio languages.
+    pub audio_languages: Option<Vec<String>>,
+}
+
 pub trait Progress: Unpin + Sized + Send + Sync {
     fn progress(&mut self, position: u64);
 
@@ -79,6 +117,18 @@ pub trait DownloadProgress: Sized {
     }
 }
 
+/// The outcome of downloading a server's missing videos.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadResult {
+    /// Every video was downloaded.
+    Complete,
+    /// Some videos failed to download.
+    Incomplete,
+    /// Downloads were stopped because they are outside of the allowed download
+    /// windows.
+    Skipped,
+}
+
 pub trait SyncProgress {
     type DP: DownloadProgress;
 
@@ -96,63 +146,73 @@ pub struct SyncItemInfo {
     pub title: String,
     pub transcode_profile: Option<String>,
     pub only_unplayed: bool,
+    pub next_episodes: Option<u32>,
+    pub priority: i32,
+    /// For library filters, the filter query.
+    pub filter: Option<String>,
 }
 
 impl SyncItemInfo {
-    async fn show_stats(show: wrappers::Show) -> VideoStats {
-        let mut stats = VideoStats::default();
+    async fn show_videos(show: wrappers::Show) -> Vec<wrappers::Video> {
+        let mut videos = Vec::new();
 
         for season in show.seasons().await {
-            stats += Self::season_stats(season).await;
+            videos.extend(Self::season_videos(season).await);
         }
 
-        stats
+        videos
     }
 
-    async fn season_stats(season: wrappers::Season) -> VideoStats {
-        let mut stats = VideoStats::default();
-
-        for video in season.episodes().await {
-            stats += video.stats().await;
-        }
-
-        stats
+    async fn season_videos(season: wrappers::Season) -> Vec<wrappers::Video> {
+        season
+            .episodes()
+            .await
+            .into_iter()
+            .map(wrappers::Video::Episode)
+            .collect()
     }
 
-    pub async fn stats(&self) -> VideoStats {
-        if let Some(playlist) = self.server.playlist(&self.id).await {
-            let mut stats = VideoStats::default();
-
-            for video in playlist.videos().await {
-                stats += video.stats().await;
-            }
-
-            stats
+    /// The videos currently included by this sync item.
+    pub async fn videos(&self) -> Vec<wrappers::Video> {
+        if matches!(self.item_type, ItemType::Filter | ItemType::Source) {
+            self.server.resolved_videos(&self.id).await
+        } else if let Some(playlist) = self.server.playlist(&self.id).await {
+            playlist.videos().await
         } else if let Some(collection) = self.server.collection(&self.id).await {
-            let mut stats = VideoStats::default();
-
             match collection {
-                Collection::Movie(c) => {
-                    for video in c.movies().await {
-                        stats += video.stats().await;
-                    }
-                }
+                Collection::Movie(c) => c
+                    .movies()
+                    .await
+                    .into_iter()
+                    .map(wrappers::Video::Movie)
+                    .collect(),
                 Collection::Show(c) => {
+                    let mut videos = Vec::new();
                     for show in c.shows().await {
-                        stats += Self::show_stats(show).await
+                        videos.extend(Self::show_videos(show).await);
                     }
+                    videos
                 }
             }
-            stats
         } else if let Some(show) = self.server.show(&self.id).await {
-            Self::show_stats(show).await
+            Self::show_videos(show).await
         } else if let Some(season) = self.server.season(&self.id).await {
-            Self::season_stats(season).await
+            Self::season_videos(season).await
         } else if let Some(video) = self.server.video(&self.id).await {
-            video.stats().await
+            vec![video]
         } else {
-            VideoStats::default()
+            Vec::new()
+        }
+    }
+
+    pub async fn stats(&self) -> VideoStats {
+        let mut stats = VideoStats::default();
+
+        for video in self.videos().await {
+            stats += video.stats().await;
         }
+
+        stats
     }
 }
 
@@ -160,7 +220,8 @@ impl SyncItemInfo {
 pub struct Server {
     pub(crate) id: String,
     pub(crate) inner: Arc<Inner>,
-    connection: Arc<Mutex<Option<plex_api::Server>>>,
+    /// API connections by user, `None` being the main user.
+    connections: Arc<Mutex<HashMap<Option<String>, plex_api::Server>>>,
 }
 
 impl fmt::Debug for Server {
@@ -242,7 +303,7 @@ impl Server {
         Self {
             id: id.to_owned(),
             inner: inner.clone(),
-            connection: Arc::new(Mutex::new(None)),
+            connections: Default::default(),
         }
     }
 
@@ -295,7 +356,19 @@ impl Server {
         let mut results: Vec<SyncItemInfo> = Vec::new();
 
         for sync in server_config.syncs.values() {
-            let (title, item_type) = if let Some(playlist) = self.playlist(&sync.id).await {
+            let (title, item_type) = if let Some(ref filter) = sync.filter {
+                let title = match self.library(&filter.library).await {
+                    Some(library) => library.title().await,
+                    None => format!("Library {}", filter.library),
+                };
+                (title, ItemType::Filter)
+            } else if let Some(source) = sync.source {
+                let title = match source {
+                    SyncSource::Watchlist => "Watchlist",
+                    SyncSource::OnDeck => "Continue Watching",
+                };
+                (title.to_string(), ItemType::Source)
+            } else if let Some(playlist) = self.playlist(&sync.id).await {
                 (playlist.title().await, ItemType::Playlist)
             } else if let Some(collection) = self.collection(&sync.id).await {
                 match collection {
@@ -322,6 +395,9 @@ impl Server {
                 title,
                 transcode_profile: sync.transcode_profile.clone(),
                 only_unplayed: sync.only_unplayed,
+                next_episodes: sync.next_episodes,
+                priority: sync.priority.unwrap_or_default(),
+                filter: sync.filter.as_ref().map(|filter| filter.query.clone()),
             });
         }
 
@@ -344,7 +420,7 @@ impl Server {
     }
 
     pub async fn update_connection(&self, auth_token: &str, server: plex_api::Server) -> Result {
-        let mut connection = self.connection.lock().await;
+        let mut connections = self.connections.lock().await;
         let mut state = self.inner.state.write().await;
 
         let server_state = state.servers.entry(self.id.to_owned()).or_default();
@@ -352,11 +428,107 @@ impl Server {
         server_state.name = server.media_container.friendly_name;
 
         self.inner.persist_state(&state).await?;
-        *connection = None;
+        connections.clear();
 
         Ok(())
     }
 
+    /// The names of the additional Plex Home users whose playback is tracked
+    /// separately from the main user's.
+    pub async fn users(&self) -> Vec<String> {
+        let config = self.inner.config.read().await;
+        let mut users: Vec<String> = config
+            .servers
+            .get(&self.id)
+            .map(|server_config| server_config.users.keys().cloned().collect())
+            .unwrap_or_default();
+        users.sort();
+        users
+    }
+
+    /// Whether playback is tracked for the user on this server. The main user,
+    /// `None`, always is.
+    pub async fn has_user(&self, user: Option<&str>) -> bool {
+        let Some(user) = user else {
+            return true;
+        };
+
+        let config = self.inner.config.read().await;
+        config
+            .servers
+            .get(&self.id)
+            .is_some_and(|server_config| server_config.users.contains_key(user))
+    }
+
+    /// Adds a Plex Home user whose playback is tracked separately from the main
+    /// user's. `plex_user` is the user's name or ID in Plex Home.
+    pub async fn add_user(&self, name: &str, plex_user: &str) -> Result {
+        if name.is_empty() || name.contains('/') {
+            bail!("Invalid user name {name}");
+        }
+
+        if !matches!(self.connection().await, ServerConnection::MyPlex { .. }) {
+            bail!("Additional users require a MyPlex connection");
+        }
+
+        let token = {
+            let state = self.inner.state.read().await;
+            state
+                .servers
+                .get(&self.id)
+                .ok_or_else(|| anyhow!("No longer authenticated."))?
+                .token
+                .clone()
+        };
+
+        let myplex = MyPlexBuilder::default()
+            .set_client(self.inner.client().await)
+            .set_token(token)
+            .set_test_token_auth(false)
+            .build()
+            .await?;
+
+        let users = myplex.home()?.users().await?;
+        let user = users
+            .iter()
+            .find(|user| user.uuid == plex_user || user.title.eq_ignore_ascii_case(plex_user))
+            .ok_or_else(|| anyhow!("No Plex Home user named {plex_user}"))?;
+
+        let mut config = self.inner.config.write().await;
+        let server_config = config.servers.get_mut(&self.id).unwrap();
+        server_config
+            .users
+            .insert(name.to_owned(), user.uuid.clone());
+        self.inner.persist_config(&config).await?;
+
+        Ok(())
+    }
+
+    /// Removes an additional user along with their playback progress. Returns
+    /// false if there was no such user.
+    pub async fn remove_user(&self, name: &str) -> Result<bool> {
+        {
+            let mut config = self.inner.config.write().await;
+            let server_config = config.servers.get_mut(&self.id).unwrap();
+            if server_config.users.remove(name).is_none() {
+                return Ok(false);
+            }
+            self.inner.persist_config(&config).await?;
+        }
+
+        self.connections.lock().await.remove(&Some(name.to_owned()));
+
+        let mut state = self.inner.state.write().await;
+        if let Some(server_state) = state.servers.get_mut(&self.id) {
+            for video in server_state.videos.values_mut() {
+                video.user_playback.remove(name);
+            }
+        }
+        self.inner.persist_state(&state).await?;
+
+        Ok(true)
+    }
+
     pub async fn name(&self) -> String {
         let state = self.inner.state.read().await;
         state.servers.get(&self.id).unwrap().name.clone()
@@ -385,6 +557,28 @@ impl Server {
             .collect()
     }
 
+    /// The videos last resolved for a filter or source sync.
+    async fn resolved_videos(&self, id: &str) -> Vec<wrappers::Video> {
+        let keys = {
+            let state = self.inner.state.read().await;
+            state
+                .servers
+                .get(&self.id)
+                .and_then(|ss| ss.resolved.get(id))
+                .cloned()
+                .unwrap_or_default()
+        };
+
+        let mut videos = Vec::new();
+        for key in keys {
+            if let Some(video) = self.video(&key).await {
+                videos.push(video);
+            }
+        }
+
+        videos
+    }
+
     pub async fn show(&self, id: &str) -> Option<wrappers::Show> {
         let state = self.inner.state.read().await;
         state
@@ -518,14 +712,21 @@ impl Server {
     }
 
     /// Connects to the Plex API for this server.
-    #[instrument(level = "trace", skip(self), fields(server = self.id))]
     pub async fn connect(&self) -> Result<plex_api::Server> {
-        let mut connection = self.connection.lock().await;
+        self.connect_as(None).await
+    }
 
-        if let Some(api) = connection.take()
+    /// Connects to the Plex API for this server as one of its additional users,
+    /// or as the main user if `None`.
+    #[instrument(level = "trace", skip(self), fields(server = self.id))]
+    pub(crate) async fn connect_as(&self, user: Option<&str>) -> Result<plex_api::Server> {
+        let mut connections = self.connections.lock().await;
+        let key = user.map(ToOwned::to_owned);
+
+        if let Some(api) = connections.remove(&key)
             && let Ok(api) = api.refresh().await
         {
-            *connection = Some(api.clone());
+            connections.insert(key, api.clone());
             return Ok(api);
         }
 
@@ -540,6 +741,14 @@ impl Server {
             ServerConnection::MyPlex {
                 user_id, device_id, ..
             } => {
+                let user_id = match user {
+                    Some(user) => server_config
+                        .users
+                        .get(user)
+                        .ok_or_else(|| anyhow!("Unknown user {user}"))?,
+                    None => user_id,
+                };
+
                 let token = state
                     .servers
                     .get(&self.id)
@@ -573,13 +782,17 @@ impl Server {
                         trace!(url=%server.client().api_url,
                             "Connected to server"
                         );
-                        *connection = Some(server.as_ref().clone());
+                        connections.insert(key, server.as_ref().clone());
                         Ok(*server)
                     }
                     _ => panic!("Unexpected client connection"),
                 }
             }
             ServerConnection::Direct { url } => {
+                if let Some(user) = user {
+                    bail!("User {user} requires a MyPlex connection");
+                }
+
                 let token = state
                     .servers
                     .get(&self.id)
@@ -591,26 +804,26 @@ impl Server {
                 trace!(url=%server.client().api_url,
                     "Connected to server",
                 );
-                *connection = Some(server.clone());
+                connections.insert(key, server.clone());
 
                 Ok(server)
             }
         }
     }
 
-    /// Adds an item to sync based on its rating key.
-    pub async fn add_sync(
+    async fn insert_sync(
         &self,
-        rating_key: &str,
-        transcode_profile: Option<String>,
-        only_unplayed: bool,
+        id: String,
+        options: SyncOptions,
+        filter: Option<LibraryFilter>,
+        source: Option<SyncSource>,
     ) -> Result {
         #[expect(unused)]
         let guard = self.try_lock_write().await?;
 
         let mut config = self.inner.config.write().await;
 
-        if let Some(ref profile) = transcode_profile
+        if let Some(ref profile) = options.transcode_profile
             && !config.profiles.contains_key(profile)
             && !DEFAULT_PROFILES.contains_key(profile)
         {
@@ -619,17 +832,63 @@ impl Server {
 
         let server_config = config.servers.get_mut(&self.id).unwrap();
         server_config.syncs.insert(
-            rating_key.to_owned(),
+            id.clone(),
             SyncItem {
-                id: rating_key.to_owned(),
-                transcode_profile,
-                only_unplayed,
+                id,
+                transcode_profile: options.transcode_profile,
+                only_unplayed: options.only_unplayed,
+                next_episodes: options.next_episodes,
+                retain_days: options.retain_days,
+                priority: options.priority,
+                filter,
+                source,
+                subtitles: options.subtitles,
+                audio_languages: options.audio_languages,
             },
         );
 
         self.inner.persist_config(&config).await
     }
 
+    /// Adds an item to sync based on its rating key.
+    pub async fn add_sync(&self, rating_key: &str, options: SyncOptions) -> Result {
+        self.insert_sync(rating_key.to_owned(), options, None, None)
+            .await
+    }
+
+    /// Adds a filter on a library section to sync. The filter uses Plex's
+    /// query parameters, e.g. `unwatched=1&genre=Documentary`. Returns the ID
+    /// of the new sync item.
+    pub async fn add_filter_sync(
+        &self,
+        library: &str,
+        query: &str,
+        options: SyncOptions,
+    ) -> Result<String> {
+        let filter = LibraryFilter::new(library, query)?;
+        let id = format!("filter-{}", &Uuid::new_v4().simple().to_string()[..8]);
+
+        self.insert_sync(id.clone(), options, Some(filter), None)
+            .await?;
+
+        Ok(id)
+    }
+
+    /// Adds a list maintained by Plex to sync. Returns the ID of the new sync
+    /// item.
+    pub async fn add_source_sync(
+        &self,
+        source: SyncSource,
+        options: SyncOptions,
+    ) -> Result<String> {
+        let id = source.to_string();
+
+        self.insert_sync(id.clone(), options, None, Some(source))
+            .await?;
+
+        Ok(id)
+    }
+
     /// Removes an item to sync based on its rating key. Returns true if the item existed.
     pub async fn remove_sync(&self, rating_key: &str) -> Result<bool> {
         #[expect(unused)]
@@ -674,19 +933,37 @@ impl Server {
                 seen_items: Default::default(),
                 seen_libraries: Default::default(),
                 transcode_profiles: Default::default(),
+                retention: Default::default(),
+                resolved: Default::default(),
+                subtitle_preferences: Default::default(),
+                audio_languages: Default::default(),
+                audio_streams: Default::default(),
                 allow_video_deletion,
             };
 
             state_sync.update_videos().await;
 
             for item in server_config.syncs.values() {
-                if let Err(e) = state_sync.add_item_by_key(item, &item.id).await {
+                let result = if let Some(ref filter) = item.filter {
+                    state_sync.add_filter(item, filter).await
+                } else if let Some(source) = item.source {
+                    state_sync.add_source(item, source).await
+                } else {
+                    state_sync.add_item_by_key(item, &item.id).await
+                };
+
+                if let Err(e) = result {
                     warn!(item=item.id, error=?e, "Failed to update item. Skipping.");
                 }
             }
 
+            state_sync.update_resolved().await;
+            state_sync.update_user_playback().await;
+            state_sync.update_video_preferences().await;
+
             if allow_video_deletion {
                 state_sync.update_profiles().await?;
+                state_sync.update_audio_streams().await;
             }
 
             state_sync.fetch_collections().await?;
@@ -701,6 +978,7 @@ impl Server {
 
         self.update_thumbnails(false).await;
         self.update_metadata(false).await;
+        self.update_subtitles().await;
         self.verify_downloads(allow_video_deletion).await;
         self.write_playlists().await;
 
@@ -789,6 +1067,10 @@ impl Server {
                                 if let Err(e) = video.update_thumbnail(rebuild).await {
                                     warn!(error=?e);
                                 }
+
+                                if let Err(e) = video.update_fanart(rebuild).await {
+                                    warn!(error=?e);
+                                }
                             }
                             .boxed(),
                         );
@@ -802,11 +1084,25 @@ impl Server {
                                 if let Err(e) = inner.update_thumbnail(rebuild).await {
                                     warn!(error=?e);
                                 }
+
+                                if let Err(e) = inner.update_fanart(rebuild).await {
+                                    warn!(error=?e);
+                                }
                             }
                             .boxed(),
                         );
 
                         for season in show.seasons().await {
+                            let inner = season.clone();
+                            jobs.push(
+                                async move {
+                                    if let Err(e) = inner.update_thumbnail(rebuild).await {
+                                        warn!(error=?e);
+                                    }
+                                }
+                                .boxed(),
+                            );
+
                             for video in season.episodes().await {
                                 jobs.push(
                                     async move {
@@ -826,6 +1122,28 @@ impl Server {
         parallelize(jobs, 20).await;
     }
 
+    /// Updates sidecar subtitle files for synced videos.
+    #[instrument(level = "trace", skip(self), fields(server = self.id))]
+    async fn update_subtitles(&self) {
+        info!("Updating subtitles");
+
+        let jobs: Vec<BoxFuture<()>> = self
+            .videos()
+            .await
+            .into_iter()
+            .map(|video| {
+                async move {
+                    if let Err(e) = video.update_subtitles().await {
+                        warn!(error=?e);
+                    }
+                }
+                .boxed()
+            })
+            .collect();
+
+        parallelize(jobs, 10).await;
+    }
+
     /// Updates metadata files for synced videos.
     #[instrument(level = "trace", skip(self), fields(server = self.id))]
     async fn update_metadata(&self, rebuild: bool) {
@@ -911,12 +1229,35 @@ impl Server {
         .await;
     }
 
-    /// Attempts to transcode and download all missing items.
+    /// The disk space currently used by completed downloads for this server.
+    async fn downloaded_bytes(&self) -> u64 {
+        let mut total = 0;
+
+        for video in self.videos().await {
+            if video.is_downloaded().await {
+                total += video.estimated_size().await;
+            }
+        }
+
+        total
+    }
+
+    /// Attempts to transcode and download all missing items. Items that would
+    /// exceed the configured storage budgets are skipped and nothing is
+    /// downloaded outside of the configured download windows.
     #[instrument(level = "trace", skip(self, sync_progress), fields(server = self.id))]
-    pub async fn download<SP>(&self, mut sync_progress: SP) -> Result<bool>
+    pub async fn download<SP>(&self, mut sync_progress: SP) -> Result<DownloadResult>
     where
         SP: SyncProgress,
     {
+        if let Some(wait) = self.inner.throttle.wait_time() {
+            info!(
+                wait = wait.as_secs(),
+                "Outside of the allowed download windows, skipping downloads"
+            );
+            return Ok(DownloadResult::Skipped);
+        }
+
         let plex_server = match self.connect().await {
             Ok(ps) => ps,
             Err(e) => {
@@ -925,21 +1266,240 @@ impl Server {
             }
         };
 
-        let mut jobs = Vec::new();
+        let (store_budget, server_budget) = {
+            let config = self.inner.config.read().await;
+            (
+                config.max_bytes,
+                config.servers.get(&self.id).and_then(|sc| sc.max_bytes),
+            )
+        };
+
+        let mut server_used = 0;
+        let mut pending = Vec::new();
 
         for video in self.videos().await {
             match video.transfer_state().await {
-                TransferState::Downloaded => {}
-                _ => {
-                    let download_progress = sync_progress.download_progress(&video).await;
-                    jobs.push(video.download(plex_server.clone(), download_progress));
+                TransferState::Downloaded => server_used += video.estimated_size().await,
+                TransferState::Expired => {}
+                _ => pending.push(video),
+            }
+        }
+
+        let mut budget = StorageBudget {
+            store_max: store_budget,
+            server_max: server_budget,
+            store_used: server_used,
+            server_used,
+        };
+        if store_budget.is_some() {
+            let flick_sync = FlickSync {
+                inner: self.inner.clone(),
+            };
+
+            for server in flick_sync.servers().await {
+                if server.id != self.id {
+                    budget.store_used += server.downloaded_bytes().await;
                 }
             }
         }
 
+        let mut jobs = Vec::new();
+
+        for video in self.schedule_downloads(pending).await {
+            let size = video.estimated_size().await;
+
+            if !budget.reserve(size) {
+                info!(
+                    video = video.id(),
+                    size, "Skipping download that would exceed the storage budget"
+                );
+                continue;
+            }
+
+            let download_progress = sync_progress.download_progress(&video).await;
+            jobs.push(video.download(plex_server.clone(), download_progress));
+        }
+
         sync_progress.jobs(jobs.len()).await;
 
-        Ok(join_all(jobs).await.into_iter().all(|r| r))
+        // Jobs are in priority order so the highest priority videos are the
+        // first to wait for a download slot.
+        let results = join_all(jobs).await;
+
+        Ok(if results.contains(&DownloadResult::Incomplete) {
+            DownloadResult::Incomplete
+        } else if results.contains(&DownloadResult::Skipped) {
+            DownloadResult::Skipped
+        } else {
+            DownloadResult::Complete
+        })
+    }
+
+    /// Sorts videos into the order they should be downloaded in. Videos that
+    /// are on deck come first, then videos from higher priority sync items,
+    /// then downloads that have already started, and finally by air date and
+    /// episode order.
+    async fn schedule_downloads(&self, videos: Vec<wrappers::Video>) -> Vec<wrappers::Video> {
+        let flick_sync = FlickSync {
+            inner: self.inner.clone(),
+        };
+        #[allow(clippy::mutable_key_type)]
+        let mut next_up = flick_sync.next_up(None, false).await;
+        for user in flick_sync.users().await {
+            next_up.extend(flick_sync.next_up(Some(&user), false).await);
+        }
+
+        let mut priorities: HashMap<String, i32> = HashMap::new();
+        for sync in self.list_syncs().await {
+            for video in sync.videos().await {
+                priorities
+                    .entry(video.id().to_owned())
+                    .and_modify(|priority| *priority = (*priority).max(sync.priority))
+                    .or_insert(sync.priority);
+            }
+        }
+
+        let mut scheduled = Vec::new();
+        for video in videos {
+            let started = !matches!(video.transfer_state().await, TransferState::Waiting);
+            let air_date = video.air_date().await;
+            let episode_order = match video {
+                wrappers::Video::Episode(ref episode) => {
+                    Some((episode.season().await.index().await, episode.index().await))
+                }
+                wrappers::Video::Movie(_) => None,
+            };
+
+            let key = (
+                Reverse(next_up.contains(&video)),
+                Reverse(priorities.get(video.id()).copied().unwrap_or_default()),
+                Reverse(started),
+                air_date.is_none(),
+                air_date,
+                episode_order,
+            );
+
+            scheduled.push((key, video));
+        }
+
+        scheduled.sort_by_key(|(key, _)| *key);
+
+        scheduled.into_iter().map(|(_, video)| video).collect()
+    }
+
+    /// Checks that every downloaded file is intact, resetting any that are not
+    /// so they are downloaded again by the next sync. Returns the number of
+    /// corrupt files found.
+    #[instrument(level = "trace", skip(self), fields(server = self.id))]
+    pub async fn verify(&self) -> usize {
+        let mut corrupt = 0;
+
+        for video in self.videos().await {
+            match video.verify_integrity().await {
+                Ok(count) => corrupt += count,
+                Err(e) => warn!(video = video.id(), error=?e, "Failed to verify downloads"),
+            }
+        }
+
+        corrupt
+    }
+
+    /// Pushes local playback changes that the server has not yet seen. Fails
+    /// if the server could not be reached or rejected any of the changes.
+    #[instrument(level = "trace", skip(self), fields(server = self.id))]
+    pub(crate) async fn sync_playback(&self) -> Result {
+        let mut failed = false;
+
+        for user in self.playback_users().await {
+            let pending: Vec<String> = {
+                let state = self.inner.state.read().await;
+                let Some(server_state) = state.servers.get(&self.id) else {
+                    return Ok(());
+                };
+
+                server_state
+                    .videos
+                    .values()
+                    .filter(|video| video.playback(user.as_deref()).has_pending_playback())
+                    .map(|video| video.id.clone())
+                    .collect()
+            };
+
+            if pending.is_empty() {
+                continue;
+            }
+
+            debug!(?user, count = pending.len(), "Pushing playback changes");
+            if let Err(e) = self.sync_user_playback(user.as_deref(), pending).await {
+                warn!(?user, error=?e, "Failed to update playback state on the server");
+                failed = true;
+            }
+        }
+
+        if failed {
+            bail!("Failed to update playback state on the server");
+        }
+
+        Ok(())
+    }
+
+    /// The main user, as `None`, followed by the additional users.
+    async fn playback_users(&self) -> Vec<Option<String>> {
+        let mut users = vec![None];
+        users.extend(self.users().await.into_iter().map(Some));
+        users
+    }
+
+    /// Reconciles a user's playback progress for the given videos with the
+    /// server. The videos are fetched in batches.
+    async fn sync_user_playback(&self, user: Option<&str>, videos: Vec<String>) -> Result {
+        let plex_server = self.connect_as(user).await?;
+        let mut failed = false;
+
+        for ids in videos.chunks(PLAYBACK_BATCH_SIZE) {
+            let items = fetch_items(
+                plex_server.client(),
+                format!("/library/metadata/{}", ids.join(",")),
+            )
+            .await?;
+
+            if items.len() < ids.len() {
+                warn!(
+                    missing = ids.len() - items.len(),
+                    "Some videos no longer appear to exist"
+                );
+            }
+
+            let mut state = self.inner.state.write().await;
+            let Some(server_state) = state.servers.get_mut(&self.id) else {
+                return Ok(());
+            };
+
+            for item in items {
+                let synced = match item {
+                    Item::Movie(movie) => {
+                        sync_item_playback(server_state, user, &movie, &plex_server).await
+                    }
+                    Item::Episode(episode) => {
+                        sync_item_playback(server_state, user, &episode, &plex_server).await
+                    }
+                    _ => {
+                        warn!("Unexpected remote item type for video");
+                        true
+                    }
+                };
+
+                failed |= !synced;
+            }
+
+            self.inner.persist_state(&state).await?;
+        }
+
+        if failed {
+            bail!("The server rejected some playback changes");
+        }
+
+        Ok(())
     }
 
     /// Verifies the presence of downloads for synced items.
@@ -981,23 +1541,45 @@ impl Server {
                 expected_files.insert(self.inner.path.join(file));
             }
 
+            if let Some(file) = show.fanart.path() {
+                expected_files.insert(self.inner.path.join(file));
+            }
+
             if let Some(file) = show.metadata.path() {
                 expected_files.insert(self.inner.path.join(file));
             }
         }
 
+        for season in server_state.seasons.values() {
+            if let Some(file) = season.thumbnail.path() {
+                expected_files.insert(self.inner.path.join(file));
+            }
+        }
+
         for video in server_state.videos.values() {
             if let Some(file) = video.thumbnail.path() {
                 expected_files.insert(self.inner.path.join(file));
             }
 
-            if let Some(file) = video.metadata.path() {
+            if let Some(file) = video.fanart.path() {
                 expected_files.insert(self.inner.path.join(file));
             }
 
-            if let Some(file) = video.download.path() {
+            if let Some(file) = video.metadata.path() {
                 expected_files.insert(self.inner.path.join(file));
             }
+
+            for subtitle in &video.subtitles {
+                if let Some(file) = subtitle.file.path() {
+                    expected_files.insert(self.inner.path.join(file));
+                }
+            }
+
+            for part in &video.parts {
+                if let Some(file) = part.download.path() {
+                    expected_files.insert(self.inner.path.join(file));
+                }
+            }
         }
 
         for playlist in self.playlists().await {
@@ -1033,9 +1615,173 @@ struct StateSync<'a> {
     seen_items: HashSet<String>,
     seen_libraries: HashSet<String>,
     transcode_profiles: HashMap<String, HashSet<String>>,
+    retention: HashMap<String, Option<u32>>,
+    resolved: HashMap<String, Vec<String>>,
+    subtitle_preferences: HashMap<String, SubtitlePreferences>,
+    audio_languages: HashMap<String, Vec<String>>,
+    audio_streams: HashMap<String, Vec<Vec<AudioStream>>>,
     allow_video_deletion: bool,
 }
 
+/// Reconciles a user's playback progress for a video with the item fetched
+/// from the server. Returns false if local changes could not be pushed.
+async fn sync_item_playback<M: MediaItem + FromMetadata>(
+    server_state: &mut ServerState,
+    user: Option<&str>,
+    item: &M,
+    plex_server: &plex_api::Server,
+) -> bool {
+    match server_state.videos.get_mut(item.rating_key()) {
+        Some(video_state) => {
+            video_state
+                .playback_mut(user)
+                .sync_playback(item, plex_server)
+                .await
+        }
+        None => true,
+    }
+}
+
+/// Fetches the items listed at a path.
+async fn fetch_items(client: &HttpClient, path: String) -> Result<Vec<Item>> {
+    Ok(fetch_page(client, path).await?.0)
+}
+
+/// Fetches every item listed at a path that Plex splits into pages.
+async fn fetch_paged_items(client: &HttpClient, path: &str, page_size: usize) -> Result<Vec<Item>> {
+    let mut items = Vec::new();
+
+    loop {
+        let (page, total_size) = fetch_page(
+            client,
+            format!(
+                "{path}?X-Plex-Container-Start={}&X-Plex-Container-Size={page_size}",
+                items.len()
+            ),
+        )
+        .await?;
+
+        let done = page.is_empty()
+            || total_size.is_none_or(|total| items.len() + page.len() >= total as usize);
+        items.extend(page);
+
+        if done {
+            return Ok(items);
+        }
+    }
+}
+
+/// Fetches the items listed at a path along with the total number of items
+/// available when the list is split into pages.
+async fn fetch_page(client: &HttpClient, path: String) -> Result<(Vec<Item>, Option<u32>)> {
+    #[derive(Deserialize)]
+    struct Wrapper {
+        #[serde(rename = "MediaContainer")]
+        media_container: MetadataMediaContainer,
+    }
+
+    let container = client.get(path).json::<Wrapper>().await?.media_container;
+    let total_size = container.media_container.total_size;
+
+    let items = container
+        .metadata
+        .into_iter()
+        .map(|metadata| {
+            let metadata = Metadata {
+                library_section_id: metadata.library_section_id.or(container.library_section_id),
+                library_section_title: metadata
+                    .library_section_title
+                    .or_else(|| container.library_section_title.clone()),
+                ..metadata
+            };
+
+            Item::from_metadata(client.clone(), metadata)
+        })
+        .collect();
+
+    Ok((items, total_size))
+}
+
+/// Disk space used against the store and server storage budgets.
+struct StorageBudget {
+    store_max: Option<u64>,
+    server_max: Option<u64>,
+    store_used: u64,
+    server_used: u64,
+}
+
+impl StorageBudget {
+    /// Reserves space for a download. Returns false, reserving nothing, if
+    /// the download would exceed either budget.
+    fn reserve(&mut self, size: u64) -> bool {
+        let exceeds = |used: u64, max: Option<u64>| max.is_some_and(|max| used + size > max);
+
+        if exceeds(self.server_used, self.server_max) || exceeds(self.store_used, self.store_max) {
+            return false;
+        }
+
+        self.server_used += size;
+        self.store_used += size;
+
+        true
+    }
+}
+
+/// Whether a video last viewed at the given time has passed its retention period.
+fn retention_expired(last_viewed_at: OffsetDateTime, retain_days: u32) -> bool {
+    OffsetDateTime::now_utc() - last_viewed_at > time::Duration::days(retain_days.into())
+}
+
+/// Combines the retention periods of two sync items that include the same
+/// video. The most generous policy wins and no period at all means the video
+/// is kept forever.
+fn merge_retention(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+    a.zip(b).map(|(a, b)| a.max(b))
+}
+
+/// What is known about an episode's place in a show and whether it has been
+/// watched.
+#[derive(Clone, Copy, Debug)]
+struct EpisodeProgress {
+    season: Option<u32>,
+    episode: Option<u32>,
+    /// Whether the episode is played locally, `None` if it isn't synced.
+    played: Option<bool>,
+    /// The number of times the server has seen the episode played.
+    view_count: u64,
+}
+
+/// Chooses the next episodes of a show to watch, returning their positions in
+/// the given list. Episodes are ordered by season and episode number, skipping
+/// specials as they don't have a meaningful place in the watch order, and the
+/// choice starts after the last played episode. Local playback includes
+/// watches not yet pushed to the server so is used for any synced episode.
+fn next_episodes(episodes: &[EpisodeProgress], count: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..episodes.len())
+        .filter(|&position| episodes[position].season != Some(0))
+        .collect();
+    order.sort_by_key(|&position| (episodes[position].season, episodes[position].episode));
+
+    let start = order
+        .iter()
+        .rposition(|&position| {
+            let episode = &episodes[position];
+            episode.played.unwrap_or(episode.view_count > 0)
+        })
+        .map_or(0, |pos| pos + 1);
+
+    order.into_iter().skip(start).take(count).collect()
+}
+
+/// The path that finds the items in a server's library matching a GUID from
+/// Plex's metadata provider.
+fn library_guid_path(guid: &Guid) -> Option<String> {
+    match guid {
+        Guid::Plex(media_type, id) => Some(format!("/library/all?guid=plex://{media_type}/{id}")),
+        _ => None,
+    }
+}
+
 macro_rules! return_if_seen {
     ($self:expr, $typ:expr) => {
         if $self.seen_items.contains($typ.rating_key()) {
@@ -1053,8 +1799,18 @@ impl StateSync<'_> {
     }
 
     async fn add_video<T: MediaItem + FromMetadata>(&mut self, sync: &SyncItem, video: &T) {
-        if sync.only_unplayed && video.metadata().view_count.unwrap_or_default() > 0 {
-            return;
+        let metadata = video.metadata();
+        let retain_days = sync.retain_days.or(self.server_config.retain_days);
+
+        if sync.only_unplayed && metadata.view_count.unwrap_or_default() > 0 {
+            // Recently played videos are kept until their retention period passes.
+            let recently_played = retain_days
+                .zip(metadata.last_viewed_at)
+                .is_some_and(|(days, viewed)| !retention_expired(viewed, days));
+
+            if !recently_played {
+                return;
+            }
         }
 
         let key = video.rating_key();
@@ -1074,6 +1830,32 @@ impl StateSync<'_> {
             let profiles = self.transcode_profiles.entry(key.to_owned()).or_default();
             profiles.insert(profile.clone());
         }
+
+        let retention = self.retention.entry(key.to_owned()).or_insert(retain_days);
+        *retention = merge_retention(*retention, retain_days);
+
+        if let Some(ref subtitles) = sync.subtitles {
+            self.subtitle_preferences
+                .entry(key.to_owned())
+                .and_modify(|prefs| prefs.merge(subtitles))
+                .or_insert_with(|| subtitles.clone());
+        }
+
+        if let Some(ref languages) = sync.audio_languages {
+            let preferred = self.audio_languages.entry(key.to_owned()).or_default();
+            for language in languages {
+                if !preferred.contains(language) {
+                    preferred.push(language.clone());
+                }
+            }
+        }
+
+        if sync.is_dynamic() {
+            self.resolved
+                .entry(sync.id.clone())
+                .or_default()
+                .push(key.to_owned());
+        }
     }
 
     async fn add_movie(&mut self, sync: &SyncItem, movie: &Movie) -> Result {
@@ -1215,12 +1997,11 @@ impl StateSync<'_> {
                 let mut server_state = self.server_state().await;
                 let video_state = server_state.videos.get_mut(key).unwrap();
                 if video_state.transcode_profile.as_ref() != Some(&selected_profile) {
-                    if video_state.download != DownloadState::None {
+                    if video_state.has_download() {
                         info!(item=key, old=?video_state.transcode_profile, new=?selected_profile, "Transcode profile changed, deleting existing download.");
 
                         video_state
-                            .download
-                            .delete(&guard, &self.plex_server, self.root)
+                            .delete_downloads(&guard, &self.plex_server, self.root)
                             .await;
                     }
 
@@ -1232,6 +2013,56 @@ impl StateSync<'_> {
         Ok(())
     }
 
+    /// Deletes downloads whose audio stream no longer matches the preferred
+    /// audio languages.
+    async fn update_audio_streams(&mut self) {
+        for (key, streams) in self.audio_streams.iter() {
+            let Ok(guard) = self.server.try_lock_write_key(key).await else {
+                continue;
+            };
+
+            let mut server_state = self.server_state().await;
+            let Some(video_state) = server_state.videos.get_mut(key) else {
+                continue;
+            };
+
+            if !video_state.has_transcode() {
+                continue;
+            }
+
+            let languages = video_state.audio_languages.clone().or_else(|| {
+                let profile = video_state.transcode_profile.as_deref()?;
+                self.config
+                    .profiles
+                    .get(profile)
+                    .or_else(|| DEFAULT_PROFILES.get(profile))?
+                    .audio_languages
+                    .clone()
+            });
+
+            for (index, part) in video_state.parts.iter_mut().enumerate() {
+                if !part.has_transcode() {
+                    continue;
+                }
+
+                let preferred = languages
+                    .as_ref()
+                    .zip(streams.get(index))
+                    .and_then(|(languages, streams)| preferred_audio_stream(languages, streams))
+                    .map(|stream| stream.id.clone());
+
+                if preferred != part.audio_stream {
+                    info!(item=key, part=index, old=?part.audio_stream, new=?preferred, "Preferred audio stream changed, deleting existing download.");
+
+                    part.download
+                        .delete(&guard, &self.plex_server, self.root)
+                        .await;
+                    part.audio_stream = None;
+                }
+            }
+        }
+    }
+
     async fn fetch_collections(&mut self) -> Result {
         for library in self.plex_server.libraries() {
             match library {
@@ -1336,9 +2167,60 @@ impl StateSync<'_> {
         map.retain(|k, _v| !items_to_delete.contains(k))
     }
 
+    /// Deletes the downloads of played videos that have passed their retention
+    /// period. The videos remain in the state so they are not downloaded again.
+    async fn expire_played(&mut self) {
+        // Downloads are shared so they only expire once every user played them.
+        let users: Vec<Option<&str>> = iter::once(None)
+            .chain(
+                self.server_config
+                    .users
+                    .keys()
+                    .map(|user| Some(user.as_str())),
+            )
+            .collect();
+
+        for (key, retain_days) in self.retention.iter() {
+            let Ok(guard) = self.server.try_lock_write_key(key).await else {
+                continue;
+            };
+
+            let mut server_state = self.server_state().await;
+            let Some(video_state) = server_state.videos.get_mut(key) else {
+                continue;
+            };
+
+            let expired = match (retain_days, video_state.last_played_by_all(&users)) {
+                (Some(days), Some(viewed)) => retention_expired(viewed, *days),
+                _ => false,
+            };
+
+            if expired {
+                if !video_state.is_expired() {
+                    info!(
+                        item = key,
+                        "Retention period has passed, deleting download."
+                    );
+
+                    video_state
+                        .expire_downloads(&guard, &self.plex_server, self.root)
+                        .await;
+                }
+            } else {
+                for part in video_state.parts.iter_mut() {
+                    if part.download == DownloadState::Expired {
+                        part.download = DownloadState::None;
+                    }
+                }
+            }
+        }
+    }
+
     async fn prune_unseen(&mut self) -> Result {
         info!("Pruning old items");
 
+        self.expire_played().await;
+
         let plex_server = self.plex_server.clone();
         self.prune_map(
             |ss| &mut ss.videos,
@@ -1363,10 +2245,11 @@ impl StateSync<'_> {
             .playlists
             .retain(|k, _v| self.seen_items.contains(k));
 
-        self.server_state()
-            .await
-            .seasons
-            .retain(|k, _v| self.seen_items.contains(k));
+        self.prune_map(
+            |ss| &mut ss.seasons,
+            |season, guard| season.delete(guard, self.root).scope_boxed(),
+        )
+        .await;
 
         self.server_state()
             .await
@@ -1385,7 +2268,7 @@ impl StateSync<'_> {
                 continue;
             };
 
-            match self.plex_server.item_by_id(video.id()).await {
+            let streams = match self.plex_server.item_by_id(video.id()).await {
                 Ok(Item::Movie(movie)) => {
                     video_state
                         .update(
@@ -1396,6 +2279,8 @@ impl StateSync<'_> {
                             self.allow_video_deletion,
                         )
                         .await;
+
+                    Some(wrappers::parts_audio_streams(&movie))
                 }
                 Ok(Item::Episode(episode)) => {
                     video_state
@@ -1407,16 +2292,53 @@ impl StateSync<'_> {
                             self.allow_video_deletion,
                         )
                         .await;
+
+                    Some(wrappers::parts_audio_streams(&episode))
                 }
                 Ok(_) => {
                     warn!(item = video.id(), "Unexpected remote item type for video");
+                    None
                 }
                 Err(plex_api::Error::ItemNotFound) => {
                     warn!(item = video.id(), "Sync item no longer appears to exist");
+                    None
                 }
                 Err(e) => {
                     error!(error = %e, item = video.id());
+                    None
                 }
+            };
+
+            drop(server_state);
+            if let Some(streams) = streams {
+                self.audio_streams.insert(video.id().to_owned(), streams);
+            }
+        }
+    }
+
+    /// Updates the playback progress of the additional users.
+    async fn update_user_playback(&mut self) {
+        let users = self.server.users().await;
+        if users.is_empty() {
+            return;
+        }
+
+        info!("Updating user playback");
+        let videos: Vec<String> = self
+            .server
+            .videos()
+            .await
+            .iter()
+            .map(|video| video.id().to_owned())
+            .collect();
+
+        for user in users {
+            if let Err(e) = self
+                .server
+                .sync_user_playback(Some(&user), videos.clone())
+                .await
+            {
+                warn!(user = user.as_str(), error=?e, "Failed to update user playback");
             }
         }
     }
@@ -1432,9 +2354,161 @@ impl StateSync<'_> {
         }
     }
 
+    /// Adds a list of items resolved for a filter or source sync.
+    async fn add_resolved(&mut self, sync: &SyncItem, items: Vec<Item>) {
+        // Make sure the sync is recorded even if nothing matches.
+        self.resolved.entry(sync.id.clone()).or_default();
+
+        for item in items {
+            let key = item.rating_key().to_owned();
+            if let Err(e) = self.add_item(sync, item).await {
+                warn!(item = key, error=?e, "Failed to add resolved item");
+            }
+        }
+    }
+
+    /// Adds everything in a library section that matches a filter.
+    async fn add_filter(&mut self, sync: &SyncItem, filter: &LibraryFilter) -> Result {
+        let path = format!(
+            "/library/sections/{}/all?{}",
+            filter.library,
+            encode_query(&filter.query)
+        );
+        let items = fetch_items(self.plex_server.client(), path).await?;
+
+        self.add_resolved(sync, items).await;
+
+        Ok(())
+    }
+
+    /// Adds the current contents of a list maintained by Plex.
+    async fn add_source(&mut self, sync: &SyncItem, source: SyncSource) -> Result {
+        let client = self.plex_server.client();
+
+        let items = match source {
+            SyncSource::OnDeck => fetch_items(client, "/library/onDeck".to_owned()).await?,
+            SyncSource::Watchlist => {
+                // The watchlist belongs to the Plex account so needs the
+                // account's token rather than the server's access token.
+                let token = self.server_state().await.token.clone();
+                let discover = HttpClientBuilder::from(client.clone())
+                    .set_api_url(WATCHLIST_API_URL)
+                    .set_x_plex_token(token)
+                    .build()?;
+                let watchlist = fetch_paged_items(
+                    &discover,
+                    "/library/sections/watchlist/all",
+                    WATCHLIST_PAGE_SIZE,
+                )
+                .await?;
+
+                // Watchlist entries come from Plex's metadata provider so
+                // must be matched against the server's library.
+                let mut items = Vec::new();
+                for entry in watchlist {
+                    let Some(path) = entry.metadata().guid.as_ref().and_then(library_guid_path)
+                    else {
+                        continue;
+                    };
+
+                    match fetch_items(client, path).await {
+                        Ok(found) => {
+                            if found.is_empty() {
+                                debug!(title = entry.title(), "Watchlist item not in library");
+                            }
+                            items.extend(found);
+                        }
+                        Err(e) => {
+                            warn!(title = entry.title(), error=?e, "Failed to find watchlist item")
+                        }
+                    }
+                }
+
+                items
+            }
+        };
+
+        self.add_resolved(sync, items).await;
+
+        Ok(())
+    }
+
+    /// Records the videos resolved for each filter or source sync, dropping
+    /// those that are no longer synced.
+    async fn update_resolved(&mut self) {
+        let resolved = std::mem::take(&mut self.resolved);
+        let mut server_state = self.server_state().await;
+
+        server_state
+            .resolved
+            .retain(|id, _| self.server_config.syncs.contains_key(id));
+
+        server_state.resolved.extend(resolved);
+    }
+
+    /// Records the subtitle and audio preferences of the sync items that
+    /// include each video.
+    async fn update_video_preferences(&mut self) {
+        let mut subtitle_preferences = std::mem::take(&mut self.subtitle_preferences);
+        let mut audio_languages = std::mem::take(&mut self.audio_languages);
+        let mut server_state = self.server_state().await;
+
+        for (key, video_state) in server_state.videos.iter_mut() {
+            video_state.subtitle_preferences = subtitle_preferences.remove(key);
+            video_state.audio_languages = audio_languages.remove(key);
+        }
+    }
+
+    /// Adds the next `count` episodes following the last played episode of a
+    /// show.
+    async fn add_next_episodes(&mut self, sync_item: &SyncItem, show: &Show, count: u32) -> Result {
+        let seasons = show.seasons().await?;
+
+        let mut episodes = Vec::new();
+        for (index, season) in seasons.iter().enumerate() {
+            for episode in season.episodes().await? {
+                episodes.push((index, episode));
+            }
+        }
+
+        let progress: Vec<EpisodeProgress> = {
+            let server_state = self.server_state().await;
+            episodes
+                .iter()
+                .map(|(index, episode)| EpisodeProgress {
+                    season: seasons[*index].season_number(),
+                    episode: episode.episode_number(),
+                    played: server_state
+                        .videos
+                        .get(episode.rating_key())
+                        .map(|video_state| {
+                            video_state.playback.playback_state == PlaybackState::Played
+                        }),
+                    view_count: episode.metadata().view_count.unwrap_or_default(),
+                })
+                .collect()
+        };
+
+        let mut added_seasons = HashSet::new();
+        for position in next_episodes(&progress, count as usize) {
+            let (index, episode) = &episodes[position];
+            if added_seasons.insert(*index) {
+                self.add_season(&seasons[*index]).await?;
+            }
+
+            self.add_episode(sync_item, episode).await?;
+        }
+
+        Ok(())
+    }
+
     async fn add_show_contents(&mut self, sync_item: &SyncItem, show: &Show) -> Result {
         self.add_show(show).await?;
 
+        if let Some(count) = sync_item.next_episodes {
+            return self.add_next_episodes(sync_item, show, count).await;
+        }
+
         for season in show.seasons().await? {
             self.add_season(&season).await?;
 
@@ -1538,3 +2612,164 @@ impl StateSync<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use plex_api::media_container::server::library::Guid;
+    use serde_json::json;
+    use time::{Duration, OffsetDateTime};
+
+    use crate::server::{
+        EpisodeProgress, StorageBudget, library_guid_path, merge_retention, next_episodes,
+        retention_expired,
+    };
+
+    #[test]
+    fn storage_budget() {
+        let mut budget = StorageBudget {
+            store_max: None,
+            server_max: None,
+            store_used: 0,
+            server_used: 0,
+        };
+        assert!(budget.reserve(u32::MAX.into()));
+
+        let mut budget = StorageBudget {
+            store_max: Some(1000),
+            server_max: Some(600),
+            store_used: 300,
+            server_used: 100,
+        };
+        assert!(budget.reserve(400));
+        assert_eq!(budget.server_used, 500);
+        assert_eq!(budget.store_used, 700);
+
+        // Exceeds the server budget.
+        assert!(!budget.reserve(200));
+        assert_eq!(budget.server_used, 500);
+
+        // Smaller downloads after a skipped one still fit.
+        assert!(budget.reserve(100));
+        assert_eq!(budget.server_used, 600);
+        assert_eq!(budget.store_used, 800);
+        assert!(!budget.reserve(1));
+
+        let mut budget = StorageBudget {
+            store_max: Some(1000),
+            server_max: None,
+            store_used: 900,
+            server_used: 0,
+        };
+        // Exceeds the store budget shared with other servers.
+        assert!(!budget.reserve(200));
+        assert!(budget.reserve(100));
+    }
+
+    #[test]
+    fn retention() {
+        let now = OffsetDateTime::now_utc();
+
+        assert!(retention_expired(now - Duration::days(8), 7));
+        assert!(!retention_expired(now - Duration::days(6), 7));
+        assert!(retention_expired(now - Duration::hours(1), 0));
+        // Played in the future according to a skewed server clock.
+        assert!(!retention_expired(now + Duration::days(1), 7));
+
+        assert_eq!(merge_retention(Some(7), Some(30)), Some(30));
+        assert_eq!(merge_retention(Some(30), Some(7)), Some(30));
+        assert_eq!(merge_retention(Some(7), None), None);
+        assert_eq!(merge_retention(None, Some(7)), None);
+        assert_eq!(merge_retention(None, None), None);
+    }
+
+    #[test]
+    fn watchlist_guids() {
+        let guid = |value: &str| serde_json::from_value::<Guid>(json!(value)).unwrap();
+
+        assert_eq!(
+            library_guid_path(&guid("plex://movie/5d7768286f4521001ea9c6a4")).as_deref(),
+            Some("/library/all?guid=plex://movie/5d7768286f4521001ea9c6a4")
+        );
+        assert_eq!(
+            library_guid_path(&guid("plex://show/5d9c086c46115600200aa2fe")).as_deref(),
+            Some("/library/all?guid=plex://show/5d9c086c46115600200aa2fe")
+        );
+        // Items from legacy agents can't be matched against the watchlist.
+        assert_eq!(library_guid_path(&guid("imdb://tt0111161")), None);
+    }
+
+    fn episode(
+        season: u32,
+        episode: u32,
+        played: Option<bool>,
+        view_count: u64,
+    ) -> EpisodeProgress {
+        EpisodeProgress {
+            season: Some(season),
+            episode: Some(episode),
+            played,
+            view_count,
+        }
+    }
+
+    #[test]
+    fn next_episodes_order() {
+        // Listed out of order, with specials.
+        let episodes = [
+            episode(2, 1, None, 0),
+            episode(1, 2, Some(true), 0),
+            episode(0, 1, None, 0),
+            episode(1, 3, None, 0),
+            episode(1, 1, Some(true), 0),
+            episode(2, 2, None, 0),
+        ];
+
+        // Starts after the last played episode and continues into the next
+        // season, skipping specials.
+        assert_eq!(next_episodes(&episodes, 3), vec![3, 0, 5]);
+        assert_eq!(next_episodes(&episodes, 1), vec![3]);
+        assert_eq!(next_episodes(&episodes, 10), vec![3, 0, 5]);
+
+        // A played special doesn't move the start.
+        let mut specials = episodes;
+        specials[2].played = Some(true);
+        assert_eq!(next_episodes(&specials, 2), vec![3, 0]);
+
+        // Nothing played starts from the beginning.
+        let unplayed = [episode(1, 2, None, 0), episode(1, 1, Some(false), 0)];
+        assert_eq!(next_episodes(&unplayed, 1), vec![1]);
+
+        // Nothing left once the last episode is played.
+        let finished = [episode(1, 1, None, 0), episode(1, 2, Some(true), 0)];
+        assert!(next_episodes(&finished, 2).is_empty());
+    }
+
+    #[test]
+    fn next_episodes_played_state() {
+        // Episodes that aren't synced fall back to the server's view count.
+        let episodes = [
+            episode(1, 1, None, 1),
+            episode(1, 2, None, 2),
+            episode(1, 3, None, 0),
+            episode(1, 4, None, 0),
+        ];
+        assert_eq!(next_episodes(&episodes, 1), vec![2]);
+
+        // Local playback wins over the server for synced episodes, in both
+        // directions.
+        let episodes = [
+            episode(1, 1, None, 1),
+            episode(1, 2, Some(false), 1),
+            episode(1, 3, Some(true), 0),
+            episode(1, 4, None, 0),
+        ];
+        assert_eq!(next_episodes(&episodes, 1), vec![3]);
+
+        let episodes = [
+            episode(1, 1, None, 1),
+            episode(1, 2, Some(false), 1),
+            episode(1, 3, None, 0),
+        ];
+        assert_eq!(next_episodes(&episodes, 2), vec![1, 2]);
+    }
+}
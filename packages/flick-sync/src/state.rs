@@ -1,8 +1,10 @@
 use std::{
+    borrow::Cow,
     cmp,
     collections::HashMap,
     fmt,
     io::ErrorKind,
+    mem,
     path::{Path, PathBuf},
     str::FromStr,
 };
@@ -13,7 +15,7 @@ use mime::Mime;
 use plex_api::{
     Server as PlexServer,
     library::{Collection, FromMetadata, MediaItem, MetadataItem, Part, Playlist, Season, Show},
-    media_container::server::library::{Metadata, MetadataType},
+    media_container::server::library::{Guid, Metadata, MetadataType},
     transcode::QueueItemStatus,
 };
 use serde::{Deserialize, Serialize};
@@ -25,12 +27,15 @@ use typeshare::typeshare;
 use uuid::Uuid;
 
 use crate::{
-    FileType, LockedFile, Result, Server, Video,
+    LockedFile, Result, Server, VideoPart,
+    config::{Config, SubtitlePreferences},
     schema::{JsonObject, JsonUtils, MigratableStore, SchemaVersion},
     sync::{OpReadGuard, OpWriteGuard},
+    transcode::{MediaTags, retag},
+    util::modified_time,
 };
 
-const SCHEMA_VERSION: u64 = 5;
+const SCHEMA_VERSION: u64 = 7;
 
 async fn remove_file(path: &Path) {
     if let Err(e) = fs::remove_file(path).await
@@ -259,6 +264,15 @@ pub(crate) struct SeasonState {
     pub(crate) show: String,
     pub(crate) index: usize,
     pub(crate) title: String,
+    #[serde(default = "unix_epoch", with = "time::serde::timestamp")]
+    #[typeshare(serialized_as = "number")]
+    pub(crate) last_updated: OffsetDateTime,
+    #[serde(default, skip_serializing_if = "RelatedFileState::is_none")]
+    pub(crate) thumbnail: RelatedFileState,
+}
+
+fn unix_epoch() -> OffsetDateTime {
+    OffsetDateTime::UNIX_EPOCH
 }
 
 impl SeasonState {
@@ -270,6 +284,8 @@ impl SeasonState {
             show: metadata.parent.parent_rating_key.clone().unwrap(),
             index: metadata.index.unwrap() as usize,
             title: season.title().to_owned(),
+            last_updated: metadata.updated_at.unwrap_or(OffsetDateTime::UNIX_EPOCH),
+            thumbnail: Default::default(),
         }
     }
 
@@ -279,6 +295,114 @@ impl SeasonState {
         self.index = metadata.index.unwrap() as usize;
         self.show = metadata.parent.parent_rating_key.clone().unwrap();
         self.title = season.title().to_owned();
+
+        if let Some(updated) = metadata.updated_at {
+            self.last_updated = updated;
+        }
+    }
+
+    pub(crate) async fn delete(&mut self, guard: &OpWriteGuard, root: &Path) {
+        self.thumbnail.delete(guard, root).await;
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[typeshare]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CastMember {
+    pub(crate) name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) role: Option<String>,
+}
+
+/// Descriptive metadata from Plex, stored so that metadata files can be
+/// rebuilt without access to the server.
+#[derive(Deserialize, Serialize, Default, Clone, Debug, PartialEq)]
+#[typeshare]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ItemDetails {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) plot: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tagline: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) studio: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) content_rating: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) genres: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) cast: Vec<CastMember>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) directors: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) writers: Vec<String>,
+    /// IDs from external databases keyed by provider, e.g. `imdb`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) unique_ids: HashMap<String, String>,
+}
+
+impl ItemDetails {
+    pub(crate) fn from(metadata: &Metadata) -> Self {
+        let mut unique_ids = HashMap::new();
+        for guid in &metadata.guids {
+            let (provider, id) = match guid {
+                Guid::Imdb(id) => ("imdb", id),
+                Guid::Tmdb(id) => ("tmdb", id),
+                Guid::Tvdb(id) => ("tvdb", id),
+                _ => continue,
+            };
+
+            unique_ids.insert(provider.to_owned(), id.clone());
+        }
+
+        Self {
+            plot: metadata.summary.clone().filter(|s| !s.is_empty()),
+            tagline: metadata.tagline.clone().filter(|s| !s.is_empty()),
+            studio: metadata.studio.clone(),
+            content_rating: metadata.content_rating.clone(),
+            genres: metadata.genres.iter().map(|t| t.tag.clone()).collect(),
+            cast: metadata
+                .roles
+                .iter()
+                .map(|r| CastMember {
+                    name: r.tag.clone(),
+                    role: r.role.clone(),
+                })
+                .collect(),
+            directors: metadata.directors.iter().map(|t| t.tag.clone()).collect(),
+            writers: metadata.writers.iter().map(|t| t.tag.clone()).collect(),
+            unique_ids,
+        }
+    }
+
+    /// Plex omits some details from listings so keep anything that was
+    /// previously seen.
+    pub(crate) fn update(&mut self, metadata: &Metadata) {
+        let mut details = Self::from(metadata);
+
+        if details.genres.is_empty() {
+            details.genres = mem::take(&mut self.genres);
+        }
+        if details.cast.is_empty() {
+            details.cast = mem::take(&mut self.cast);
+        }
+        if details.directors.is_empty() {
+            details.directors = mem::take(&mut self.directors);
+        }
+        if details.writers.is_empty() {
+            details.writers = mem::take(&mut self.writers);
+        }
+        if details.unique_ids.is_empty() {
+            details.unique_ids = mem::take(&mut self.unique_ids);
+        }
+
+        details.plot = details.plot.or(self.plot.take());
+        details.tagline = details.tagline.or(self.tagline.take());
+        details.studio = details.studio.or(self.studio.take());
+        details.content_rating = details.content_rating.or(self.content_rating.take());
+
+        *self = details;
     }
 }
 
@@ -295,7 +419,14 @@ pub(crate) struct ShowState {
     pub(crate) last_updated: OffsetDateTime,
     pub(crate) thumbnail: RelatedFileState,
     #[serde(default, skip_serializing_if = "RelatedFileState::is_none")]
+    pub(crate) fanart: RelatedFileState,
+    #[serde(default, skip_serializing_if = "RelatedFileState::is_none")]
     pub(crate) metadata: RelatedFileState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[typeshare(serialized_as = "Option<string>")]
+    pub(crate) air_date: Option<Date>,
+    #[serde(default)]
+    pub(crate) details: ItemDetails,
 }
 
 impl ShowState {
@@ -312,7 +443,10 @@ impl ShowState {
             year,
             last_updated: metadata.updated_at.unwrap(),
             thumbnail: Default::default(),
+            fanart: Default::default(),
             metadata: Default::default(),
+            air_date: metadata.originally_available_at,
+            details: ItemDetails::from(metadata),
         }
     }
 
@@ -321,6 +455,8 @@ impl ShowState {
 
         self.year = metadata.year.unwrap();
         self.title = show.title().to_owned();
+        self.air_date = metadata.originally_available_at;
+        self.details.update(metadata);
 
         if let Some(updated) = show.metadata().updated_at {
             self.last_updated = updated;
@@ -329,6 +465,7 @@ impl ShowState {
 
     pub(crate) async fn delete(&mut self, guard: &OpWriteGuard, root: &Path) {
         self.thumbnail.delete(guard, root).await;
+        self.fanart.delete(guard, root).await;
         self.metadata.delete(guard, root).await;
     }
 }
@@ -382,19 +519,33 @@ pub(crate) enum DownloadState {
     #[default]
     None,
     #[serde(rename_all = "camelCase")]
-    Downloading { queue_id: u32, path: PathBuf },
+    Downloading {
+        queue_id: u32,
+        path: PathBuf,
+        /// The length of the content being downloaded, if Plex reported it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_length: Option<u64>,
+        /// A fingerprint of the start of the content being downloaded, used to
+        /// check that a partial download can be resumed.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fingerprint: Option<String>,
+    },
     #[serde(rename_all = "camelCase")]
     Transcoding { queue_id: u32 },
     #[serde(rename_all = "camelCase")]
     Downloaded { path: PathBuf },
     #[serde(rename_all = "camelCase")]
     Transcoded { path: PathBuf },
+    /// The download was deleted by a retention policy and should not be
+    /// downloaded again.
+    Expired,
 }
 
 impl DownloadState {
     pub(crate) fn path(&self) -> Option<PathBuf> {
         match self {
             Self::None => None,
+            Self::Expired => None,
             Self::Transcoding { .. } => None,
             Self::Downloading { path, .. } => Some(path.clone()),
             Self::Downloaded { path } => Some(path.clone()),
@@ -415,41 +566,21 @@ impl DownloadState {
         )
     }
 
-    #[instrument(level = "trace", skip(root, guard))]
+    #[instrument(level = "trace", skip(root, guard, tags))]
     pub(crate) async fn strip_metadata(
         &self,
         #[expect(unused)] guard: &OpWriteGuard,
-        #[expect(unused)] root: &Path,
+        root: &Path,
+        ffmpeg: &Path,
+        tags: &MediaTags,
     ) -> Result {
-        // let source_file = match self {
-        //     Self::Downloaded { path } => root.join(path),
-        //     Self::Transcoded { path } => root.join(path),
-        //     _ => return Ok(()),
-        // };
-
-        // let temp_file = NamedTempFile::new()?;
-
-        // let result = Command::new("ffmpeg")
-        //     .arg("-y")
-        //     .arg("-loglevel")
-        //     .arg("warning")
-        //     .arg("-i")
-        //     .arg(&source_file)
-        //     .arg("-map_metadata")
-        //     .arg("-1")
-        //     .arg("-c")
-        //     .arg("copy")
-        //     .arg("-map")
-        //     .arg("0")
-        //     .arg(temp_file.path())
-        //     .output()
-        //     .await?;
-
-        // if result.status.success() {
-        //     fs::copy(temp_file.path(), &source_file).await?;
-        // }
+        let source_file = match self {
+            Self::Downloaded { path } => root.join(path),
+            Self::Transcoded { path } => root.join(path),
+            _ => return Ok(()),
+        };
 
-        Ok(())
+        retag(ffmpeg, &source_file, tags).await
     }
 
     async fn verify_queue_status(&mut self, plex_server: &PlexServer, queue_id: u32) {
@@ -491,17 +622,17 @@ impl DownloadState {
         }
     }
 
-    #[instrument(level = "trace", skip(self, root, guard, plex_server, video), fields(video = video.id()))]
+    #[instrument(level = "trace", skip(self, root, guard, plex_server, part), fields(video = part.id(), part = part.index()))]
     pub(crate) async fn verify(
         &mut self,
         #[expect(unused)] guard: &OpWriteGuard,
         plex_server: &PlexServer,
-        video: &Video,
+        part: &VideoPart,
         root: &Path,
         allow_delete: bool,
     ) {
         match self.clone() {
-            DownloadState::None => return,
+            DownloadState::None | DownloadState::Expired => return,
             DownloadState::Downloading { queue_id, .. } => {
                 self.verify_queue_status(plex_server, queue_id).await;
                 return;
@@ -524,7 +655,7 @@ impl DownloadState {
             .and_then(|os| os.to_str())
             .unwrap()
             .to_owned();
-        let expected_path = video.file_path(FileType::Video, &extension).await.unwrap();
+        let expected_path = part.file_path(&extension).await.unwrap();
 
         match fs::metadata(&file).await {
             Ok(stats) => {
@@ -598,8 +729,8 @@ impl DownloadState {
         root: &Path,
     ) {
         let (queue_id, path) = match self {
-            DownloadState::None => return,
-            DownloadState::Downloading { queue_id, path } => (Some(queue_id), Some(path)),
+            DownloadState::None | DownloadState::Expired => return,
+            DownloadState::Downloading { queue_id, path, .. } => (Some(queue_id), Some(path)),
             DownloadState::Transcoding { queue_id } => (Some(queue_id), None),
             DownloadState::Downloaded { path } => (None, Some(path)),
             DownloadState::Transcoded { path } => (None, Some(path)),
@@ -633,6 +764,17 @@ impl DownloadState {
 
         *self = DownloadState::None;
     }
+
+    /// Deletes any local download, marking it so it will not be downloaded again.
+    pub(crate) async fn expire(
+        &mut self,
+        guard: &OpWriteGuard,
+        plex_server: &PlexServer,
+        root: &Path,
+    ) {
+        self.delete(guard, plex_server, root).await;
+        *self = DownloadState::Expired;
+    }
 }
 
 impl fmt::Debug for DownloadState {
@@ -643,6 +785,7 @@ impl fmt::Debug for DownloadState {
             Self::Transcoding { queue_id, .. } => write!(f, "Transcoding({queue_id})"),
             Self::Downloaded { .. } => write!(f, "Downloaded"),
             Self::Transcoded { .. } => write!(f, "Transcoded"),
+            Self::Expired => write!(f, "Expired"),
         }
     }
 }
@@ -657,6 +800,26 @@ pub(crate) struct VideoPartState {
     pub(crate) size: u64,
     #[typeshare(serialized_as = "number")]
     pub(crate) duration: u64,
+    pub(crate) download: DownloadState,
+    /// The SHA-256 checksum of the downloaded file, if recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) checksum: Option<String>,
+    /// The audio stream selected for the current download.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) audio_stream: Option<String>,
+}
+
+impl VideoPartState {
+    /// Whether this part has been, or is being, transcoded. Original downloads
+    /// include every audio stream so don't depend on the preferred stream.
+    pub(crate) fn has_transcode(&self) -> bool {
+        matches!(
+            self.download,
+            DownloadState::Transcoding { .. }
+                | DownloadState::Downloading { .. }
+                | DownloadState::Transcoded { .. }
+        )
+    }
 }
 
 impl<M> From<&Part<'_, M>> for VideoPartState
@@ -670,6 +833,9 @@ where
             key: metadata.key.clone().unwrap(),
             size: metadata.size.unwrap(),
             duration: metadata.duration.unwrap(),
+            download: DownloadState::None,
+            checksum: None,
+            audio_stream: None,
         }
     }
 }
@@ -698,14 +864,304 @@ pub(crate) enum VideoDetail {
     Episode(EpisodeDetail),
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
 #[serde(tag = "state", rename_all = "lowercase")]
 pub enum PlaybackState {
+    #[default]
     Unplayed,
-    InProgress { position: u64 },
+    InProgress {
+        position: u64,
+    },
     Played,
 }
 
+impl PlaybackState {
+    /// How far through the video this state is, for comparing progress.
+    fn progress(&self) -> u64 {
+        match self {
+            Self::Unplayed => 0,
+            Self::InProgress { position } => *position,
+            Self::Played => u64::MAX,
+        }
+    }
+}
+
+/// The most playback events kept for each video.
+const PLAYBACK_HISTORY_LENGTH: usize = 20;
+
+/// A change to a video's playback state.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[typeshare]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PlaybackEvent {
+    #[serde(with = "time::serde::timestamp")]
+    #[typeshare(serialized_as = "number")]
+    pub(crate) at: OffsetDateTime,
+    pub(crate) state: PlaybackState,
+    /// Whether the server has seen this state, either because it came from the
+    /// server or because it was sent there.
+    #[serde(default)]
+    pub(crate) synced: bool,
+}
+
+/// A user's playback progress through a video.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[typeshare]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PlaybackProgress {
+    pub(crate) playback_state: PlaybackState,
+    #[serde(default, with = "time::serde::timestamp::option")]
+    #[typeshare(serialized_as = "Option<number>")]
+    pub(crate) last_viewed_at: Option<OffsetDateTime>,
+    /// Recent changes to the playback state, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) playback_history: Vec<PlaybackEvent>,
+}
+
+impl From<&Metadata> for PlaybackProgress {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            playback_state: playback_state_from_metadata(metadata),
+            last_viewed_at: metadata.last_viewed_at,
+            playback_history: Vec::new(),
+        }
+    }
+}
+
+impl PlaybackProgress {
+    /// Records a local change to the playback state. Changes older than the
+    /// latest known change are kept in the history but do not alter the
+    /// current state.
+    pub(crate) fn record_playback(&mut self, state: PlaybackState, at: OffsetDateTime) {
+        if self
+            .playback_history
+            .last()
+            .is_some_and(|event| event.at > at)
+        {
+            self.insert_playback_event(PlaybackEvent {
+                at,
+                state,
+                synced: false,
+            });
+            return;
+        }
+
+        self.playback_state = state.clone();
+
+        // Collapse successive position updates that the server has not seen.
+        if let Some(last) = self.playback_history.last_mut()
+            && !last.synced
+            && matches!(last.state, PlaybackState::InProgress { .. })
+            && matches!(state, PlaybackState::InProgress { .. })
+        {
+            last.at = at;
+            last.state = state;
+            return;
+        }
+
+        self.insert_playback_event(PlaybackEvent {
+            at,
+            state,
+            synced: false,
+        });
+    }
+
+    /// When the playback state last changed, if known.
+    pub(crate) fn last_change(&self) -> Option<OffsetDateTime> {
+        self.playback_history
+            .last()
+            .map(|event| event.at)
+            .max(self.last_viewed_at)
+    }
+
+    fn to_update(&self) -> PlaybackUpdate {
+        PlaybackUpdate {
+            state: self.playback_state.clone(),
+            at: self.last_change(),
+        }
+    }
+
+    /// Adds an event to the history, keeping it in time order.
+    fn insert_playback_event(&mut self, event: PlaybackEvent) {
+        let index = self
+            .playback_history
+            .partition_point(|existing| existing.at <= event.at);
+        self.playback_history.insert(index, event);
+        self.trim_playback_history();
+    }
+
+    fn trim_playback_history(&mut self) {
+        if self.playback_history.len() > PLAYBACK_HISTORY_LENGTH {
+            let excess = self.playback_history.len() - PLAYBACK_HISTORY_LENGTH;
+            self.playback_history.drain(..excess);
+        }
+    }
+
+    fn mark_playback_synced(&mut self) {
+        for event in self.playback_history.iter_mut() {
+            event.synced = true;
+        }
+    }
+
+    /// Decides whether unsynced local playback changes win over a change made
+    /// on the server. If both sides moved forward from the last synced state
+    /// the furthest position wins, otherwise the most recent change wins.
+    fn local_playback_wins(
+        &self,
+        server_state: &PlaybackState,
+        server_viewed: Option<OffsetDateTime>,
+    ) -> bool {
+        let Some(local) = self.playback_history.last().filter(|event| !event.synced) else {
+            return false;
+        };
+
+        let baseline = self
+            .playback_history
+            .iter()
+            .rev()
+            .find(|event| event.synced)
+            .map(|event| event.state.progress())
+            .unwrap_or_default();
+
+        let local_progress = local.state.progress();
+        let server_progress = server_state.progress();
+
+        if local_progress > baseline && server_progress > baseline {
+            local_progress >= server_progress
+        } else {
+            server_viewed.is_none_or(|viewed| local.at > viewed)
+        }
+    }
+
+    /// Whether there are local playback changes that the server has not seen.
+    pub(crate) fn has_pending_playback(&self) -> bool {
+        self.playback_history
+            .last()
+            .is_some_and(|event| !event.synced)
+    }
+
+    /// Reconciles the local playback state with the server's, pushing local
+    /// changes to the server if they win. Returns false if that failed.
+    pub(crate) async fn sync_playback<M: MediaItem + FromMetadata>(
+        &mut self,
+        item: &M,
+        plex_server: &PlexServer,
+    ) -> bool {
+        let metadata = item.metadata();
+        let server_state = playback_state_from_metadata(metadata);
+        // With no server-side views since the last sync local changes win.
+        let local_wins = self.last_viewed_at == metadata.last_viewed_at
+            || self.local_playback_wins(&server_state, metadata.last_viewed_at);
+
+        if local_wins {
+            if server_state == self.playback_state {
+                self.last_viewed_at = metadata.last_viewed_at;
+                self.mark_playback_synced();
+            } else {
+                match self.playback_state {
+                    PlaybackState::Unplayed => {
+                        debug!(
+                            video = item.rating_key(),
+                            "Marking item as unwatched on server"
+                        );
+                        match plex_server.mark_unwatched(item).await {
+                            Ok(item) => {
+                                let metadata = item.metadata();
+                                self.playback_state = playback_state_from_metadata(metadata);
+                                self.last_viewed_at = metadata.last_viewed_at;
+                                self.mark_playback_synced();
+                            }
+                            Err(e) => {
+                                warn!("Failed to mark item as unwatched: {e}");
+                                return false;
+                            }
+                        }
+                    }
+                    PlaybackState::InProgress { position } => {
+                        debug!(
+                            video = item.rating_key(),
+                            position, "Updating playback position on server"
+                        );
+                        match plex_server.update_timeline(item, position).await {
+                            Ok(item) => {
+                                let metadata = item.metadata();
+                                self.playback_state = playback_state_from_metadata(metadata);
+                                self.last_viewed_at = metadata.last_viewed_at;
+                                self.mark_playback_synced();
+                            }
+                            Err(e) => {
+                                warn!("Failed to update playback position: {e}");
+                                return false;
+                            }
+                        }
+                    }
+                    PlaybackState::Played => {
+                        debug!(
+                            video = item.rating_key(),
+                            "Marking item as watched on server"
+                        );
+                        match plex_server.mark_watched(item).await {
+                            Ok(item) => {
+                                let metadata = item.metadata();
+                                self.playback_state = playback_state_from_metadata(metadata);
+                                self.last_viewed_at = metadata.last_viewed_at;
+                                self.mark_playback_synced();
+                            }
+                            Err(e) => {
+                                warn!("Failed to mark item as watched: {e}");
+                                return false;
+                            }
+                        }
+                    }
+                }
+            }
+        } else {
+            debug!(
+                video = item.rating_key(),
+                "Taking playback state from server"
+            );
+            self.last_viewed_at = metadata.last_viewed_at;
+            self.mark_playback_synced();
+            self.insert_playback_event(PlaybackEvent {
+                at: metadata
+                    .last_viewed_at
+                    .unwrap_or_else(OffsetDateTime::now_utc),
+                state: server_state.clone(),
+                synced: true,
+            });
+            self.playback_state = server_state;
+        }
+
+        true
+    }
+}
+
+/// A sidecar subtitle file for a video.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[typeshare]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SubtitleState {
+    /// The Plex stream ID.
+    pub(crate) id: String,
+    pub(crate) language: String,
+    #[serde(default)]
+    pub(crate) forced: bool,
+    /// The file extension, e.g. `srt`.
+    pub(crate) format: String,
+    pub(crate) file: RelatedFileState,
+}
+
+impl SubtitleState {
+    /// The extension used for this subtitle's file, including the language.
+    pub(crate) fn extension(&self) -> String {
+        if self.forced {
+            format!("{}.forced.{}", self.language, self.format)
+        } else {
+            format!("{}.{}", self.language, self.format)
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 #[typeshare]
 #[serde(rename_all = "camelCase")]
@@ -717,6 +1173,8 @@ pub(crate) struct VideoState {
     pub(crate) air_date: Option<Date>,
     pub(crate) thumbnail: RelatedFileState,
     #[serde(default, skip_serializing_if = "RelatedFileState::is_none")]
+    pub(crate) fanart: RelatedFileState,
+    #[serde(default, skip_serializing_if = "RelatedFileState::is_none")]
     pub(crate) metadata: RelatedFileState,
     pub(crate) media_id: String,
     #[serde(with = "time::serde::timestamp")]
@@ -724,11 +1182,60 @@ pub(crate) struct VideoState {
     pub(crate) last_updated: OffsetDateTime,
     pub(crate) parts: Vec<VideoPartState>,
     pub(crate) transcode_profile: Option<String>,
+    /// The playback progress of the server's main user.
+    #[serde(flatten)]
+    pub(crate) playback: PlaybackProgress,
+    /// The playback progress of additional users, by user name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) user_playback: HashMap<String, PlaybackProgress>,
+    /// Subtitle preferences from the sync items that include this video.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) subtitle_preferences: Option<SubtitlePreferences>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) subtitles: Vec<SubtitleState>,
+    /// When the subtitles were last checked against the server's streams.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[typeshare(skip)]
+    pub(crate) subtitles_checked: Option<SubtitlesChecked>,
+    /// Preferred audio languages from the sync items that include this video.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) audio_languages: Option<Vec<String>>,
+    #[serde(default)]
+    pub(crate) details: ItemDetails,
+    /// The playback details last written to the metadata file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[typeshare(skip)]
+    pub(crate) written_playback: Option<MetadataPlayback>,
+}
+
+/// The preferences that a video's subtitles were last chosen with and when.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SubtitlesChecked {
+    pub(crate) preferences: SubtitlePreferences,
+    #[serde(with = "time::serde::timestamp")]
+    pub(crate) at: OffsetDateTime,
+}
+
+impl SubtitlesChecked {
+    /// Whether the subtitles chosen still apply for the given preferences and
+    /// the video's last update.
+    pub(crate) fn is_current(
+        &self,
+        preferences: &SubtitlePreferences,
+        since: OffsetDateTime,
+    ) -> bool {
+        self.preferences == *preferences && self.at >= since
+    }
+}
+
+/// The playback details of a video that are included in its metadata file.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MetadataPlayback {
     pub(crate) playback_state: PlaybackState,
-    #[serde(default, with = "time::serde::timestamp::option")]
-    #[typeshare(serialized_as = "Option<number>")]
-    pub(crate) last_viewed_at: Option<OffsetDateTime>,
-    pub(crate) download: DownloadState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_played: Option<Date>,
 }
 
 fn playback_state_from_metadata(metadata: &Metadata) -> PlaybackState {
@@ -773,15 +1280,70 @@ impl VideoState {
             detail,
             air_date: metadata.originally_available_at,
             thumbnail: Default::default(),
+            fanart: Default::default(),
             metadata: Default::default(),
             media_id: media.metadata().id.clone().unwrap(),
             last_updated: metadata.updated_at.unwrap_or(OffsetDateTime::UNIX_EPOCH),
             parts,
             // Determined later
             transcode_profile: None,
-            playback_state: playback_state_from_metadata(metadata),
-            last_viewed_at: metadata.last_viewed_at,
-            download: DownloadState::None,
+            playback: PlaybackProgress::from(metadata),
+            user_playback: HashMap::new(),
+            subtitle_preferences: None,
+            subtitles: Vec::new(),
+            subtitles_checked: None,
+            audio_languages: None,
+            details: ItemDetails::from(metadata),
+            written_playback: None,
+        }
+    }
+
+    /// The playback details to include in the metadata file.
+    pub(crate) fn metadata_playback(&self) -> MetadataPlayback {
+        MetadataPlayback {
+            playback_state: self.playback.playback_state.clone(),
+            last_played: self.playback.last_viewed_at.map(|at| at.date()),
+        }
+    }
+
+    /// Whether the playback details have changed since the metadata file was
+    /// written.
+    pub(crate) fn metadata_playback_changed(&self) -> bool {
+        self.written_playback.as_ref() != Some(&self.metadata_playback())
+    }
+
+    /// The playback progress of a user, or the main user if `None`.
+    pub(crate) fn playback(&self, user: Option<&str>) -> Cow<'_, PlaybackProgress> {
+        match user {
+            None => Cow::Borrowed(&self.playback),
+            Some(user) => match self.user_playback.get(user) {
+                Some(progress) => Cow::Borrowed(progress),
+                None => Cow::Owned(PlaybackProgress::default()),
+            },
+        }
+    }
+
+    /// When the video was last played, if every one of the users has played it.
+    pub(crate) fn last_played_by_all(&self, users: &[Option<&str>]) -> Option<OffsetDateTime> {
+        let mut last_played = None;
+
+        for user in users {
+            let progress = self.playback(*user);
+            let viewed = match (&progress.playback_state, progress.last_viewed_at) {
+                (PlaybackState::Played, Some(viewed)) => viewed,
+                _ => return None,
+            };
+
+            last_played = last_played.max(Some(viewed));
+        }
+
+        last_played
+    }
+
+    pub(crate) fn playback_mut(&mut self, user: Option<&str>) -> &mut PlaybackProgress {
+        match user {
+            None => &mut self.playback,
+            Some(user) => self.user_playback.entry(user.to_owned()).or_default(),
         }
     }
 
@@ -795,58 +1357,10 @@ impl VideoState {
     ) {
         let metadata = item.metadata();
         self.title = item.title().to_owned();
+        self.air_date = metadata.originally_available_at;
+        self.details.update(metadata);
 
-        let server_state = playback_state_from_metadata(metadata);
-        if self.last_viewed_at == metadata.last_viewed_at {
-            // No server-side views since last sync.
-            if server_state != self.playback_state {
-                match self.playback_state {
-                    PlaybackState::Unplayed => {
-                        debug!(
-                            video = item.rating_key(),
-                            "Marking item as unwatched on server"
-                        );
-                        match plex_server.mark_unwatched(item).await {
-                            Ok(item) => {
-                                let metadata = item.metadata();
-                                self.playback_state = playback_state_from_metadata(metadata);
-                            }
-                            Err(e) => warn!("Failed to mark item as unwatched: {e}"),
-                        }
-                    }
-                    PlaybackState::InProgress { position } => {
-                        debug!(
-                            video = item.rating_key(),
-                            position, "Updating playback position on server"
-                        );
-                        match plex_server.update_timeline(item, position).await {
-                            Ok(item) => {
-                                let metadata = item.metadata();
-                                self.playback_state = playback_state_from_metadata(metadata);
-                            }
-                            Err(e) => warn!("Failed to update playback position: {e}"),
-                        }
-                    }
-                    PlaybackState::Played => {
-                        debug!(
-                            video = item.rating_key(),
-                            "Marking item as watched on server"
-                        );
-                        match plex_server.mark_watched(item).await {
-                            Ok(item) => {
-                                let metadata = item.metadata();
-                                self.playback_state = playback_state_from_metadata(metadata);
-                            }
-                            Err(e) => warn!("Failed to mark item as watched: {e}"),
-                        }
-                    }
-                }
-            }
-        } else {
-            // Viewed on the server, just take the server's state.
-            self.last_viewed_at = metadata.last_viewed_at;
-            self.playback_state = server_state;
-        }
+        self.playback.sync_playback(item, plex_server).await;
 
         match self.detail {
             VideoDetail::Movie(ref mut m) => m.update(metadata),
@@ -861,9 +1375,9 @@ impl VideoState {
         let parts = media.parts();
 
         if allow_delete && let Ok(guard) = server.try_lock_write_key(&self.id).await {
-            let mut parts_changed = parts.len() != self.parts.len();
-
-            if parts_changed {
+            if parts.len() != self.parts.len() {
+                info!("Video parts changed, deleting existing downloads.");
+                self.delete_downloads(&guard, plex_server, root).await;
                 self.parts = parts.iter().map(VideoPartState::from).collect()
             } else {
                 for (part_state, part) in self.parts.iter_mut().zip(parts.iter()) {
@@ -880,18 +1394,65 @@ impl VideoState {
                             old_duration = part_state.duration,
                             new_duration = metadata.duration,
                             part = part_state.id,
-                            "Part changed."
+                            "Part changed, deleting existing download."
                         );
-                        parts_changed = true;
+                        part_state.download.delete(&guard, plex_server, root).await;
                         *part_state = part.into();
                     }
                 }
             }
+        }
+    }
 
-            if parts_changed {
-                info!("Video parts changed, deleting existing download.");
-                self.download.delete(&guard, plex_server, root).await;
-            }
+    /// Whether every part has been downloaded.
+    pub(crate) fn is_downloaded(&self) -> bool {
+        self.parts
+            .iter()
+            .all(|part| !part.download.needs_download())
+    }
+
+    /// Whether the downloads were deleted by a retention policy.
+    pub(crate) fn is_expired(&self) -> bool {
+        !self.parts.is_empty()
+            && self
+                .parts
+                .iter()
+                .all(|part| part.download == DownloadState::Expired)
+    }
+
+    /// Whether any part has been downloaded or is being downloaded.
+    pub(crate) fn has_download(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| !matches!(part.download, DownloadState::None | DownloadState::Expired))
+    }
+
+    /// Whether any part has been, or is being, transcoded.
+    pub(crate) fn has_transcode(&self) -> bool {
+        self.parts.iter().any(VideoPartState::has_transcode)
+    }
+
+    pub(crate) async fn delete_downloads(
+        &mut self,
+        guard: &OpWriteGuard,
+        plex_server: &PlexServer,
+        root: &Path,
+    ) {
+        for part in self.parts.iter_mut() {
+            part.download.delete(guard, plex_server, root).await;
+        }
+    }
+
+    /// Deletes any local downloads, marking them so they will not be
+    /// downloaded again.
+    pub(crate) async fn expire_downloads(
+        &mut self,
+        guard: &OpWriteGuard,
+        plex_server: &PlexServer,
+        root: &Path,
+    ) {
+        for part in self.parts.iter_mut() {
+            part.download.expire(guard, plex_server, root).await;
         }
     }
 
@@ -902,19 +1463,105 @@ impl VideoState {
         root: &Path,
     ) {
         self.thumbnail.delete(guard, root).await;
+        self.fanart.delete(guard, root).await;
 
         self.metadata.delete(guard, root).await;
 
-        self.download.delete(guard, plex_server, root).await;
+        for subtitle in self.subtitles.iter_mut() {
+            subtitle.file.delete(guard, root).await;
+        }
+        self.subtitles.clear();
+
+        self.delete_downloads(guard, plex_server, root).await;
     }
 }
 
+/// Playback changes made outside of flick-sync, by the mobile app or by other
+/// players. This is the format of the `.flicksync.playback.json` file merged
+/// into the store on startup, of the files used by the `import-playback` and
+/// `export-playback` commands and of the `/playback.json` web endpoint:
+///
+/// ```json
+/// {
+///   "servers": {
+///     "<server id>": {
+///       "<video id>": { "state": "inprogress", "position": 754000, "at": 1718900000 },
+///       "<other video id>": { "state": "played" }
+///     }
+///   },
+///   "users": {
+///     "<user name>": {
+///       "<server id>": {
+///         "<video id>": { "state": "unplayed", "at": 1718900000 }
+///       }
+///     }
+///   }
+/// }
+/// ```
+///
+/// `servers` holds changes for the server's main user and `users` those of the
+/// additional users added with `add-user`. The state is one of `unplayed`,
+/// `inprogress` with a `position` in milliseconds, or `played`. `at` is the
+/// unix timestamp of the change, when missing the change is assumed to have
+/// happened when the file was written. Changes older than the latest known
+/// change for a video are kept in its history but do not alter its state.
 #[derive(Deserialize, Serialize, Clone, Debug, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct PlaybackUpdates {
-    pub servers: HashMap<String, HashMap<String, PlaybackState>>,
+    /// Playback changes for the main user by server ID and video ID.
+    #[serde(default)]
+    pub servers: ServerPlaybackUpdates,
+    /// Playback changes for additional users by user name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub users: HashMap<String, ServerPlaybackUpdates>,
+}
+
+impl PlaybackUpdates {
+    /// Reads playback updates from a file along with when the file was last
+    /// modified. Returns `None` if the file doesn't exist.
+    pub(crate) async fn read(path: &Path) -> Result<Option<(Self, OffsetDateTime)>> {
+        match fs::read_to_string(path).await {
+            Ok(str) => {
+                let updates = str.parse()?;
+                Ok(Some((updates, modified_time(path).await)))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl FromStr for PlaybackUpdates {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(serde_json::from_str(s)?)
+    }
+}
+
+/// A change to a video's playback state.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct PlaybackUpdate {
+    #[serde(flatten)]
+    pub state: PlaybackState,
+    /// When the change happened, if known.
+    #[serde(
+        default,
+        with = "time::serde::timestamp::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub at: Option<OffsetDateTime>,
 }
 
+impl From<PlaybackState> for PlaybackUpdate {
+    fn from(state: PlaybackState) -> Self {
+        Self { state, at: None }
+    }
+}
+
+/// Playback changes by server ID and then video ID.
+pub type ServerPlaybackUpdates = HashMap<String, HashMap<String, PlaybackUpdate>>;
+
 #[derive(Deserialize, Default, Serialize, Clone, Debug)]
 #[typeshare]
 #[serde(rename_all = "camelCase")]
@@ -934,6 +1581,9 @@ pub(crate) struct ServerState {
     pub(crate) seasons: HashMap<String, SeasonState>,
     #[serde(default)]
     pub(crate) videos: HashMap<String, VideoState>,
+    /// The videos last resolved for each filter or source sync.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) resolved: HashMap<String, Vec<String>>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -947,6 +1597,106 @@ pub(crate) struct State {
 }
 
 impl State {
+    /// Merges playback updates into the state, returning whether anything
+    /// changed. Updates without a time are assumed to have been made at the
+    /// given time. Updates for unknown servers or videos, or for users that
+    /// are not configured for the server, are ignored.
+    pub(crate) fn merge_playback_updates(
+        &mut self,
+        config: &Config,
+        updates: PlaybackUpdates,
+        at: OffsetDateTime,
+    ) -> bool {
+        let mut changed = self.merge_user_playback(config, None, updates.servers, at);
+
+        for (user, servers) in updates.users {
+            changed |= self.merge_user_playback(config, Some(&user), servers, at);
+        }
+
+        changed
+    }
+
+    fn merge_user_playback(
+        &mut self,
+        config: &Config,
+        user: Option<&str>,
+        servers: ServerPlaybackUpdates,
+        at: OffsetDateTime,
+    ) -> bool {
+        let mut changed = false;
+
+        for (server_id, videos) in servers {
+            if let Some(user) = user
+                && !config
+                    .servers
+                    .get(&server_id)
+                    .is_some_and(|server| server.users.contains_key(user))
+            {
+                warn!(
+                    server = server_id,
+                    user, "Ignoring playback for unknown user"
+                );
+                continue;
+            }
+
+            if let Some(server_state) = self.servers.get_mut(&server_id) {
+                for (video_id, update) in videos {
+                    if let Some(video) = server_state.videos.get_mut(&video_id) {
+                        let progress = video.playback_mut(user);
+                        let update_at = update.at.unwrap_or(at);
+
+                        // Re-importing the same changes shouldn't add history. An
+                        // update without a time is dated by the file, which may
+                        // have been touched since.
+                        if (progress.playback_state == update.state
+                            && (update.at.is_none()
+                                || progress.last_change().is_some_and(|last| last >= update_at)))
+                            || progress
+                                .playback_history
+                                .iter()
+                                .any(|event| event.at == update_at && event.state == update.state)
+                        {
+                            continue;
+                        }
+
+                        progress.record_playback(update.state, update_at);
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// The current playback state of every video, in the same form as
+    /// imported updates.
+    pub(crate) fn playback_updates(&self) -> PlaybackUpdates {
+        let mut updates = PlaybackUpdates::default();
+
+        for (server_id, server_state) in &self.servers {
+            for (video_id, video) in &server_state.videos {
+                updates
+                    .servers
+                    .entry(server_id.clone())
+                    .or_default()
+                    .insert(video_id.clone(), video.playback.to_update());
+
+                for (user, progress) in &video.user_playback {
+                    updates
+                        .users
+                        .entry(user.clone())
+                        .or_default()
+                        .entry(server_id.clone())
+                        .or_default()
+                        .insert(video_id.clone(), progress.to_update());
+                }
+            }
+        }
+
+        updates
+    }
+
     fn migrate_v0(data: &mut JsonObject) -> Result {
         for thumbnail in data
             .prop("servers")
@@ -1150,6 +1900,52 @@ impl State {
 
         Ok(())
     }
+
+    fn migrate_v5(data: &mut JsonObject) -> Result {
+        for video in data
+            .prop("servers")
+            .values()
+            .prop("videos")
+            .values()
+            .as_object()
+        {
+            // Previously videos were downloaded as a single file. Keep any existing
+            // download, or its queue item, as the first part so that it is neither
+            // lost nor leaked.
+            let mut download = video.remove("download");
+            for part in video.prop("parts").values().as_object() {
+                part.insert(
+                    "download".to_string(),
+                    download
+                        .take()
+                        .unwrap_or_else(|| to_value(DownloadState::None).unwrap()),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn migrate_v6(data: &mut JsonObject) -> Result {
+        for video in data
+            .prop("servers")
+            .values()
+            .prop("videos")
+            .values()
+            .as_object()
+        {
+            // The selected audio stream was recorded once per video, it only
+            // ever applied to the first part.
+            let mut audio_stream = video.remove("audioStream");
+            for part in video.prop("parts").values().as_object() {
+                if let Some(audio_stream) = audio_stream.take() {
+                    part.insert("audioStream".to_string(), audio_stream);
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Default for State {
@@ -1204,8 +2000,341 @@ impl MigratableStore for State {
             Self::migrate_v4(data)?;
         }
 
+        if version < 6 {
+            Self::migrate_v5(data)?;
+        }
+
+        if version < 7 {
+            Self::migrate_v6(data)?;
+        }
+
         data.insert("schema".to_string(), SCHEMA_VERSION.into());
 
         Ok(true)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_value, json};
+    use time::OffsetDateTime;
+
+    use crate::{
+        config::Config,
+        state::{
+            DownloadState, PlaybackEvent, PlaybackProgress, PlaybackState, PlaybackUpdates,
+            ServerState, State, VideoState,
+        },
+    };
+
+    fn at(seconds: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(seconds).unwrap()
+    }
+
+    fn in_progress(position: u64) -> PlaybackState {
+        PlaybackState::InProgress { position }
+    }
+
+    fn history(progress: &PlaybackProgress) -> Vec<(i64, PlaybackState, bool)> {
+        progress
+            .playback_history
+            .iter()
+            .map(|event| (event.at.unix_timestamp(), event.state.clone(), event.synced))
+            .collect()
+    }
+
+    fn video_state(downloads: &[DownloadState]) -> VideoState {
+        let parts: Vec<_> = downloads
+            .iter()
+            .enumerate()
+            .map(|(index, download)| {
+                json!({
+                    "id": index.to_string(),
+                    "key": format!("/library/parts/{index}"),
+                    "size": 1000,
+                    "duration": 60000,
+                    "download": download,
+                })
+            })
+            .collect();
+
+        from_value(json!({
+            "id": "1",
+            "title": "Movie",
+            "detail": { "library": "1", "year": 2000 },
+            "airDate": null,
+            "thumbnail": { "state": "none" },
+            "mediaId": "1",
+            "lastUpdated": 0,
+            "parts": parts,
+            "transcodeProfile": null,
+            "playbackState": { "state": "unplayed" },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn transcoded_downloads() {
+        let downloaded = DownloadState::Downloaded {
+            path: "movie.mkv".into(),
+        };
+        let transcoded = DownloadState::Transcoded {
+            path: "movie.mp4".into(),
+        };
+
+        assert!(!video_state(&[DownloadState::None]).has_transcode());
+        assert!(!video_state(&[downloaded.clone(), DownloadState::None]).has_transcode());
+        assert!(video_state(&[DownloadState::Transcoding { queue_id: 5 }]).has_transcode());
+        assert!(video_state(&[downloaded, transcoded]).has_transcode());
+    }
+
+    #[test]
+    fn metadata_playback() {
+        let mut state = video_state(&[DownloadState::None]);
+        assert!(state.metadata_playback_changed());
+
+        state.written_playback = Some(state.metadata_playback());
+        assert!(!state.metadata_playback_changed());
+
+        state.playback.playback_state = PlaybackState::InProgress { position: 5000 };
+        assert!(state.metadata_playback_changed());
+
+        state.written_playback = Some(state.metadata_playback());
+        state.playback.last_viewed_at = Some(time::OffsetDateTime::UNIX_EPOCH);
+        assert!(state.metadata_playback_changed());
+    }
+
+    #[test]
+    fn record_playback() {
+        let mut progress = PlaybackProgress::default();
+
+        progress.record_playback(in_progress(1000), at(100));
+        progress.record_playback(in_progress(2000), at(110));
+        assert_eq!(progress.playback_state, in_progress(2000));
+        assert_eq!(history(&progress), vec![(110, in_progress(2000), false)]);
+
+        progress.record_playback(PlaybackState::Played, at(200));
+        progress.record_playback(PlaybackState::Unplayed, at(150));
+        assert_eq!(progress.playback_state, PlaybackState::Played);
+        assert_eq!(
+            history(&progress),
+            vec![
+                (110, in_progress(2000), false),
+                (150, PlaybackState::Unplayed, false),
+                (200, PlaybackState::Played, false),
+            ]
+        );
+        assert_eq!(progress.last_change(), Some(at(200)));
+
+        progress.mark_playback_synced();
+        progress.record_playback(in_progress(500), at(300));
+        progress.record_playback(in_progress(600), at(310));
+        assert_eq!(
+            history(&progress)[2..],
+            [
+                (200, PlaybackState::Played, true),
+                (310, in_progress(600), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn local_playback_wins() {
+        let mut progress = PlaybackProgress::default();
+        assert!(!progress.local_playback_wins(&PlaybackState::Played, Some(at(100))));
+
+        progress.playback_history.push(PlaybackEvent {
+            at: at(100),
+            state: in_progress(1000),
+            synced: true,
+        });
+        assert!(!progress.local_playback_wins(&in_progress(5000), Some(at(300))));
+
+        // Both sides progressed, the furthest wins regardless of time.
+        progress.record_playback(in_progress(5000), at(200));
+        assert!(progress.local_playback_wins(&in_progress(3000), Some(at(300))));
+        assert!(!progress.local_playback_wins(&in_progress(8000), Some(at(150))));
+
+        // Otherwise the most recent change wins.
+        progress.record_playback(PlaybackState::Unplayed, at(250));
+        assert!(!progress.local_playback_wins(&in_progress(3000), Some(at(300))));
+        assert!(progress.local_playback_wins(&in_progress(3000), Some(at(200))));
+        assert!(progress.local_playback_wins(&in_progress(3000), None));
+    }
+
+    #[test]
+    fn merge_user_playback() {
+        let mut server = ServerState::default();
+        server
+            .videos
+            .insert("1".to_string(), video_state(&[DownloadState::None]));
+        let mut state = State::default();
+        state.servers.insert("server".to_string(), server.clone());
+        state.servers.insert("other".to_string(), server);
+
+        let config: Config = from_value(json!({
+            "servers": {
+                "server": {
+                    "connection": { "type": "Direct", "url": "http://localhost:32400" },
+                    "users": { "alice": "1" },
+                },
+                "other": {
+                    "connection": { "type": "Direct", "url": "http://localhost:32401" },
+                },
+            },
+        }))
+        .unwrap();
+
+        let updates = |json: serde_json::Value| -> PlaybackUpdates { from_value(json).unwrap() };
+        let progress = |state: &State| state.servers["server"].videos["1"].playback.clone();
+
+        let first = updates(json!({
+            "servers": {
+                "server": {
+                    "1": { "state": "inprogress", "position": 5000, "at": 100 },
+                    "2": { "state": "played" },
+                },
+                "other": { "1": { "state": "played" } },
+            },
+        }));
+        assert!(state.merge_playback_updates(&config, first.clone(), at(1000)));
+        assert_eq!(progress(&state).playback_state, in_progress(5000));
+
+        // Re-importing the same file changes nothing.
+        assert!(!state.merge_playback_updates(&config, first, at(2000)));
+
+        // Nor does re-importing an older change that is still in the history.
+        let played = updates(json!({
+            "servers": { "server": { "1": { "state": "played" } } },
+        }));
+        assert!(state.merge_playback_updates(&config, played.clone(), at(200)));
+        assert!(!state.merge_playback_updates(&config, played, at(200)));
+        assert!(!state.merge_playback_updates(
+            &config,
+            updates(json!({
+                "servers": {
+                    "server": { "1": { "state": "inprogress", "position": 5000, "at": 100 } },
+                },
+            })),
+            at(3000),
+        ));
+
+        // Users are only known on the servers they are configured for.
+        assert!(state.merge_playback_updates(
+            &config,
+            updates(json!({
+                "users": {
+                    "alice": {
+                        "server": { "1": { "state": "played" } },
+                        "other": { "1": { "state": "played" } },
+                    },
+                    "bob": { "server": { "1": { "state": "played" } } },
+                },
+            })),
+            at(4000),
+        ));
+        let video = &state.servers["server"].videos["1"];
+        assert_eq!(
+            video.playback(Some("alice")).playback_state,
+            PlaybackState::Played
+        );
+        assert!(!video.user_playback.contains_key("bob"));
+        assert!(state.servers["other"].videos["1"].user_playback.is_empty());
+
+        let progress = progress(&state);
+        assert_eq!(progress.playback_state, PlaybackState::Played);
+        assert_eq!(
+            history(&progress),
+            vec![
+                (100, in_progress(5000), false),
+                (200, PlaybackState::Played, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn playback_updates_format() {
+        // The example from the `PlaybackUpdates` documentation.
+        let updates: PlaybackUpdates = r#"{
+              "servers": {
+                "<server id>": {
+                  "<video id>": { "state": "inprogress", "position": 754000, "at": 1718900000 },
+                  "<other video id>": { "state": "played" }
+                }
+              },
+              "users": {
+                "<user name>": {
+                  "<server id>": {
+                    "<video id>": { "state": "unplayed", "at": 1718900000 }
+                  }
+                }
+              }
+            }"#
+        .parse()
+        .unwrap();
+
+        let videos = &updates.servers["<server id>"];
+        assert_eq!(videos["<video id>"].state, in_progress(754000));
+        assert_eq!(videos["<video id>"].at, Some(at(1718900000)));
+        assert_eq!(videos["<other video id>"].state, PlaybackState::Played);
+        assert_eq!(videos["<other video id>"].at, None);
+
+        let update = &updates.users["<user name>"]["<server id>"]["<video id>"];
+        assert_eq!(update.state, PlaybackState::Unplayed);
+        assert_eq!(update.at, Some(at(1718900000)));
+
+        // Files written by older versions of the Android app have no times
+        // or users.
+        let updates: PlaybackUpdates =
+            r#"{"servers":{"server":{"1":{"state":"inprogress","position":5000},"2":{"state":"unplayed"}}}}"#
+                .parse()
+                .unwrap();
+        assert!(updates.users.is_empty());
+        assert_eq!(updates.servers["server"]["1"].state, in_progress(5000));
+        assert_eq!(updates.servers["server"]["1"].at, None);
+        assert_eq!(
+            updates.servers["server"]["2"].state,
+            PlaybackState::Unplayed
+        );
+    }
+
+    #[test]
+    fn playback_updates_round_trip() {
+        let mut server = ServerState::default();
+        for id in ["1", "2", "3"] {
+            server
+                .videos
+                .insert(id.to_string(), video_state(&[DownloadState::None]));
+        }
+
+        let videos = &mut server.videos;
+        videos
+            .get_mut("1")
+            .unwrap()
+            .playback
+            .record_playback(in_progress(5000), at(100));
+        videos.get_mut("2").unwrap().playback.playback_state = PlaybackState::Played;
+        let alice = videos.get_mut("3").unwrap().playback_mut(Some("alice"));
+        alice.record_playback(PlaybackState::Played, at(200));
+        alice.record_playback(PlaybackState::Unplayed, at(150));
+
+        let mut state = State::default();
+        state.servers.insert("server".to_string(), server);
+
+        let config: Config = from_value(json!({
+            "servers": {
+                "server": {
+                    "connection": { "type": "Direct", "url": "http://localhost:32400" },
+                    "users": { "alice": "1" },
+                },
+            },
+        }))
+        .unwrap();
+
+        let exported = serde_json::to_string(&state.playback_updates()).unwrap();
+        let before = serde_json::to_value(&state).unwrap();
+
+        assert!(!state.merge_playback_updates(&config, exported.parse().unwrap(), at(1000)));
+        assert_eq!(serde_json::to_value(&state).unwrap(), before);
+    }
+}
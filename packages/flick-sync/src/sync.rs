@@ -16,14 +16,21 @@ use file_format::FileFormat;
 use lazy_static::lazy_static;
 use mime::Mime;
 use pin_project::pin_project;
+use thiserror::Error;
+use time::OffsetDateTime;
 use tokio::{
     fs,
     io::{AsyncRead, AsyncSeek, BufReader, ReadBuf},
     sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock},
-    time::timeout,
+    time::{Instant, timeout},
 };
 use tracing::trace;
 
+use crate::{
+    config::{Config, DownloadWindow},
+    util::local_offset_at,
+};
+
 type Lock = Arc<RwLock<()>>;
 
 const BUFFER_CAPACITY: usize = 4 * 8 * 1024;
@@ -143,6 +150,75 @@ impl Clone for OpReadGuard {
     }
 }
 
+/// Returned when a download is attempted outside of the allowed download
+/// windows.
+#[derive(Debug, Error)]
+#[error("Outside of the allowed download windows")]
+pub(crate) struct OutsideDownloadWindow;
+
+impl OutsideDownloadWindow {
+    /// Whether an error was caused by a download window closing.
+    pub(crate) fn is_cause(error: &anyhow::Error) -> bool {
+        error.chain().any(|cause| {
+            cause.is::<Self>()
+                || cause
+                    .downcast_ref::<io::Error>()
+                    .and_then(|e| e.get_ref())
+                    .is_some_and(|inner| inner.is::<Self>())
+        })
+    }
+}
+
+/// Limits the rate and times of day at which downloads happen.
+pub(crate) struct Throttle {
+    rate: Option<u64>,
+    windows: Vec<DownloadWindow>,
+    next: StdMutex<Instant>,
+}
+
+impl Throttle {
+    pub(crate) fn new(config: &Config) -> Self {
+        Self {
+            rate: config.max_download_rate,
+            windows: config.download_windows.clone(),
+            next: StdMutex::new(Instant::now()),
+        }
+    }
+
+    /// Returns how long until downloads are allowed or `None` if they are
+    /// allowed now.
+    pub(crate) fn wait_time(&self) -> Option<Duration> {
+        if self.windows.is_empty() {
+            return None;
+        }
+
+        let now = OffsetDateTime::now_utc();
+        let (hour, minute, second) = now.to_offset(local_offset_at(now)).to_hms();
+        let now = u32::from(hour) * 3600 + u32::from(minute) * 60 + u32::from(second);
+        if self.windows.iter().any(|window| window.contains(now)) {
+            return None;
+        }
+
+        self.windows
+            .iter()
+            .map(|window| window.until_open(now))
+            .min()
+            .map(|seconds| Duration::from_secs(seconds.into()))
+    }
+
+    /// Records that some bytes have been downloaded. Returns the time that the
+    /// next write should wait until if the rate is limited.
+    pub(crate) fn consume(&self, bytes: u64) -> Option<Instant> {
+        let rate = self.rate.filter(|rate| *rate > 0)?;
+
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap();
+        *next = (*next).max(now) + Duration::from_secs_f64(bytes as f64 / rate as f64);
+
+        Some(*next)
+    }
+}
+
 #[derive(Clone)]
 pub struct LockedFile {
     guard: OpReadGuard,
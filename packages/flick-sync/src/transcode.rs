@@ -0,0 +1,385 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use anyhow::bail;
+use time::Date;
+use tokio::{
+    fs::{remove_file, rename},
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+};
+use tracing::{instrument, trace};
+
+use crate::{Progress, Result};
+
+/// Reports progress as a percentage of the total length, the same as the
+/// progress Plex reports for its own transcodes.
+pub(crate) struct PercentProgress<P> {
+    inner: P,
+    length: u64,
+}
+
+impl<P: Progress> PercentProgress<P> {
+    pub(crate) fn new(inner: P) -> Self {
+        Self { inner, length: 0 }
+    }
+}
+
+impl<P: Progress> Progress for PercentProgress<P> {
+    fn progress(&mut self, position: u64) {
+        if let Some(percent) = (position.min(self.length) * 100).checked_div(self.length) {
+            self.inner.progress(percent);
+        }
+    }
+
+    fn length(&mut self, length: u64) {
+        self.length = length;
+        self.inner.length(100);
+    }
+
+    fn finished(self) {
+        self.inner.finished()
+    }
+
+    fn failed(self, error: anyhow::Error) {
+        self.inner.failed(error)
+    }
+}
+
+/// The streams of the source file to keep in a local transcode.
+#[derive(Default, Debug)]
+pub(crate) struct LocalStreams {
+    /// The index of the audio stream to keep, otherwise every audio stream is
+    /// kept.
+    pub(crate) audio: Option<u32>,
+    /// A subtitle stream to burn into the video.
+    pub(crate) burned_subtitle: Option<BurnedSubtitle>,
+}
+
+#[derive(Debug)]
+pub(crate) enum BurnedSubtitle {
+    /// An image based subtitle stream, by its index in the source file.
+    Image { index: u32 },
+    /// A text subtitle stream, by its position amongst the subtitle streams of
+    /// the source file.
+    Text { source: PathBuf, position: usize },
+}
+
+/// Escapes a value for use as a filter option in an ffmpeg filtergraph.
+pub(crate) fn escape_filter_value(value: &str) -> String {
+    let escape = |value: &str, special: &[char]| {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            if special.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    };
+
+    escape(
+        &escape(value, &['\\', '\'', ':']),
+        &['\\', '\'', '[', ']', ',', ';'],
+    )
+}
+
+/// Transcodes a local file with ffmpeg. Progress is reported in microseconds
+/// of the output written.
+#[instrument(level = "trace", skip(args, progress))]
+pub(crate) async fn transcode<P: Progress>(
+    ffmpeg: &Path,
+    source: &Path,
+    target: &Path,
+    args: &[String],
+    duration: Duration,
+    progress: &mut P,
+) -> Result {
+    let mut child = Command::new(ffmpeg)
+        .arg("-y")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-nostats")
+        .arg("-progress")
+        .arg("pipe:1")
+        .arg("-i")
+        .arg(source)
+        .args(args)
+        .arg(target)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    progress.length(duration.as_micros() as u64);
+
+    if let Some(stdout) = child.stdout.take() {
+        let mut lines = BufReader::new(stdout).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            // Despite the name, older versions of ffmpeg report microseconds
+            // in `out_time_ms` too.
+            if let Some(time) = line
+                .strip_prefix("out_time_us=")
+                .or_else(|| line.strip_prefix("out_time_ms="))
+                && let Ok(time) = time.parse::<u64>()
+            {
+                trace!(time, "Transcode progress");
+                progress.progress(time);
+            }
+        }
+    }
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        bail!(
+            "ffmpeg failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Tags to embed in a downloaded video file.
+#[derive(Default, Debug)]
+pub(crate) struct MediaTags {
+    pub(crate) title: String,
+    pub(crate) show: Option<String>,
+    pub(crate) season: Option<usize>,
+    pub(crate) episode: Option<usize>,
+    pub(crate) air_date: Option<Date>,
+    /// Absolute path to the cover art.
+    pub(crate) cover: Option<PathBuf>,
+}
+
+impl MediaTags {
+    fn metadata(&self, is_mp4: bool) -> Vec<(&'static str, String)> {
+        let mut metadata = vec![("title", self.title.clone())];
+
+        if let Some(ref show) = self.show {
+            metadata.push(("show", show.clone()));
+        }
+
+        if let Some(season) = self.season {
+            metadata.push(("season_number", season.to_string()));
+        }
+
+        if let Some(episode) = self.episode {
+            metadata.push(("episode_sort", episode.to_string()));
+        }
+
+        if let Some(air_date) = self.air_date {
+            metadata.push(("date", air_date.to_string()));
+        }
+
+        if is_mp4 {
+            // iTunes media kinds, 9 is a movie and 10 is a TV show.
+            let media_type = if self.show.is_some() { "10" } else { "9" };
+            metadata.push(("media_type", media_type.to_string()));
+        }
+
+        metadata
+    }
+}
+
+fn is_mp4(path: &Path) -> bool {
+    matches!(
+        path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .as_deref(),
+        Some("mp4" | "m4v" | "mov")
+    )
+}
+
+/// Builds the ffmpeg arguments that copy a video file to `target` with its
+/// global metadata replaced by the given tags and `cover` embedded as the
+/// cover art.
+fn retag_args(path: &Path, target: &Path, tags: &MediaTags, cover: Option<&Path>) -> Vec<OsString> {
+    let is_mp4 = is_mp4(path);
+    let mut args: Vec<OsString> = vec!["-y".into(), "-loglevel".into(), "error".into()];
+    args.extend(["-i".into(), path.into()]);
+
+    if is_mp4 && let Some(cover) = cover {
+        args.extend(["-i".into(), cover.into()]);
+    }
+
+    // Existing cover art and attachments are dropped and replaced.
+    args.extend(
+        [
+            "-map",
+            "0:V?",
+            "-map",
+            "0:a?",
+            "-map",
+            "0:s?",
+            "-map_metadata:g",
+            "-1",
+            "-c",
+            "copy",
+        ]
+        .map(OsString::from),
+    );
+
+    if let Some(cover) = cover {
+        if is_mp4 {
+            args.extend(["-map", "1", "-disposition:v:1", "attached_pic"].map(OsString::from));
+        } else {
+            let (mime_type, filename) = match cover.extension().and_then(|e| e.to_str()) {
+                Some("png") => ("image/png", "cover.png"),
+                _ => ("image/jpeg", "cover.jpg"),
+            };
+
+            args.extend([
+                "-attach".into(),
+                cover.into(),
+                "-metadata:s:t:0".into(),
+                format!("mimetype={mime_type}").into(),
+                "-metadata:s:t:0".into(),
+                format!("filename={filename}").into(),
+            ]);
+        }
+    }
+
+    for (key, value) in tags.metadata(is_mp4) {
+        args.extend(["-metadata".into(), format!("{key}={value}").into()]);
+    }
+
+    args.push(target.into());
+
+    args
+}
+
+/// Replaces the global metadata of a video file with the given tags, leaving
+/// the streams untouched. Cover art is only embedded in MP4 and MKV files.
+#[instrument(level = "trace", skip(tags))]
+pub(crate) async fn retag(ffmpeg: &Path, path: &Path, tags: &MediaTags) -> Result {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let is_mkv = extension == "mkv";
+
+    let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+        bail!("Invalid video path");
+    };
+    // Keep the extension so ffmpeg picks the same container format.
+    let temp_file = path.with_file_name(format!(".{file_name}.tmp.{extension}"));
+
+    let cover = tags
+        .cover
+        .as_deref()
+        .filter(|cover| (is_mp4(path) || is_mkv) && cover.exists());
+
+    let output = Command::new(ffmpeg)
+        .args(retag_args(path, &temp_file, tags, cover))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let _ = remove_file(&temp_file).await;
+
+        bail!(
+            "ffmpeg failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    rename(&temp_file, path).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, path::Path};
+
+    use time::{Date, Month};
+
+    use crate::transcode::{MediaTags, retag_args};
+
+    fn strings(args: Vec<OsString>) -> Vec<String> {
+        args.into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn retag_arguments() {
+        let tags = MediaTags {
+            title: "The Unquiet Dead".to_string(),
+            show: Some("Doctor Who".to_string()),
+            season: Some(1),
+            episode: Some(3),
+            air_date: Some(Date::from_calendar_date(2005, Month::April, 9).unwrap()),
+            cover: None,
+        };
+
+        let args = strings(retag_args(
+            Path::new("/videos/episode.mp4"),
+            Path::new("/videos/.episode.mp4.tmp.mp4"),
+            &tags,
+            Some(Path::new("/videos/poster.jpg")),
+        ));
+
+        assert_eq!(
+            args[..8],
+            [
+                "-y",
+                "-loglevel",
+                "error",
+                "-i",
+                "/videos/episode.mp4",
+                "-i",
+                "/videos/poster.jpg",
+                "-map"
+            ]
+        );
+        assert!(
+            args.windows(4)
+                .any(|w| w == ["-map", "1", "-disposition:v:1", "attached_pic"])
+        );
+        for tag in [
+            "title=The Unquiet Dead",
+            "show=Doctor Who",
+            "season_number=1",
+            "episode_sort=3",
+            "date=2005-04-09",
+            "media_type=10",
+        ] {
+            assert!(args.windows(2).any(|w| w == ["-metadata", tag]), "{tag}");
+        }
+        assert_eq!(args.last().unwrap(), "/videos/.episode.mp4.tmp.mp4");
+
+        let tags = MediaTags {
+            title: "Alien".to_string(),
+            ..Default::default()
+        };
+        let args = strings(retag_args(
+            Path::new("/videos/movie.mkv"),
+            Path::new("/videos/.movie.mkv.tmp.mkv"),
+            &tags,
+            Some(Path::new("/videos/poster.png")),
+        ));
+
+        assert_eq!(args.iter().filter(|a| *a == "-i").count(), 1);
+        assert!(
+            args.windows(2)
+                .any(|w| w == ["-attach", "/videos/poster.png"])
+        );
+        assert!(args.iter().any(|a| a == "mimetype=image/png"));
+        assert!(args.iter().any(|a| a == "title=Alien"));
+        assert!(!args.iter().any(|a| a.starts_with("media_type=")));
+    }
+}
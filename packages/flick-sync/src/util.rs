@@ -7,12 +7,14 @@ use std::{
     path::Path,
     pin::Pin,
     result,
+    sync::Once,
     task::{Context, Poll},
 };
 
 use pin_project::pin_project;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::to_string_pretty;
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 pub(crate) trait ListItem<T> {
@@ -30,7 +32,7 @@ macro_rules! derive_list_item {
 }
 
 pub(crate) use derive_list_item;
-use tracing::trace;
+use tracing::{trace, warn};
 
 pub(crate) fn from_list<'de, D, K, V>(deserializer: D) -> result::Result<HashMap<K, V>, D::Error>
 where
@@ -79,6 +81,34 @@ pub(crate) async fn safe_write<S: Serialize>(
     Ok(tokio::fs::rename(temp_path, path).await?)
 }
 
+/// The time a file was last modified, or now if that is unavailable.
+pub(crate) async fn modified_time(path: &Path) -> OffsetDateTime {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|stats| stats.modified())
+        .map(OffsetDateTime::from)
+        .unwrap_or_else(|_| OffsetDateTime::now_utc())
+}
+
+/// The system's offset from UTC at the given time, which changes with
+/// daylight saving. Falls back to UTC if the time zone cannot be determined.
+pub(crate) fn local_offset_at(time: OffsetDateTime) -> UtcOffset {
+    static WARNED: Once = Once::new();
+
+    UtcOffset::local_offset_at(time).unwrap_or_else(|e| {
+        WARNED.call_once(|| warn!(error=%e, "Unable to determine the local time zone, using UTC"));
+        UtcOffset::UTC
+    })
+}
+
+/// Interprets a date and time in the system's time zone.
+pub(crate) fn assume_local(time: PrimitiveDateTime) -> OffsetDateTime {
+    // Guess the offset from the same time in UTC then correct the guess in
+    // case a daylight saving change falls in between.
+    let guess = time.assume_offset(local_offset_at(time.assume_utc()));
+    time.assume_offset(local_offset_at(guess))
+}
+
 pub(crate) fn safe<S: AsRef<str>>(str: S) -> String {
     str.as_ref()
         .chars()
@@ -90,6 +120,32 @@ pub(crate) fn safe<S: AsRef<str>>(str: S) -> String {
         .collect()
 }
 
+/// Percent-encodes any characters that are not allowed in a URL query string.
+pub(crate) fn encode_query(query: &str) -> String {
+    let mut encoded = String::with_capacity(query.len());
+    for byte in query.bytes() {
+        match byte {
+            b'!' | b'$'..=b';' | b'=' | b'?'..=b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Percent-encodes a single key or value for use in a URL query string.
+pub(crate) fn encode_param(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'-' | b'.' | b'0'..=b'9' | b'A'..=b'Z' | b'_' | b'a'..=b'z' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
 #[pin_project]
 pub(crate) struct AsyncWriteAdapter<W> {
     #[pin]
@@ -194,7 +250,7 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::util::parallelize;
+    use crate::util::{encode_param, encode_query, parallelize};
 
     use futures::task::noop_waker;
     use std::{
@@ -235,6 +291,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn query_encoding() {
+        assert_eq!(
+            encode_query("unwatched=1&genre=Science Fiction"),
+            "unwatched=1&genre=Science%20Fiction"
+        );
+        assert_eq!(encode_query("addedAt>>=-30d"), "addedAt%3E%3E=-30d");
+        assert_eq!(encode_query("title<=M"), "title%3C=M");
+        // Already encoded queries are passed through.
+        assert_eq!(encode_query("title=Star%20Trek"), "title=Star%20Trek");
+        assert_eq!(encode_query("title=#1"), "title=%231");
+        assert_eq!(encode_query("title=Amélie"), "title=Am%C3%A9lie");
+        assert_eq!(
+            encode_query("year=1990,1991&sort=title:desc"),
+            "year=1990,1991&sort=title:desc"
+        );
+    }
+
+    #[test]
+    fn param_encoding() {
+        assert_eq!(encode_param("subtitleStreamID"), "subtitleStreamID");
+        assert_eq!(encode_param("1920x1080"), "1920x1080");
+        assert_eq!(
+            encode_param("add-limitation(scope=videoCodec&type=upperBound)"),
+            "add-limitation%28scope%3DvideoCodec%26type%3DupperBound%29"
+        );
+        assert_eq!(encode_param("a+b c"), "a%2Bb%20c");
+    }
+
     #[test]
     fn limits_parallelism_and_backfills_slots() {
         let states = (0..4)
@@ -1,5 +1,6 @@
 use std::{
     cmp::Ordering,
+    collections::HashSet,
     fmt,
     hash::{Hash, Hasher},
     io::{ErrorKind, IoSlice},
@@ -8,51 +9,170 @@ use std::{
     path::{Path, PathBuf},
     pin::Pin,
     result,
-    task::{Context, Poll},
+    task::{Context, Poll, ready},
     time::Duration,
 };
 
 use anyhow::{anyhow, bail};
-use futures::io::AsyncWrite;
+use futures::io::{AsyncWrite, AsyncWriteExt as _, Cursor};
 use pathdiff::diff_paths;
 use pin_project::pin_project;
 use plex_api::{
-    Server as PlexServer,
-    library::{self, Item, MetadataItem, Transcodable},
-    media_container::server::library::ContainerFormat,
-    transcode::{DownloadQueue, QueueItemStatus, VideoTranscodeOptions},
+    HttpClient, Server as PlexServer,
+    library::{self, Item, MediaItem, MetadataItem, Transcodable},
+    media_container::server::library::{
+        AudioStream, ContainerFormat, Protocol, Stream, SubtitleCodec, SubtitleStream,
+    },
+    transcode::{
+        Context as TranscodeContext, DownloadQueue, QueueItem, QueueItemStatus, TranscodeOptions,
+        VideoTranscodeOptions,
+    },
 };
+use serde::Deserialize;
 use time::{Date, OffsetDateTime};
 use tokio::{
-    fs::{File, OpenOptions, create_dir_all, metadata},
+    fs::{File, OpenOptions, create_dir_all, metadata, remove_file, rename},
     io::{AsyncWriteExt, BufWriter},
     sync::OwnedSemaphorePermit,
-    time::sleep,
+    time::{Sleep, sleep, sleep_until},
 };
 use tracing::{debug, info, instrument, trace, warn};
+use uuid::Uuid;
 use xml::{EmitterConfig, writer::XmlEvent};
 
 use crate::{
     DownloadProgress, FlickSync, LockedFile, Result, Server,
-    config::OutputStyle,
-    server::Progress,
+    config::{
+        Artwork, OutputLayout, OutputStyle, SubtitlePreferences, preferred_audio_stream,
+        render_template,
+    },
+    integrity::{self, Corrupt, FINGERPRINT_LENGTH},
+    server::{DownloadResult, Progress},
     state::{
-        CollectionState, DownloadState, LibraryState, LibraryType, PlaybackState, PlaylistState,
-        RelatedFileState, SeasonState, ServerState, ShowState, VideoDetail, VideoPartState,
-        VideoState,
+        CollectionState, DownloadState, ItemDetails, LibraryState, LibraryType, MetadataPlayback,
+        PlaybackState, PlaylistState, RelatedFileState, SeasonState, ServerState, ShowState,
+        SubtitleState, SubtitlesChecked, VideoDetail, VideoPartState, VideoState,
     },
-    sync::{OpReadGuard, OpWriteGuard, Timeout},
-    util::{AsyncWriteAdapter, safe},
+    sync::{OpReadGuard, OpWriteGuard, OutsideDownloadWindow, Throttle, Timeout},
+    transcode::{BurnedSubtitle, LocalStreams, MediaTags, PercentProgress, transcode},
+    util::{AsyncWriteAdapter, encode_param, safe},
 };
 
 type EventWriter = xml::writer::EventWriter<std::fs::File>;
 
 const METADATA_DIR: &str = ".metadata";
 
+fn write_element(writer: &mut EventWriter, name: &str, value: &str) -> Result {
+    writer.write(XmlEvent::start_element(name))?;
+    writer.write(XmlEvent::characters(value))?;
+    writer.write(XmlEvent::end_element())?;
+
+    Ok(())
+}
+
+/// Writes the Kodi NFO elements shared by shows, episodes and movies. The
+/// first available of the preferred providers is marked as the default ID.
+fn write_details(
+    writer: &mut EventWriter,
+    details: &ItemDetails,
+    preferred_ids: &[&str],
+) -> Result {
+    if let Some(ref plot) = details.plot {
+        write_element(writer, "plot", plot)?;
+    }
+
+    if let Some(ref tagline) = details.tagline {
+        write_element(writer, "tagline", tagline)?;
+    }
+
+    if let Some(ref content_rating) = details.content_rating {
+        write_element(writer, "mpaa", content_rating)?;
+    }
+
+    if let Some(ref studio) = details.studio {
+        write_element(writer, "studio", studio)?;
+    }
+
+    for genre in &details.genres {
+        write_element(writer, "genre", genre)?;
+    }
+
+    let default_id = preferred_ids
+        .iter()
+        .find(|provider| details.unique_ids.contains_key(**provider));
+    let mut unique_ids: Vec<(&String, &String)> = details.unique_ids.iter().collect();
+    unique_ids.sort();
+
+    for (provider, id) in unique_ids {
+        let is_default = default_id.is_some_and(|p| *p == provider.as_str());
+
+        writer.write(
+            XmlEvent::start_element("uniqueid")
+                .attr("type", provider)
+                .attr("default", if is_default { "true" } else { "false" }),
+        )?;
+        writer.write(XmlEvent::characters(id))?;
+        writer.write(XmlEvent::end_element())?;
+    }
+
+    for director in &details.directors {
+        write_element(writer, "director", director)?;
+    }
+
+    for credit in &details.writers {
+        write_element(writer, "credits", credit)?;
+    }
+
+    for (order, actor) in details.cast.iter().enumerate() {
+        writer.write(XmlEvent::start_element("actor"))?;
+        write_element(writer, "name", &actor.name)?;
+        if let Some(ref role) = actor.role {
+            write_element(writer, "role", role)?;
+        }
+        write_element(writer, "order", &order.to_string())?;
+        writer.write(XmlEvent::end_element())?;
+    }
+
+    Ok(())
+}
+
+/// Writes the runtime and watched state of a video. Returns the playback
+/// details that were written.
+fn write_playback(writer: &mut EventWriter, state: &VideoState) -> Result<MetadataPlayback> {
+    let runtime: u64 = state.parts.iter().map(|part| part.duration).sum();
+    if runtime > 0 {
+        write_element(writer, "runtime", &(runtime / 60000).to_string())?;
+    }
+
+    let playback = state.metadata_playback();
+
+    let played = playback.playback_state == PlaybackState::Played;
+    write_element(writer, "playcount", if played { "1" } else { "0" })?;
+    write_element(writer, "watched", if played { "true" } else { "false" })?;
+
+    if let PlaybackState::InProgress { position } = playback.playback_state {
+        writer.write(XmlEvent::start_element("resume"))?;
+        write_element(
+            writer,
+            "position",
+            &format!("{:.3}", position as f64 / 1000.0),
+        )?;
+        write_element(writer, "total", &format!("{:.3}", runtime as f64 / 1000.0))?;
+        writer.write(XmlEvent::end_element())?;
+    }
+
+    if let Some(last_played) = playback.last_played {
+        write_element(writer, "lastplayed", &last_played.to_string())?;
+    }
+
+    Ok(playback)
+}
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum FileType {
     Video,
     Thumbnail,
+    Fanart,
     Metadata,
     Playlist,
 }
@@ -145,76 +265,78 @@ macro_rules! wrapper_builders {
     };
 }
 
-macro_rules! thumbnail_methods {
-    () => {
-        pub async fn thumbnail(&self) -> result::Result<Option<LockedFile>, Timeout> {
-            let guard = self.try_lock_read().await?;
-
-            let thumbnail_state = self.with_state(|s| s.thumbnail.clone()).await;
-
-            Ok(thumbnail_state.file(guard, &self.server.inner.path))
-        }
-
+macro_rules! artwork_method {
+    ($meth:ident, $field:ident, $file_type:expr, $artwork:expr) => {
         #[instrument(level = "trace")]
-        pub(crate) async fn update_thumbnail(&self, rebuild: bool) -> Result {
+        pub(crate) async fn $meth(&self, rebuild: bool) -> Result {
             let Ok(guard) = self.try_lock_write().await else {
                 return Ok(());
             };
 
-            let (mut thumbnail, last_updated) = self
-                .with_state(|s| (s.thumbnail.clone(), s.last_updated))
+            let (mut artwork, last_updated) = self
+                .with_state(|s| (s.$field.clone(), s.last_updated))
                 .await;
 
-            let Some(thumbnail_path) = self.file_path(FileType::Thumbnail, "jpg").await else {
-                thumbnail.delete(&guard, &self.server.inner.path).await;
-                return self.update_state(|s| s.thumbnail = thumbnail.clone()).await;
+            let Some(artwork_path) = self.file_path($file_type, "jpg").await else {
+                artwork.delete(&guard, &self.server.inner.path).await;
+                return self.update_state(|s| s.$field = artwork.clone()).await;
             };
 
             let must_download = if rebuild {
-                thumbnail.delete(&guard, &self.server.inner.path).await;
+                artwork.delete(&guard, &self.server.inner.path).await;
                 true
             } else {
-                thumbnail
-                    .verify(&guard, &self.server.inner.path, &thumbnail_path)
+                artwork
+                    .verify(&guard, &self.server.inner.path, &artwork_path)
                     .await;
-                thumbnail.needs_update(last_updated)
+                artwork.needs_update(last_updated)
             };
 
-            self.update_state(|s| s.thumbnail = thumbnail.clone())
-                .await?;
+            self.update_state(|s| s.$field = artwork.clone()).await?;
 
             if must_download {
                 let server = self.server.connect().await?;
                 let item = server.item_by_id(&self.id).await?;
-                debug!("Updating thumbnail for {}", item.title());
+                debug!("Updating {} for {}", stringify!($field), item.title());
 
-                let image = if let Some(ref thumb) = item.metadata().thumb {
-                    thumb.clone()
-                } else if let Some(ref composite) = item.metadata().composite {
-                    composite.clone()
-                } else {
-                    warn!("No thumbnail found for {}", item.title());
+                let metadata = item.metadata();
+                let image = match $artwork {
+                    Artwork::Fanart => metadata.art.clone(),
+                    _ => metadata
+                        .thumb
+                        .clone()
+                        .or_else(|| metadata.composite.clone()),
+                };
+
+                let Some(image) = image else {
+                    warn!("No {} found for {}", stringify!($field), item.title());
                     return Ok(());
                 };
 
-                let target = self.server.inner.path.join(&thumbnail_path);
+                let target = self.server.inner.path.join(&artwork_path);
 
                 if let Some(parent) = target.parent() {
                     create_dir_all(parent).await?;
                 }
 
+                let (width, height) = self.server.inner.artwork_size($artwork).await;
+
                 let file = AsyncWriteAdapter::new(File::create(&target).await?);
                 server
-                    .transcode_artwork(&image, 320, 320, Default::default(), file)
+                    .transcode_artwork(&image, width, height, Default::default(), file)
                     .await?;
 
                 let state = RelatedFileState::Stored {
-                    path: thumbnail_path,
+                    path: artwork_path,
                     updated: OffsetDateTime::now_utc(),
                 };
 
-                self.update_state(|s| s.thumbnail = state).await?;
-                trace!("Thumbnail for {} successfully updated", item.title());
+                self.update_state(|s| s.$field = state).await?;
+                trace!(
+                    "{} for {} successfully updated",
+                    stringify!($field),
+                    item.title()
+                );
             }
 
             Ok(())
@@ -222,16 +344,43 @@ macro_rules! thumbnail_methods {
     };
 }
 
+macro_rules! thumbnail_methods {
+    () => {
+        thumbnail_methods!(Artwork::Thumbnail);
+    };
+    ($artwork:expr) => {
+        pub async fn thumbnail(&self) -> result::Result<Option<LockedFile>, Timeout> {
+            let guard = self.try_lock_read().await?;
+
+            let thumbnail_state = self.with_state(|s| s.thumbnail.clone()).await;
+
+            Ok(thumbnail_state.file(guard, &self.server.inner.path))
+        }
+
+        artwork_method!(update_thumbnail, thumbnail, FileType::Thumbnail, $artwork);
+    };
+}
+
+/// Background artwork, only stored in the standardized output style.
+macro_rules! fanart_methods {
+    () => {
+        artwork_method!(update_fanart, fanart, FileType::Fanart, Artwork::Fanart);
+    };
+}
+
 macro_rules! metadata_methods {
     () => {
+        metadata_methods!(|_| false);
+    };
+    ($changed:expr) => {
         #[instrument(level = "trace")]
         pub(crate) async fn update_metadata(&self, rebuild: bool) -> Result {
             let Ok(guard) = self.try_lock_write().await else {
                 return Ok(());
             };
 
-            let (mut metadata, last_updated) = self
-                .with_state(|s| (s.metadata.clone(), s.last_updated))
+            let (mut metadata, last_updated, changed) = self
+                .with_state(|s| (s.metadata.clone(), s.last_updated, ($changed)(s)))
                 .await;
 
             let Some(metadata_path) = self.file_path(FileType::Metadata, "nfo").await else {
@@ -246,7 +395,7 @@ macro_rules! metadata_methods {
                 metadata
                     .verify(&guard, &self.server.inner.path, &metadata_path)
                     .await;
-                metadata.needs_update(last_updated)
+                changed || metadata.needs_update(last_updated)
             };
 
             self.update_state(|s| s.metadata = metadata.clone()).await?;
@@ -318,16 +467,19 @@ async fn write_playlist(root: &Path, playlist_path: &Path, videos: Vec<Video>) -
     let mut writer = BufWriter::new(output);
 
     for video in videos {
-        let download = video.download_state().await;
+        if !video.is_downloaded().await {
+            continue;
+        }
 
-        if let Some(video_path) = download.path()
-            && !download.needs_download()
-            && let Some(relative) = diff_paths(root.join(video_path), parent)
-        {
-            writer
-                .write_all(relative.as_os_str().as_encoded_bytes())
-                .await?;
-            writer.write_all(b"\n").await?;
+        for part in video.parts().await {
+            if let Some(video_path) = part.download_state().await.path()
+                && let Some(relative) = diff_paths(root.join(video_path), parent)
+            {
+                writer
+                    .write_all(relative.as_os_str().as_encoded_bytes())
+                    .await?;
+                writer.write_all(b"\n").await?;
+            }
         }
     }
 
@@ -353,7 +505,8 @@ state_wrapper!(Show, ShowState, shows);
 wrapper_builders!(Show, ShowState);
 
 impl Show {
-    thumbnail_methods!();
+    thumbnail_methods!(Artwork::Poster);
+    fanart_methods!();
     metadata_methods!();
     parent!(library, ShowLibrary, library);
 
@@ -379,9 +532,14 @@ impl Show {
         self.with_state(|state| {
             writer.write(XmlEvent::start_element("tvshow"))?;
 
-            writer.write(XmlEvent::start_element("title"))?;
-            writer.write(XmlEvent::characters(&state.title))?;
-            writer.write(XmlEvent::end_element())?;
+            write_element(writer, "title", &state.title)?;
+            write_element(writer, "year", &state.year.to_string())?;
+
+            if let Some(air_date) = state.air_date {
+                write_element(writer, "premiered", &air_date.to_string())?;
+            }
+
+            write_details(writer, &state.details, &["tvdb", "tmdb", "imdb"])?;
 
             writer.write(XmlEvent::end_element())?;
 
@@ -391,7 +549,18 @@ impl Show {
     }
 
     pub(crate) async fn file_path(&self, file_type: FileType, extension: &str) -> Option<PathBuf> {
-        let output_style = self.server.inner.output_style().await;
+        let layout = self.server.inner.output_layout().await;
+        self.layout_path(&layout, file_type, extension).await
+    }
+
+    /// The path for a file of this item when stored with the given layout.
+    pub(crate) async fn layout_path(
+        &self,
+        layout: &OutputLayout,
+        file_type: FileType,
+        extension: &str,
+    ) -> Option<PathBuf> {
+        let output_style = layout.style;
 
         self.with_server_state(|ss| {
             let state = ss.shows.get(&self.id).unwrap();
@@ -402,11 +571,18 @@ impl Show {
                 }
                 FileType::Thumbnail => {
                     if output_style == OutputStyle::Standardized {
-                        format!("dvdcover.{extension}")
+                        format!("poster.{extension}")
                     } else {
                         format!("{}.{extension}", &self.id)
                     }
                 }
+                FileType::Fanart => {
+                    if output_style != OutputStyle::Standardized {
+                        return None;
+                    }
+
+                    format!("fanart.{extension}")
+                }
                 FileType::Metadata => {
                     if output_style != OutputStyle::Standardized {
                         return None;
@@ -449,6 +625,7 @@ state_wrapper!(Season, SeasonState, seasons);
 wrapper_builders!(Season, SeasonState);
 
 impl Season {
+    thumbnail_methods!(Artwork::Poster);
     parent!(show, Show, show);
 
     pub async fn index(&self) -> usize {
@@ -492,6 +669,62 @@ impl Season {
         })
         .await
     }
+
+    pub(crate) async fn file_path(&self, file_type: FileType, extension: &str) -> Option<PathBuf> {
+        let layout = self.server.inner.output_layout().await;
+        self.layout_path(&layout, file_type, extension).await
+    }
+
+    /// The path for a file of this item when stored with the given layout.
+    pub(crate) async fn layout_path(
+        &self,
+        layout: &OutputLayout,
+        file_type: FileType,
+        extension: &str,
+    ) -> Option<PathBuf> {
+        let output_style = layout.style;
+
+        self.with_server_state(|ss| {
+            let state = ss.seasons.get(&self.id).unwrap();
+            let show = ss.shows.get(&state.show).unwrap();
+
+            if !matches!(file_type, FileType::Thumbnail) {
+                return None;
+            }
+
+            Some(if output_style == OutputStyle::Standardized {
+                let library_title = &ss.libraries.get(&show.library).unwrap().title;
+
+                let name = if state.index == 0 {
+                    format!("season-specials-poster.{extension}")
+                } else {
+                    format!("season{:02}-poster.{extension}", state.index)
+                };
+
+                PathBuf::from(safe(&self.server.id))
+                    .join(safe(library_title))
+                    .join(safe(format!("{} ({})", show.title, show.year)))
+                    .join(safe(name))
+            } else {
+                PathBuf::from(safe(&self.server.id))
+                    .join(METADATA_DIR)
+                    .join(safe(format!("{}.{extension}", self.id)))
+            })
+        })
+        .await
+    }
+}
+
+/// Reports a failed transfer. Transfers stopped by a download window closing
+/// are not failures and are passed back to the caller instead.
+fn transfer_failed<P: Progress>(progress: P, error: anyhow::Error) -> Result<bool> {
+    if OutsideDownloadWindow::is_cause(&error) {
+        return Err(error);
+    }
+
+    warn!(error=?error);
+    progress.failed(error);
+    Ok(false)
 }
 
 #[pin_project]
@@ -501,6 +734,28 @@ struct WriterProgress<'a, W, P> {
     writer: W,
     progress: &'a mut P,
     access_permit: &'a mut Option<OwnedSemaphorePermit>,
+    throttle: &'a Throttle,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W, P> WriterProgress<'_, W, P> {
+    /// Waits until the throttle allows more data to be written.
+    fn poll_throttle(
+        delay: &mut Option<Pin<Box<Sleep>>>,
+        throttle: &Throttle,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), futures::io::Error>> {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+
+        if throttle.wait_time().is_some() {
+            return Poll::Ready(Err(futures::io::Error::other(OutsideDownloadWindow)));
+        }
+
+        Poll::Ready(Ok(()))
+    }
 }
 
 impl<W, P> AsyncWrite for WriterProgress<'_, W, P>
@@ -514,6 +769,8 @@ where
         buf: &[u8],
     ) -> Poll<result::Result<usize, futures::io::Error>> {
         let this = self.project();
+        ready!(Self::poll_throttle(this.delay, this.throttle, cx))?;
+
         let result = this.writer.poll_write(cx, buf);
 
         if let Poll::Ready(Ok(count)) = result {
@@ -522,6 +779,10 @@ where
                 this.access_permit.take();
             }
             this.progress.progress(*this.offset);
+
+            if let Some(deadline) = this.throttle.consume(count as u64) {
+                *this.delay = Some(Box::pin(sleep_until(deadline)));
+            }
         }
 
         result
@@ -533,12 +794,18 @@ where
         bufs: &[IoSlice<'_>],
     ) -> Poll<result::Result<usize, futures::io::Error>> {
         let this = self.project();
+        ready!(Self::poll_throttle(this.delay, this.throttle, cx))?;
+
         let result = this.writer.poll_write_vectored(cx, bufs);
 
         if let Poll::Ready(Ok(count)) = result {
             this.access_permit.take();
             *this.offset += count as u64;
             this.progress.progress(*this.offset);
+
+            if let Some(deadline) = this.throttle.consume(count as u64) {
+                *this.delay = Some(Box::pin(sleep_until(deadline)));
+            }
         }
 
         result
@@ -567,6 +834,7 @@ pub enum TransferState {
     Transcoding,
     Downloading,
     Downloaded,
+    Expired,
 }
 
 #[derive(Clone)]
@@ -607,6 +875,10 @@ impl VideoPart {
         self.index
     }
 
+    pub fn server(&self) -> Server {
+        self.server.clone()
+    }
+
     async fn with_video_state<F, R>(&self, cb: F) -> R
     where
         F: Send + FnOnce(&VideoState) -> R,
@@ -628,102 +900,1032 @@ impl VideoPart {
     pub(crate) async fn remote_size(&self) -> u64 {
         self.with_state(|vps| vps.size).await
     }
-}
 
-#[derive(Clone, Copy, Default)]
-pub struct VideoStats {
-    pub local_videos: u32,
-    pub remote_videos: u32,
-    pub local_bytes: u64,
-    pub remote_bytes: u64,
-    pub local_duration: Duration,
-    pub remote_duration: Duration,
-}
+    async fn update_state<F>(&self, cb: F) -> Result
+    where
+        F: Send + FnOnce(&mut VideoPartState),
+    {
+        let mut state = self.server.inner.state.write().await;
+        let server_state = state.servers.get_mut(&self.server.id).unwrap();
+        let video_state = server_state.videos.get_mut(&self.id).unwrap();
+        cb(video_state.parts.get_mut(self.index).unwrap());
+        self.server.inner.persist_state(&state).await
+    }
 
-impl Add for VideoStats {
-    type Output = VideoStats;
+    pub async fn file(&self) -> result::Result<Option<LockedFile>, Timeout> {
+        let guard = self.server.try_lock_read_key(&self.id).await?;
 
-    fn add(self, rhs: VideoStats) -> VideoStats {
-        Self {
-            local_videos: self.local_videos + rhs.local_videos,
-            remote_videos: self.remote_videos + rhs.remote_videos,
-            local_bytes: self.local_bytes + rhs.local_bytes,
-            remote_bytes: self.remote_bytes + rhs.remote_bytes,
-            local_duration: self.local_duration + rhs.local_duration,
-            remote_duration: self.remote_duration + rhs.remote_duration,
-        }
+        Ok(self
+            .download_state()
+            .await
+            .file(guard, &self.server.inner.path)
+            .await)
     }
-}
 
-impl AddAssign for VideoStats {
-    fn add_assign(&mut self, rhs: VideoStats) {
-        self.local_videos += rhs.local_videos;
-        self.remote_videos += rhs.remote_videos;
-        self.local_bytes += rhs.local_bytes;
-        self.remote_bytes += rhs.remote_bytes;
-        self.local_duration += rhs.local_duration;
-        self.remote_duration += rhs.remote_duration;
+    pub async fn is_downloaded(&self) -> bool {
+        !self.download_state().await.needs_download()
     }
-}
-
-impl VideoStats {
-    async fn from(video: &Video) -> Self {
-        let mut stats = VideoStats::default();
-
-        let state = video.download_state().await;
-        if !state.needs_download() {
-            stats.local_videos += 1;
-        }
-
-        for part in video.parts().await {
-            stats.remote_videos += 1;
-
-            let part_duration = part.duration().await;
-            stats.remote_duration += part_duration;
 
-            if !state.needs_download() {
-                stats.local_duration += part_duration;
-            }
+    /// The position in the video at which this part starts.
+    pub async fn offset(&self) -> Duration {
+        self.with_video_state(|vs| {
+            vs.parts
+                .iter()
+                .take(self.index)
+                .map(|part| Duration::from_millis(part.duration))
+                .sum()
+        })
+        .await
+    }
 
-            let mut remote_bytes = part.remote_size().await;
+    /// The path for this part's video file. Videos with multiple parts have
+    /// the part number appended to the name.
+    pub(crate) async fn file_path(&self, extension: &str) -> Option<PathBuf> {
+        let layout = self.server.inner.output_layout().await;
+        self.layout_path(&layout, extension).await
+    }
 
-            if let Some(path) = state.path() {
-                let path = part.server.inner.path.join(path);
-                if let Ok(file_stats) = metadata(path).await {
-                    stats.local_bytes += file_stats.len();
-                    remote_bytes = file_stats.len();
-                }
-            }
+    /// The path for this part's video file when stored with the given layout.
+    pub(crate) async fn layout_path(
+        &self,
+        layout: &OutputLayout,
+        extension: &str,
+    ) -> Option<PathBuf> {
+        let path = self
+            .video()
+            .await
+            .layout_path(layout, FileType::Video, extension)
+            .await?;
 
-            stats.remote_bytes += remote_bytes;
+        if self.with_video_state(|vs| vs.parts.len()).await < 2 {
+            return Some(path);
         }
 
-        stats
+        let stem = path.file_stem()?.to_str()?;
+        Some(path.with_file_name(format!("{stem} - pt{}.{extension}", self.index + 1)))
     }
-}
-
-#[derive(Clone)]
-pub struct Episode {
-    server: Server,
-    id: String,
-}
 
-impl fmt::Debug for Episode {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.pad(&format!("Episode({})", self.id))
+    pub(crate) async fn download_state(&self) -> DownloadState {
+        self.with_state(|part_state| part_state.download.clone())
+            .await
     }
-}
 
-impl PartialEq for Episode {
-    fn eq(&self, other: &Self) -> bool {
-        self.server.id == other.server.id && self.id == other.id
+    async fn verify_download(
+        &self,
+        guard: &OpWriteGuard,
+        plex_server: &PlexServer,
+        allow_video_deletion: bool,
+    ) -> Result {
+        let mut download_state = self.download_state().await;
+
+        download_state
+            .verify(
+                guard,
+                plex_server,
+                self,
+                &self.server.inner.path,
+                allow_video_deletion,
+            )
+            .await;
+
+        self.update_state(|state| state.download = download_state)
+            .await
     }
-}
 
-impl Eq for Episode {}
+    async fn recover_download(&self) -> Result {
+        let title = self.with_video_state(|vs| vs.title.clone()).await;
+        let expected_size = self.remote_size().await;
 
-impl Hash for Episode {
-    fn hash<H: Hasher>(&self, state: &mut H) {
+        for container in [
+            ContainerFormat::Avi,
+            ContainerFormat::Mpeg,
+            ContainerFormat::MpegTs,
+            ContainerFormat::M4v,
+            ContainerFormat::Mp4,
+            ContainerFormat::Mkv,
+        ] {
+            let path = self.file_path(&container.to_string()).await.unwrap();
+            let target = self.server.inner.path.join(&path);
+
+            if let Ok(stats) = metadata(target).await
+                && stats.is_file()
+            {
+                info!(path=?path.display(), part = self.index, "Recovered download for {title}");
+
+                let download_state = if stats.size() == expected_size {
+                    DownloadState::Downloaded { path }
+                } else {
+                    DownloadState::Transcoded { path }
+                };
+
+                return self
+                    .update_state(|state| state.download = download_state)
+                    .await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Opens the target file for appending, returning the length of any
+    /// previously downloaded data.
+    async fn open_download(&self, path: &Path) -> Result<(u64, File)> {
+        let target = self.server.inner.path.join(path);
+        let offset = match metadata(&target).await {
+            Ok(stats) => stats.len(),
+            Err(e) => {
+                if e.kind() == ErrorKind::NotFound {
+                    0
+                } else {
+                    return Err(e.into());
+                }
+            }
+        };
+
+        if self.server.inner.throttle.wait_time().is_some() {
+            return Err(OutsideDownloadWindow.into());
+        }
+
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent).await?;
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&target)
+            .await?;
+
+        Ok((offset, file))
+    }
+
+    /// Checks that a partially downloaded file matches the recorded length and
+    /// fingerprint of the content, deleting it so the download starts again if
+    /// not. When nothing was recorded only the start of the file is checked.
+    /// The content's current length and fingerprint are then recorded.
+    async fn prepare_resume(
+        &self,
+        queue_id: u32,
+        path: &Path,
+        content_length: Option<u64>,
+        prefix: &[u8],
+    ) -> Result {
+        let fingerprint = integrity::fingerprint(prefix);
+
+        let recorded = match self.download_state().await {
+            DownloadState::Downloading {
+                content_length,
+                fingerprint,
+                ..
+            } => Some((content_length, fingerprint)),
+            _ => None,
+        };
+
+        let target = self.server.inner.path.join(path);
+        let partial = match metadata(&target).await {
+            Ok(stats) => stats.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        if partial > 0 {
+            let local_fingerprint = integrity::file_fingerprint(&target, prefix.len()).await?;
+            let matches_recorded = match &recorded {
+                Some((recorded_length, Some(recorded_fingerprint))) => {
+                    *recorded_fingerprint == fingerprint && *recorded_length == content_length
+                }
+                // Downloads started before fingerprints were recorded can only
+                // be checked against the start of the partial file.
+                Some((_, None)) => true,
+                None => false,
+            };
+            let resumable = matches_recorded
+                && content_length.is_none_or(|len| partial <= len)
+                && local_fingerprint.as_ref() == Some(&fingerprint);
+
+            if !resumable {
+                warn!(path=?path, partial, "Partial download does not match the content, restarting");
+                remove_file(&target).await?;
+            } else if matches!(recorded, Some((_, Some(_)))) {
+                return Ok(());
+            }
+        }
+
+        self.update_state(|state| {
+            state.download = DownloadState::Downloading {
+                queue_id,
+                path: path.to_owned(),
+                content_length,
+                fingerprint: Some(fingerprint),
+            };
+        })
+        .await
+    }
+
+    #[instrument(level = "trace", skip(self, queue, guard, path, progress), fields(video=self.id(), part=self.index))]
+    async fn download_media<P: Progress>(
+        &self,
+        queue: &DownloadQueue,
+        queue_id: u32,
+        guard: &OpWriteGuard,
+        path: &Path,
+        progress: &mut P,
+    ) -> Result {
+        let item = queue.item(queue_id).await?;
+        let content_length = item.content_length().await.ok().flatten();
+
+        // Fingerprint the start of the content to check that any partial
+        // download came from the same transcode.
+        let fingerprint_length =
+            content_length.map_or(FINGERPRINT_LENGTH, |len| len.min(FINGERPRINT_LENGTH));
+        let mut prefix = Cursor::new(Vec::new());
+        if fingerprint_length > 0 {
+            item.download(&mut prefix, 0..fingerprint_length).await?;
+        }
+
+        self.prepare_resume(queue_id, path, content_length, &prefix.into_inner())
+            .await?;
+
+        let (offset, file) = self.open_download(path).await?;
+
+        if let Some(len) = content_length {
+            progress.length(len);
+        }
+
+        let writer = WriterProgress {
+            offset,
+            writer: AsyncWriteAdapter::new(BufWriter::new(file)),
+            progress,
+            access_permit: &mut None,
+            throttle: &self.server.inner.throttle,
+            delay: None,
+        };
+        info!(path=?path, offset, "Downloading source file");
+
+        item.download(writer, offset..).await?;
+
+        info!(path=?path, "Download complete");
+
+        let new_state = if item.is_transcode() {
+            DownloadState::Transcoded {
+                path: path.to_owned(),
+            }
+        } else {
+            DownloadState::Downloaded {
+                path: path.to_owned(),
+            }
+        };
+
+        if let Err(e) = item.delete().await {
+            warn!(error=?e, "Failed to delete transcode session");
+        }
+
+        self.update_state(|state| {
+            state.download = new_state;
+        })
+        .await?;
+
+        self.finish_download(guard, path).await;
+
+        Ok(())
+    }
+
+    /// Tags a freshly downloaded file and records its checksum.
+    async fn finish_download(&self, guard: &OpWriteGuard, path: &Path) {
+        if let Some(ffmpeg) = self.server.inner.ffmpeg().await
+            && let Err(e) = self.retag(guard, &ffmpeg).await
+        {
+            warn!(path=?path, error=%e, "Failed to strip metadata");
+        }
+
+        if let Err(e) = self.update_checksum().await {
+            warn!(path=?path, error=%e, "Failed to record checksum");
+        }
+    }
+
+    /// Replaces the metadata of the downloaded file with tags describing the
+    /// video.
+    async fn retag(&self, guard: &OpWriteGuard, ffmpeg: &Path) -> Result {
+        let tags = self.video().await.media_tags().await;
+
+        self.download_state()
+            .await
+            .strip_metadata(guard, &self.server.inner.path, ffmpeg, &tags)
+            .await
+    }
+
+    /// Retags an existing download if ffmpeg is configured, recording the new
+    /// checksum. A file that no longer matches its recorded checksum is left
+    /// alone so that the integrity check can replace it.
+    pub(crate) async fn strip_metadata(&self, guard: &OpWriteGuard) -> Result {
+        let Some(ffmpeg) = self.server.inner.ffmpeg().await else {
+            return Ok(());
+        };
+
+        let download_state = self.download_state().await;
+        let Some(path) = download_state
+            .path()
+            .filter(|_| !download_state.needs_download())
+        else {
+            return Ok(());
+        };
+
+        let expected = self.with_state(|state| state.checksum.clone()).await;
+        if let Some(expected) = expected
+            && integrity::checksum(&self.server.inner.path.join(&path)).await? != expected
+        {
+            warn!(path=?path, "Download no longer matches its checksum, not retagging");
+            return Ok(());
+        }
+
+        self.retag(guard, &ffmpeg).await?;
+        self.update_checksum().await
+    }
+
+    /// Records the checksum of the downloaded file if checksums are enabled,
+    /// otherwise clears any previously recorded checksum.
+    async fn update_checksum(&self) -> Result {
+        let download_state = self.download_state().await;
+
+        let mut checksum = None;
+        if self.server.inner.checksums().await
+            && !download_state.needs_download()
+            && let Some(path) = download_state.path()
+        {
+            checksum = Some(integrity::checksum(&self.server.inner.path.join(path)).await?);
+        }
+
+        self.update_state(|state| state.checksum = checksum).await
+    }
+
+    /// Checks that the downloaded file is intact, deleting it so that it is
+    /// downloaded again if not. Returns false if the file was corrupt.
+    pub(crate) async fn verify_integrity(
+        &self,
+        #[expect(unused)] guard: &OpWriteGuard,
+    ) -> Result<bool> {
+        let download_state = self.download_state().await;
+        if download_state.needs_download() {
+            return Ok(true);
+        }
+
+        let Some(path) = download_state.path() else {
+            return Ok(true);
+        };
+
+        let file = self.server.inner.path.join(&path);
+        let (duration, checksum) = self
+            .with_state(|state| {
+                (
+                    Duration::from_millis(state.duration),
+                    state.checksum.clone(),
+                )
+            })
+            .await;
+        let ffmpeg = self.server.inner.ffmpeg().await;
+
+        match integrity::check(&file, ffmpeg.as_deref(), duration, checksum.as_deref()).await {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                let corrupt = e.downcast::<Corrupt>()?;
+                warn!(path=?path, reason=%corrupt, "Download is corrupt");
+
+                if let Err(e) = remove_file(&file).await
+                    && e.kind() != ErrorKind::NotFound
+                {
+                    warn!(path=?path, error=?e, "Failed to remove corrupt download");
+                }
+
+                self.update_state(|state| {
+                    state.download = DownloadState::None;
+                    state.checksum = None;
+                })
+                .await?;
+
+                Ok(false)
+            }
+        }
+    }
+
+    /// Downloads the original media file to the given path.
+    async fn fetch_original<P: Progress>(
+        &self,
+        video: &library::Video,
+        path: &Path,
+        progress: &mut P,
+    ) -> Result {
+        let media = video.media();
+        let Some(part) = media
+            .first()
+            .and_then(|m| m.parts().into_iter().nth(self.index))
+        else {
+            bail!("Video part is no longer available");
+        };
+
+        let (offset, file) = self.open_download(path).await?;
+
+        if let Some(len) = part.metadata().size {
+            progress.length(len);
+        }
+
+        let writer = WriterProgress {
+            offset,
+            writer: AsyncWriteAdapter::new(BufWriter::new(file)),
+            progress,
+            access_permit: &mut None,
+            throttle: &self.server.inner.throttle,
+            delay: None,
+        };
+        info!(path=?path, offset, "Downloading original file");
+
+        part.download(writer, offset..).await?;
+
+        info!(path=?path, "Download complete");
+
+        Ok(())
+    }
+
+    /// Downloads the original media file without transcoding.
+    #[instrument(level = "trace", skip(self, video, guard, path, progress), fields(video=self.id(), part=self.index))]
+    async fn download_original<P: Progress>(
+        &self,
+        video: &library::Video,
+        guard: &OpWriteGuard,
+        path: &Path,
+        progress: &mut P,
+    ) -> Result {
+        self.fetch_original(video, path, progress).await?;
+
+        let new_state = DownloadState::Downloaded {
+            path: path.to_owned(),
+        };
+
+        self.update_state(|state| {
+            state.download = new_state;
+        })
+        .await?;
+
+        self.finish_download(guard, path).await;
+
+        Ok(())
+    }
+
+    /// Downloads the original media file and transcodes it with ffmpeg.
+    /// Returns false if either step failed.
+    #[instrument(level = "trace", skip_all, fields(video=self.id(), part=self.index))]
+    async fn transcode_locally<D: DownloadProgress>(
+        &self,
+        plex_server: &PlexServer,
+        plex_video: &mut Option<library::Video>,
+        guard: &OpWriteGuard,
+        download_progress: &D,
+    ) -> Result<bool> {
+        let Some(ffmpeg) = self.server.inner.ffmpeg().await else {
+            bail!("Local transcoding is not configured");
+        };
+
+        let video = self.video().await;
+
+        if plex_video.is_none() {
+            *plex_video = Some(video.plex_video(plex_server).await?);
+        }
+        let item = plex_video.as_ref().unwrap();
+
+        let source_container = item
+            .media()
+            .first()
+            .and_then(|m| m.metadata().container)
+            .unwrap_or(ContainerFormat::Mkv);
+        let container = video
+            .transcode_profile()
+            .await
+            .containers
+            .first()
+            .cloned()
+            .unwrap_or(ContainerFormat::Mp4);
+
+        let source = self
+            .file_path(&format!("original.{source_container}"))
+            .await
+            .unwrap();
+        let path = self.file_path(&container.to_string()).await.unwrap();
+
+        let root = &self.server.inner.path;
+
+        let streams = self.local_streams(item, root.join(&source)).await?;
+        let args = video.ffmpeg_args(&streams).await?;
+        let audio_stream = video.select_audio_stream(item, self.index).await;
+
+        {
+            let _permit = self
+                .server
+                .inner
+                .download_permits
+                .clone()
+                .acquire_owned()
+                .await
+                .unwrap();
+
+            let mut progress = download_progress.download_started().await;
+
+            if let Err(e) = self.fetch_original(item, &source, &mut progress).await {
+                return transfer_failed(progress, e);
+            }
+
+            progress.finished();
+        }
+
+        let _permit = self.server.inner.transcode_permits.acquire().await.unwrap();
+
+        info!(path=?path, "Transcoding locally");
+        let mut progress = PercentProgress::new(download_progress.transcode_started().await);
+
+        let result = transcode(
+            &ffmpeg,
+            &root.join(&source),
+            &root.join(&path),
+            &args,
+            self.duration().await,
+            &mut progress,
+        )
+        .await;
+
+        if let Err(e) = remove_file(root.join(&source)).await {
+            warn!(path=?source, error=?e, "Failed to remove original file");
+        }
+
+        if let Err(e) = result {
+            warn!(error=?e);
+            let _ = remove_file(root.join(&path)).await;
+            progress.failed(e);
+            return Ok(false);
+        }
+
+        progress.finished();
+        info!(path=?path, "Transcode complete");
+
+        let new_state = DownloadState::Transcoded { path: path.clone() };
+
+        self.update_state(|state| {
+            state.download = new_state;
+            state.audio_stream = audio_stream;
+        })
+        .await?;
+
+        self.finish_download(guard, &path).await;
+
+        Ok(true)
+    }
+
+    /// Chooses the streams of the original file to keep when transcoding
+    /// locally, following the same preferences as a Plex transcode.
+    async fn local_streams(&self, video: &library::Video, source: PathBuf) -> Result<LocalStreams> {
+        let media = video.media();
+        let Some(part) = media
+            .first()
+            .and_then(|m| m.parts().into_iter().nth(self.index))
+        else {
+            bail!("Video part is no longer available");
+        };
+
+        let streams = part.metadata().streams.clone().unwrap_or_default();
+        let wrapper = self.video().await;
+
+        let audio_streams: Vec<AudioStream> = streams
+            .iter()
+            .filter_map(|stream| match stream {
+                Stream::Audio(stream) => Some(stream.clone()),
+                _ => None,
+            })
+            .collect();
+
+        let audio = wrapper
+            .audio_languages()
+            .await
+            .and_then(|languages| preferred_audio_stream(&languages, &audio_streams)?.index);
+
+        let preferences = match wrapper.subtitle_preferences().await {
+            Some(preferences) if preferences.burn => preferences,
+            _ => {
+                return Ok(LocalStreams {
+                    audio,
+                    burned_subtitle: None,
+                });
+            }
+        };
+
+        let subtitle_streams: Vec<&SubtitleStream> = streams
+            .iter()
+            .filter_map(|stream| match stream {
+                Stream::Subtitle(stream) => Some(stream),
+                _ => None,
+            })
+            .collect();
+
+        let Some(stream) = subtitle_streams.iter().find(|stream| {
+            let languages = [
+                stream.language_code.as_deref(),
+                stream.language_tag.as_deref(),
+            ];
+            preferences.matches(&languages, stream.forced.unwrap_or_default())
+        }) else {
+            debug!(video = self.id(), "No matching subtitles to burn");
+            return Ok(LocalStreams {
+                audio,
+                burned_subtitle: None,
+            });
+        };
+
+        let Some(index) = stream.index else {
+            bail!("Local transcoding cannot burn external subtitles");
+        };
+
+        let burned_subtitle = match stream.codec {
+            SubtitleCodec::Pgs | SubtitleCodec::DvdSubtitle | SubtitleCodec::DvbSubtitle => {
+                BurnedSubtitle::Image { index }
+            }
+            _ => BurnedSubtitle::Text {
+                source,
+                position: subtitle_streams
+                    .iter()
+                    .filter(|stream| stream.index.is_some_and(|i| i < index))
+                    .count(),
+            },
+        };
+
+        Ok(LocalStreams {
+            audio,
+            burned_subtitle: Some(burned_subtitle),
+        })
+    }
+
+    async fn queue_download(
+        &self,
+        plex_server: &PlexServer,
+        queue: &DownloadQueue,
+        video: &library::Video,
+    ) -> Result {
+        let media = video.media();
+        let Some(part) = media
+            .first()
+            .and_then(|m| m.parts().into_iter().nth(self.index))
+        else {
+            bail!("Video part is no longer available");
+        };
+
+        let wrapper = self.video().await;
+
+        let mut options = wrapper.transcode_profile().await;
+        let subtitle_stream = match wrapper.subtitle_preferences().await {
+            Some(preferences) if preferences.burn => {
+                wrapper.select_burned_subtitle(video, self.index, &preferences)
+            }
+            _ => None,
+        };
+        options.burn_subtitles = subtitle_stream.is_some();
+
+        let audio_stream = wrapper.select_audio_stream(video, self.index).await;
+
+        let queue_item = if audio_stream.is_some() || subtitle_stream.is_some() {
+            queue_transcode(
+                plex_server,
+                queue,
+                video,
+                self.index,
+                options,
+                audio_stream.as_deref(),
+                subtitle_stream.as_deref(),
+            )
+            .await?
+        } else {
+            part.queue_download(options, Some(queue)).await?
+        };
+
+        self.update_state(|state| {
+            state.download = DownloadState::Transcoding {
+                queue_id: queue_item.id(),
+            };
+            state.audio_stream = audio_stream;
+        })
+        .await
+    }
+
+    #[instrument(level = "trace", skip(self, queue, queue_id, download_progress), fields(video=self.id(), part=self.index))]
+    async fn wait_for_available<D: DownloadProgress>(
+        &self,
+        queue: &DownloadQueue,
+        queue_id: u32,
+        download_progress: &D,
+    ) -> Result<bool> {
+        let mut queue_item = queue.item(queue_id).await?;
+
+        loop {
+            match queue_item.status() {
+                QueueItemStatus::Available => break,
+                QueueItemStatus::Waiting | QueueItemStatus::Deciding => {
+                    sleep(Duration::from_millis(500)).await;
+                    queue_item.update().await?;
+                }
+                QueueItemStatus::Processing => {
+                    let mut progress = download_progress.transcode_started().await;
+
+                    loop {
+                        match queue_item.status() {
+                            QueueItemStatus::Waiting | QueueItemStatus::Deciding => {
+                                // This shouldn't happen.
+                                progress.failed(anyhow!(
+                                    "Transcode session unexpectedly went back to waiting/deciding"
+                                ));
+                                break;
+                            }
+                            QueueItemStatus::Available => {
+                                progress.finished();
+                                break;
+                            }
+                            QueueItemStatus::Processing => {
+                                let delay = if let Some(stats) = queue_item.stats() {
+                                    progress.progress(stats.progress as u64);
+                                    if let Some(remaining) = stats.remaining {
+                                        remaining.clamp(1, 5) as u64
+                                    } else {
+                                        5
+                                    }
+                                } else {
+                                    5
+                                };
+
+                                sleep(Duration::from_secs(delay)).await;
+                                queue_item.update().await?;
+                            }
+                            status @ (QueueItemStatus::Expired | QueueItemStatus::Error) => {
+                                warn!("Transcode failed or expired");
+                                progress.failed(anyhow!("Transcode failed or expired"));
+
+                                if let Err(e) = queue_item.delete().await {
+                                    warn!(error=?e, "Failed to delete transcode session");
+                                }
+
+                                self.update_state(|state| {
+                                    state.download = DownloadState::None;
+                                })
+                                .await?;
+
+                                return Ok(!matches!(status, QueueItemStatus::Error));
+                            }
+                        }
+                    }
+                }
+                status @ (QueueItemStatus::Expired | QueueItemStatus::Error) => {
+                    if let Err(e) = queue_item.delete().await {
+                        warn!(error=?e, "Failed to delete transcode session");
+                    }
+
+                    self.update_state(|state| {
+                        state.download = DownloadState::None;
+                    })
+                    .await?;
+
+                    if matches!(status, QueueItemStatus::Error)
+                        && self.server.inner.ffmpeg().await.is_some()
+                    {
+                        return Ok(false);
+                    }
+
+                    bail!("Transcode failed or expired");
+                }
+            }
+        }
+
+        let container = match queue_item.container().await {
+            Ok(c) => c,
+            Err(e) => {
+                let options = self.video().await.transcode_profile().await;
+                let container = options
+                    .containers
+                    .first()
+                    .cloned()
+                    .unwrap_or(ContainerFormat::Mp4);
+
+                warn!(
+                    error=?e,
+                    ?container,
+                    "No container specified by Plex. Guessing based on transcode profile"
+                );
+                container
+            }
+        };
+
+        let path = self.file_path(&container.to_string()).await.unwrap();
+
+        self.update_state(|state| {
+            state.download = DownloadState::Downloading {
+                queue_id,
+                path,
+                content_length: None,
+                fingerprint: None,
+            };
+        })
+        .await?;
+
+        Ok(true)
+    }
+
+    /// Runs this part's download through to completion. Returns false if the
+    /// download failed after it had been reported as started.
+    #[instrument(level = "trace", skip_all, fields(video=self.id(), part=self.index))]
+    async fn download<D: DownloadProgress>(
+        &self,
+        plex_server: &PlexServer,
+        queue: &DownloadQueue,
+        plex_video: &mut Option<library::Video>,
+        guard: &OpWriteGuard,
+        download_progress: &D,
+    ) -> Result<bool> {
+        if self.download_state().await == DownloadState::None {
+            let video = self.video().await;
+
+            if plex_video.is_none() {
+                *plex_video = Some(video.plex_video(plex_server).await?);
+            }
+            let item = plex_video.as_ref().unwrap();
+
+            if let Some(container) = video.direct_play_container(item).await {
+                let path = self.file_path(&container.to_string()).await.unwrap();
+
+                let _permit = self
+                    .server
+                    .inner
+                    .download_permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .unwrap();
+
+                let mut progress = download_progress.download_started().await;
+
+                if let Err(e) = self
+                    .download_original(item, guard, &path, &mut progress)
+                    .await
+                {
+                    return transfer_failed(progress, e);
+                }
+
+                progress.finished();
+                return Ok(true);
+            }
+
+            if let Err(e) = self.queue_download(plex_server, queue, item).await {
+                if self.server.inner.ffmpeg().await.is_none() {
+                    return Err(e);
+                }
+
+                warn!(error=?e, "Failed to queue transcode, transcoding locally");
+                return self
+                    .transcode_locally(plex_server, plex_video, guard, download_progress)
+                    .await;
+            }
+        }
+
+        loop {
+            match self.download_state().await {
+                DownloadState::None => return Ok(false),
+                DownloadState::Transcoding { queue_id } => {
+                    let transcoded = self
+                        .wait_for_available(queue, queue_id, download_progress)
+                        .await?;
+
+                    if !transcoded && self.server.inner.ffmpeg().await.is_some() {
+                        warn!("Plex failed to transcode, transcoding locally");
+                        return self
+                            .transcode_locally(plex_server, plex_video, guard, download_progress)
+                            .await;
+                    }
+                }
+                DownloadState::Downloading { queue_id, path, .. } => {
+                    let _permit = self
+                        .server
+                        .inner
+                        .download_permits
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .unwrap();
+
+                    let mut progress = download_progress.download_started().await;
+
+                    if let Err(e) = self
+                        .download_media(queue, queue_id, guard, &path, &mut progress)
+                        .await
+                    {
+                        return transfer_failed(progress, e);
+                    }
+
+                    progress.finished();
+                }
+                DownloadState::Downloaded { .. }
+                | DownloadState::Transcoded { .. }
+                | DownloadState::Expired => return Ok(true),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct VideoStats {
+    pub local_videos: u32,
+    pub remote_videos: u32,
+    pub local_bytes: u64,
+    pub remote_bytes: u64,
+    pub local_duration: Duration,
+    pub remote_duration: Duration,
+}
+
+impl Add for VideoStats {
+    type Output = VideoStats;
+
+    fn add(self, rhs: VideoStats) -> VideoStats {
+        Self {
+            local_videos: self.local_videos + rhs.local_videos,
+            remote_videos: self.remote_videos + rhs.remote_videos,
+            local_bytes: self.local_bytes + rhs.local_bytes,
+            remote_bytes: self.remote_bytes + rhs.remote_bytes,
+            local_duration: self.local_duration + rhs.local_duration,
+            remote_duration: self.remote_duration + rhs.remote_duration,
+        }
+    }
+}
+
+impl AddAssign for VideoStats {
+    fn add_assign(&mut self, rhs: VideoStats) {
+        self.local_videos += rhs.local_videos;
+        self.remote_videos += rhs.remote_videos;
+        self.local_bytes += rhs.local_bytes;
+        self.remote_bytes += rhs.remote_bytes;
+        self.local_duration += rhs.local_duration;
+        self.remote_duration += rhs.remote_duration;
+    }
+}
+
+impl VideoStats {
+    async fn from(video: &Video) -> Self {
+        let mut stats = VideoStats {
+            remote_videos: 1,
+            ..Default::default()
+        };
+
+        if video.is_downloaded().await {
+            stats.local_videos += 1;
+        }
+
+        for part in video.parts().await {
+            let state = part.download_state().await;
+
+            let part_duration = part.duration().await;
+            stats.remote_duration += part_duration;
+
+            if !state.needs_download() {
+                stats.local_duration += part_duration;
+            }
+
+            let mut remote_bytes = part.remote_size().await;
+
+            if let Some(path) = state.path() {
+                let path = part.server.inner.path.join(path);
+                if let Ok(file_stats) = metadata(path).await {
+                    stats.local_bytes += file_stats.len();
+                    remote_bytes = file_stats.len();
+                }
+            }
+
+            stats.remote_bytes += remote_bytes;
+        }
+
+        stats
+    }
+}
+
+#[derive(Clone)]
+pub struct Episode {
+    server: Server,
+    id: String,
+}
+
+impl fmt::Debug for Episode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&format!("Episode({})", self.id))
+    }
+}
+
+impl PartialEq for Episode {
+    fn eq(&self, other: &Self) -> bool {
+        self.server.id == other.server.id && self.id == other.id
+    }
+}
+
+impl Eq for Episode {}
+
+impl Hash for Episode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
         self.server.id.hash(state);
         self.id.hash(state);
     }
@@ -734,7 +1936,7 @@ wrapper_builders!(Episode, VideoState);
 
 impl Episode {
     thumbnail_methods!();
-    metadata_methods!();
+    metadata_methods!(VideoState::metadata_playback_changed);
     parent!(season, Season, episode_state().season);
 
     pub fn video(&self) -> Video {
@@ -749,12 +1951,14 @@ impl Episode {
         self.with_state(|vs| vs.air_date).await
     }
 
-    pub async fn playback_state(&self) -> PlaybackState {
-        self.with_state(|vs| vs.playback_state.clone()).await
+    /// The playback state for a user, or the main user if `None`.
+    pub async fn playback_state(&self, user: Option<&str>) -> PlaybackState {
+        self.with_state(|vs| vs.playback(user).playback_state.clone())
+            .await
     }
 
-    pub async fn last_played(&self) -> Option<OffsetDateTime> {
-        self.with_state(|vs| vs.last_viewed_at).await
+    pub async fn last_played(&self, user: Option<&str>) -> Option<OffsetDateTime> {
+        self.with_state(|vs| vs.playback(user).last_viewed_at).await
     }
 
     pub async fn next_episode(&self) -> Option<Episode> {
@@ -778,11 +1982,25 @@ impl Episode {
         }
     }
 
-    pub async fn set_playback_state(&self, state: PlaybackState) -> Result {
+    /// Records a change to the playback state for a user, or the main user if
+    /// `None`. Fails if the user is not configured for this video's server.
+    pub async fn set_playback_state(&self, user: Option<&str>, state: PlaybackState) -> Result {
+        if !self.server.has_user(user).await {
+            bail!(
+                "Unknown user {} for server {}",
+                user.unwrap_or_default(),
+                self.server.id
+            );
+        }
+
         self.update_state(|vs| {
-            vs.playback_state = state;
+            vs.playback_mut(user)
+                .record_playback(state, OffsetDateTime::now_utc());
         })
-        .await
+        .await?;
+
+        self.server.inner.playback_changed.notify_one();
+        Ok(())
     }
 
     pub async fn duration(&self) -> Duration {
@@ -799,32 +2017,34 @@ impl Episode {
         let season = self.season().await.with_state(|ss| ss.index).await;
         let show = self.show().await.with_state(|ss| ss.title.clone()).await;
 
-        self.with_state(|state| {
-            writer.write(XmlEvent::start_element("episodedetails"))?;
+        let playback = self
+            .with_state(|state| -> Result<MetadataPlayback> {
+                writer.write(XmlEvent::start_element("episodedetails"))?;
 
-            writer.write(XmlEvent::start_element("title"))?;
-            writer.write(XmlEvent::characters(&state.title))?;
-            writer.write(XmlEvent::end_element())?;
+                write_element(writer, "title", &state.title)?;
+                write_element(writer, "showtitle", &show)?;
+                write_element(writer, "season", &season.to_string())?;
 
-            writer.write(XmlEvent::start_element("showtitle"))?;
-            writer.write(XmlEvent::characters(&show))?;
-            writer.write(XmlEvent::end_element())?;
+                if let Some(index) = state.episode_state().index {
+                    write_element(writer, "episode", &index.to_string())?;
+                }
 
-            writer.write(XmlEvent::start_element("season"))?;
-            writer.write(XmlEvent::characters(&season.to_string()))?;
-            writer.write(XmlEvent::end_element())?;
+                if let Some(air_date) = state.air_date {
+                    write_element(writer, "aired", &air_date.to_string())?;
+                    write_element(writer, "premiered", &air_date.to_string())?;
+                }
+
+                write_details(writer, &state.details, &["tvdb", "tmdb", "imdb"])?;
+                let playback = write_playback(writer, state)?;
 
-            if let Some(index) = state.episode_state().index {
-                writer.write(XmlEvent::start_element("episode"))?;
-                writer.write(XmlEvent::characters(&index.to_string()))?;
                 writer.write(XmlEvent::end_element())?;
-            }
 
-            writer.write(XmlEvent::end_element())?;
+                Ok(playback)
+            })
+            .await?;
 
-            Ok(())
-        })
-        .await
+        self.update_state(|state| state.written_playback = Some(playback))
+            .await
     }
 
     pub async fn stats(&self) -> VideoStats {
@@ -859,7 +2079,19 @@ impl Episode {
     }
 
     pub(crate) async fn file_path(&self, file_type: FileType, extension: &str) -> Option<PathBuf> {
-        let output_style = self.server.inner.output_style().await;
+        let layout = self.server.inner.output_layout().await;
+        self.layout_path(&layout, file_type, extension).await
+    }
+
+    /// The path for a file of this item when stored with the given layout.
+    pub(crate) async fn layout_path(
+        &self,
+        layout: &OutputLayout,
+        file_type: FileType,
+        extension: &str,
+    ) -> Option<PathBuf> {
+        let output_style = layout.style;
+        let templates = &layout.templates;
 
         self.with_server_state(|ss| {
             let state = ss.videos.get(&self.id).unwrap();
@@ -867,6 +2099,36 @@ impl Episode {
             let season = ss.seasons.get(&ep_state.season).unwrap();
             let show = ss.shows.get(&season.show).unwrap();
 
+            if output_style == OutputStyle::Template {
+                let template = match file_type {
+                    FileType::Video => Some(templates.episode()),
+                    FileType::Thumbnail => templates.thumbnail.as_deref(),
+                    FileType::Metadata => templates.metadata.as_deref(),
+                    FileType::Fanart | FileType::Playlist => None,
+                };
+
+                if let Some(template) = template {
+                    let library_title = &ss.libraries.get(&show.library).unwrap().title;
+
+                    return Some(PathBuf::from(safe(&self.server.id)).join(render_template(
+                        template,
+                        &[
+                            ("library", library_title),
+                            ("show", &show.title),
+                            ("title", &state.title),
+                            ("year", &show.year.to_string()),
+                            ("season", &season.index.to_string()),
+                            (
+                                "episode",
+                                &ep_state.index.map(|i| i.to_string()).unwrap_or_default(),
+                            ),
+                            ("id", &self.id),
+                            ("ext", extension),
+                        ],
+                    )));
+                }
+            }
+
             let name = match file_type {
                 FileType::Playlist => return None,
                 FileType::Video => {
@@ -886,12 +2148,12 @@ impl Episode {
                     if output_style == OutputStyle::Standardized {
                         if let Some(index) = ep_state.index {
                             format!(
-                                "S{:02}E{:02} - {}.{extension}",
+                                "S{:02}E{:02} - {}-thumb.{extension}",
                                 season.index, index, state.title
                             )
                         } else {
                             format!(
-                                "S{:02} - {} - {}.{extension}",
+                                "S{:02} - {} - {}-thumb.{extension}",
                                 season.index, state.id, state.title
                             )
                         }
@@ -899,6 +2161,7 @@ impl Episode {
                         format!("{}.{extension}", self.id)
                     }
                 }
+                FileType::Fanart => return None,
                 FileType::Metadata => {
                     if output_style != OutputStyle::Standardized {
                         return None;
@@ -968,8 +2231,9 @@ state_wrapper!(Movie, VideoState, videos);
 wrapper_builders!(Movie, VideoState);
 
 impl Movie {
-    thumbnail_methods!();
-    metadata_methods!();
+    thumbnail_methods!(Artwork::Poster);
+    fanart_methods!();
+    metadata_methods!(VideoState::metadata_playback_changed);
     parent!(library, MovieLibrary, movie_state().library);
 
     pub fn video(&self) -> Video {
@@ -980,12 +2244,14 @@ impl Movie {
         self.with_state(|vs| vs.air_date).await
     }
 
-    pub async fn playback_state(&self) -> PlaybackState {
-        self.with_state(|vs| vs.playback_state.clone()).await
+    /// The playback state for a user, or the main user if `None`.
+    pub async fn playback_state(&self, user: Option<&str>) -> PlaybackState {
+        self.with_state(|vs| vs.playback(user).playback_state.clone())
+            .await
     }
 
-    pub async fn last_played(&self) -> Option<OffsetDateTime> {
-        self.with_state(|vs| vs.last_viewed_at).await
+    pub async fn last_played(&self, user: Option<&str>) -> Option<OffsetDateTime> {
+        self.with_state(|vs| vs.playback(user).last_viewed_at).await
     }
 
     pub async fn next_movie(&self) -> Option<Movie> {
@@ -1024,11 +2290,25 @@ impl Movie {
         None
     }
 
-    pub async fn set_playback_state(&self, state: PlaybackState) -> Result {
+    /// Records a change to the playback state for a user, or the main user if
+    /// `None`. Fails if the user is not configured for this video's server.
+    pub async fn set_playback_state(&self, user: Option<&str>, state: PlaybackState) -> Result {
+        if !self.server.has_user(user).await {
+            bail!(
+                "Unknown user {} for server {}",
+                user.unwrap_or_default(),
+                self.server.id
+            );
+        }
+
         self.update_state(|vs| {
-            vs.playback_state = state;
+            vs.playback_mut(user)
+                .record_playback(state, OffsetDateTime::now_utc());
         })
-        .await
+        .await?;
+
+        self.server.inner.playback_changed.notify_one();
+        Ok(())
     }
 
     pub async fn duration(&self) -> Duration {
@@ -1042,18 +2322,28 @@ impl Movie {
     }
 
     async fn write_metadata(&self, writer: &mut EventWriter) -> Result {
-        self.with_state(|state| {
-            writer.write(XmlEvent::start_element("movie"))?;
+        let playback = self
+            .with_state(|state| -> Result<MetadataPlayback> {
+                writer.write(XmlEvent::start_element("movie"))?;
 
-            writer.write(XmlEvent::start_element("title"))?;
-            writer.write(XmlEvent::characters(&state.title))?;
-            writer.write(XmlEvent::end_element())?;
+                write_element(writer, "title", &state.title)?;
+                write_element(writer, "year", &state.movie_state().year.to_string())?;
 
-            writer.write(XmlEvent::end_element())?;
+                if let Some(air_date) = state.air_date {
+                    write_element(writer, "premiered", &air_date.to_string())?;
+                }
 
-            Ok(())
-        })
-        .await
+                write_details(writer, &state.details, &["imdb", "tmdb"])?;
+                let playback = write_playback(writer, state)?;
+
+                writer.write(XmlEvent::end_element())?;
+
+                Ok(playback)
+            })
+            .await?;
+
+        self.update_state(|state| state.written_playback = Some(playback))
+            .await
     }
 
     pub async fn is_downloaded(&self) -> bool {
@@ -1080,12 +2370,48 @@ impl Movie {
     }
 
     pub(crate) async fn file_path(&self, file_type: FileType, extension: &str) -> Option<PathBuf> {
-        let output_style = self.server.inner.output_style().await;
+        let layout = self.server.inner.output_layout().await;
+        self.layout_path(&layout, file_type, extension).await
+    }
+
+    /// The path for a file of this item when stored with the given layout.
+    pub(crate) async fn layout_path(
+        &self,
+        layout: &OutputLayout,
+        file_type: FileType,
+        extension: &str,
+    ) -> Option<PathBuf> {
+        let output_style = layout.style;
+        let templates = &layout.templates;
 
         self.with_server_state(|ss| {
             let state = ss.videos.get(&self.id).unwrap();
             let m_state = state.movie_state();
 
+            if output_style == OutputStyle::Template {
+                let template = match file_type {
+                    FileType::Video => Some(templates.movie()),
+                    FileType::Thumbnail => templates.thumbnail.as_deref(),
+                    FileType::Metadata => templates.metadata.as_deref(),
+                    FileType::Fanart | FileType::Playlist => None,
+                };
+
+                if let Some(template) = template {
+                    let library_title = &ss.libraries.get(&m_state.library).unwrap().title;
+
+                    return Some(PathBuf::from(safe(&self.server.id)).join(render_template(
+                        template,
+                        &[
+                            ("library", library_title),
+                            ("title", &state.title),
+                            ("year", &m_state.year.to_string()),
+                            ("id", &self.id),
+                            ("ext", extension),
+                        ],
+                    )));
+                }
+            }
+
             let name = match file_type {
                 FileType::Playlist => return None,
                 FileType::Video => {
@@ -1094,11 +2420,16 @@ impl Movie {
                 FileType::Thumbnail => {
                     if output_style != OutputStyle::Standardized {
                         format!("{}.{extension}", self.id)
-                    } else if state.parts.len() == 1 {
-                        format!("{} ({}).{extension}", state.title, m_state.year)
                     } else {
-                        format!("movie.{extension}")
+                        format!("poster.{extension}")
+                    }
+                }
+                FileType::Fanart => {
+                    if output_style != OutputStyle::Standardized {
+                        return None;
                     }
+
+                    format!("fanart.{extension}")
                 }
                 FileType::Metadata => {
                     if output_style != OutputStyle::Standardized {
@@ -1135,6 +2466,20 @@ impl Movie {
     }
 }
 
+/// Estimates the size of a transcode at the given bitrate in kbps and duration
+/// in milliseconds. A transcode is never expected to be larger than its source
+/// when the source size is known.
+fn estimated_transcode_size(bitrate: u64, duration: u64, source_size: u64) -> u64 {
+    // Bitrate is in kbps and duration in ms so this gives bytes.
+    let transcoded_size = bitrate * duration / 8;
+
+    if source_size > 0 {
+        transcoded_size.min(source_size)
+    } else {
+        transcoded_size
+    }
+}
+
 #[derive(Clone)]
 pub enum Video {
     Movie(Movie),
@@ -1163,10 +2508,10 @@ impl Hash for Video {
 }
 
 impl Video {
-    pub async fn playback_state(&self) -> PlaybackState {
+    pub async fn playback_state(&self, user: Option<&str>) -> PlaybackState {
         match self {
-            Self::Movie(v) => v.playback_state().await,
-            Self::Episode(v) => v.playback_state().await,
+            Self::Movie(v) => v.playback_state(user).await,
+            Self::Episode(v) => v.playback_state(user).await,
         }
     }
 
@@ -1177,10 +2522,10 @@ impl Video {
         }
     }
 
-    pub async fn last_played(&self) -> Option<OffsetDateTime> {
+    pub async fn last_played(&self, user: Option<&str>) -> Option<OffsetDateTime> {
         match self {
-            Self::Movie(v) => v.last_played().await,
-            Self::Episode(v) => v.last_played().await,
+            Self::Movie(v) => v.last_played(user).await,
+            Self::Episode(v) => v.last_played(user).await,
         }
     }
 
@@ -1191,15 +2536,19 @@ impl Video {
         }
     }
 
-    pub async fn set_playback_state(&self, state: PlaybackState) -> Result {
+    pub async fn set_playback_state(&self, user: Option<&str>, state: PlaybackState) -> Result {
         match self {
-            Self::Movie(v) => v.set_playback_state(state).await,
-            Self::Episode(v) => v.set_playback_state(state).await,
+            Self::Movie(v) => v.set_playback_state(user, state).await,
+            Self::Episode(v) => v.set_playback_state(user, state).await,
         }
     }
 
-    pub async fn set_playback_position(&self, position: u64) -> Result {
-        trace!(video = self.id(), position, "Updating playback position");
+    /// Records a playback position for a user, or the main user if `None`.
+    pub async fn set_playback_position(&self, user: Option<&str>, position: u64) -> Result {
+        trace!(
+            video = self.id(),
+            user, position, "Updating playback position"
+        );
 
         let new_state = if position <= (5 * 60000) {
             PlaybackState::Unplayed
@@ -1214,7 +2563,7 @@ impl Video {
             }
         };
 
-        self.set_playback_state(new_state).await
+        self.set_playback_state(user, new_state).await
     }
 
     pub async fn duration(&self) -> Duration {
@@ -1294,6 +2643,99 @@ impl Video {
         }
     }
 
+    pub(crate) async fn layout_path(
+        &self,
+        layout: &OutputLayout,
+        file_type: FileType,
+        extension: &str,
+    ) -> Option<PathBuf> {
+        match self {
+            Self::Movie(v) => v.layout_path(layout, file_type, extension).await,
+            Self::Episode(v) => v.layout_path(layout, file_type, extension).await,
+        }
+    }
+
+    /// Lists the files stored for this video, including those of its show and
+    /// season, along with the path each would have in the given layout.
+    /// Nothing is listed until every part has been downloaded.
+    pub(crate) async fn export_files(&self, layout: &OutputLayout) -> Vec<(PathBuf, PathBuf)> {
+        let mut files = Vec::new();
+
+        if !self.is_downloaded().await {
+            return files;
+        }
+
+        for part in self.parts().await {
+            if let Some(source) = part.download_state().await.path()
+                && let Some(extension) = source.extension().and_then(|e| e.to_str())
+                && let Some(target) = part.layout_path(layout, extension).await
+            {
+                files.push((source, target));
+            }
+        }
+
+        let (thumbnail, fanart, subtitles) = self
+            .with_state(|vs| (vs.thumbnail.path(), vs.fanart.path(), vs.subtitles.clone()))
+            .await;
+
+        for (source, file_type) in [(thumbnail, FileType::Thumbnail), (fanart, FileType::Fanart)] {
+            if let Some(source) = source
+                && let Some(target) = self.layout_path(layout, file_type, "jpg").await
+            {
+                files.push((source, target));
+            }
+        }
+
+        if let Some(source) = self.file_path(FileType::Metadata, "nfo").await
+            && let Some(target) = self.layout_path(layout, FileType::Metadata, "nfo").await
+        {
+            files.push((source, target));
+        }
+
+        for subtitle in subtitles {
+            if let Some(source) = subtitle.file.path()
+                && let Some(target) = self
+                    .layout_path(layout, FileType::Video, &subtitle.extension())
+                    .await
+            {
+                files.push((source, target));
+            }
+        }
+
+        if let Self::Episode(episode) = self {
+            let season = episode.season().await;
+            let show = season.show().await;
+
+            let (thumbnail, fanart) = show
+                .with_state(|ss| (ss.thumbnail.path(), ss.fanart.path()))
+                .await;
+
+            for (source, file_type) in
+                [(thumbnail, FileType::Thumbnail), (fanart, FileType::Fanart)]
+            {
+                if let Some(source) = source
+                    && let Some(target) = show.layout_path(layout, file_type, "jpg").await
+                {
+                    files.push((source, target));
+                }
+            }
+
+            if let Some(source) = show.file_path(FileType::Metadata, "nfo").await
+                && let Some(target) = show.layout_path(layout, FileType::Metadata, "nfo").await
+            {
+                files.push((source, target));
+            }
+
+            if let Some(source) = season.with_state(|ss| ss.thumbnail.path()).await
+                && let Some(target) = season.layout_path(layout, FileType::Thumbnail, "jpg").await
+            {
+                files.push((source, target));
+            }
+        }
+
+        files
+    }
+
     pub async fn update_thumbnail(&self, rebuild: bool) -> Result {
         match self {
             Self::Movie(v) => v.update_thumbnail(rebuild).await,
@@ -1309,13 +2751,30 @@ impl Video {
     }
 
     pub(crate) async fn transfer_state(&self) -> TransferState {
-        let download_state = self.download_state().await;
+        let mut states = Vec::new();
+        for part in self.parts().await {
+            states.push(match part.download_state().await {
+                DownloadState::None => TransferState::Waiting,
+                DownloadState::Downloading { .. } => TransferState::Downloading,
+                DownloadState::Transcoding { .. } => TransferState::Transcoding,
+                DownloadState::Expired => TransferState::Expired,
+                _ => TransferState::Downloaded,
+            });
+        }
 
-        match download_state {
-            DownloadState::None => TransferState::Waiting,
-            DownloadState::Downloading { .. } => TransferState::Downloading,
-            DownloadState::Transcoding { .. } => TransferState::Transcoding,
-            _ => TransferState::Downloaded,
+        if states.iter().all(|s| *s == TransferState::Downloaded) {
+            TransferState::Downloaded
+        } else if states.iter().all(|s| *s == TransferState::Expired) {
+            TransferState::Expired
+        } else if states.contains(&TransferState::Downloading) {
+            TransferState::Downloading
+        } else if states.contains(&TransferState::Transcoding) {
+            TransferState::Transcoding
+        } else if states.contains(&TransferState::Downloaded) {
+            // Some parts are complete.
+            TransferState::Downloading
+        } else {
+            TransferState::Waiting
         }
     }
 
@@ -1323,10 +2782,6 @@ impl Video {
         self.server().try_lock_write_key(self.id()).await
     }
 
-    async fn try_lock_read(&self) -> result::Result<OpReadGuard, Timeout> {
-        self.server().try_lock_read_key(self.id()).await
-    }
-
     async fn with_server_state<F, R>(&self, cb: F) -> R
     where
         F: Send + FnOnce(&ServerState) -> R,
@@ -1356,16 +2811,6 @@ impl Video {
         server.inner.persist_state(&state).await
     }
 
-    pub async fn file(&self) -> result::Result<Option<LockedFile>, Timeout> {
-        let guard = self.try_lock_read().await?;
-
-        Ok(self
-            .download_state()
-            .await
-            .file(guard, &self.server().inner.path)
-            .await)
-    }
-
     #[instrument(level = "trace", skip(self, plex_server), fields(video=self.id()))]
     pub(crate) async fn verify_download(
         &self,
@@ -1376,67 +2821,24 @@ impl Video {
             return Ok(());
         };
 
-        let mut download_state = self.download_state().await;
-
-        download_state
-            .verify(
-                &guard,
-                plex_server,
-                self,
-                &self.server().inner.path,
-                allow_video_deletion,
-            )
-            .await;
+        for part in self.parts().await {
+            part.verify_download(&guard, plex_server, allow_video_deletion)
+                .await?;
+        }
 
-        self.update_state(|state| state.download = download_state)
-            .await
+        Ok(())
     }
 
     pub async fn recover_download(&self) -> Result {
-        let title = self.with_state(|vs| vs.title.clone()).await;
-        let mut expected_size = 0;
-
         for part in self.parts().await {
-            expected_size += part.remote_size().await;
-        }
-
-        for container in [
-            ContainerFormat::Avi,
-            ContainerFormat::Mpeg,
-            ContainerFormat::MpegTs,
-            ContainerFormat::M4v,
-            ContainerFormat::Mp4,
-            ContainerFormat::Mkv,
-        ] {
-            let path = self
-                .file_path(FileType::Video, &container.to_string())
-                .await
-                .unwrap();
-            let target = self.server().inner.path.join(&path);
-
-            if let Ok(stats) = metadata(target).await
-                && stats.is_file()
-            {
-                info!(path=?path.display(), "Recovered download for {title}");
-
-                let download_state = if stats.size() == expected_size {
-                    DownloadState::Downloaded { path }
-                } else {
-                    DownloadState::Transcoded { path }
-                };
-
-                return self
-                    .update_state(|state| state.download = download_state)
-                    .await;
-            }
+            part.recover_download().await?;
         }
 
         Ok(())
     }
 
     pub async fn is_downloaded(&self) -> bool {
-        let download_state = self.download_state().await;
-        !download_state.needs_download()
+        self.with_state(|vs| vs.is_downloaded()).await
     }
 
     async fn transcode_profile(&self) -> VideoTranscodeOptions {
@@ -1450,206 +2852,69 @@ impl Video {
             .await
     }
 
-    #[instrument(level = "trace", skip(self, queue, guard, path, progress), fields(video=self.id()))]
-    async fn download_media<P: Progress>(
-        &self,
-        queue: &DownloadQueue,
-        queue_id: u32,
-        guard: &OpWriteGuard,
-        path: &Path,
-        progress: &mut P,
-    ) -> Result {
-        let target = self.server().inner.path.join(path);
-        let offset = match metadata(&target).await {
-            Ok(stats) => stats.len(),
-            Err(e) => {
-                if e.kind() == ErrorKind::NotFound {
-                    0
-                } else {
-                    return Err(e.into());
-                }
-            }
-        };
-
-        let item = queue.item(queue_id).await?;
-
-        if let Some(parent) = target.parent() {
-            create_dir_all(parent).await?;
-        }
-
-        let file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&target)
-            .await?;
-
-        if let Ok(Some(len)) = item.content_length().await {
-            progress.length(len);
-        }
-
-        let writer = WriterProgress {
-            offset,
-            writer: AsyncWriteAdapter::new(BufWriter::new(file)),
-            progress,
-            access_permit: &mut None,
-        };
-        info!(path=?path, offset, "Downloading source file");
-
-        item.download(writer, offset..).await?;
-
-        info!(path=?path, "Download complete");
-
-        let new_state = if item.is_transcode() {
-            DownloadState::Transcoded {
-                path: path.to_owned(),
-            }
-        } else {
-            DownloadState::Downloaded {
-                path: path.to_owned(),
-            }
-        };
-
-        if let Err(e) = item.delete().await {
-            warn!(error=?e, "Failed to delete transcode session");
-        }
+    async fn ffmpeg_args(&self, streams: &LocalStreams) -> Result<Vec<String>> {
+        let profile = self.with_state(|vs| vs.transcode_profile.clone()).await;
 
-        self.update_state(|state| {
-            state.download = new_state.clone();
-        })
-        .await?;
+        let server_profile = self.server().transcode_profile().await;
 
-        if let Err(e) = new_state
-            .strip_metadata(guard, &self.server().inner.path)
+        self.server()
+            .inner
+            .ffmpeg_args(&profile.unwrap_or(server_profile), streams)
             .await
-        {
-            warn!(path=?path, error=%e, "Failed to strip metadata");
-        }
-
-        Ok(())
     }
 
-    async fn queue_download(&self, plex_server: &PlexServer, queue: &DownloadQueue) -> Result {
-        let item = plex_server.item_by_id(self.id()).await?;
-        let video = match item {
-            Item::Movie(m) => library::Video::Movie(m),
-            Item::Episode(e) => library::Video::Episode(e),
-            _ => panic!("Unexpected item type"),
-        };
+    /// Estimates the disk space used by this video once downloaded. For videos
+    /// that are already downloaded this is the actual size of the file.
+    pub(crate) async fn estimated_size(&self) -> u64 {
+        if self.is_downloaded().await {
+            return self.stats().await.local_bytes;
+        }
 
         let options = self.transcode_profile().await;
+        let mut source_size = 0;
+        let mut duration = 0;
 
-        let queue_item = video.queue_download(options, Some(queue)).await?;
-
-        self.update_state(|state| {
-            state.download = DownloadState::Transcoding {
-                queue_id: queue_item.id(),
-            };
-        })
-        .await?;
+        for part in self.parts().await {
+            source_size += part.remote_size().await;
+            duration += part.duration().await.as_millis() as u64;
+        }
 
-        Ok(())
+        estimated_transcode_size(options.bitrate as u64, duration, source_size)
     }
 
-    #[instrument(level = "trace", skip(self, queue, queue_id, download_progress), fields(video=self.id()))]
-    async fn wait_for_available<D: DownloadProgress>(
-        &self,
-        queue: &DownloadQueue,
-        queue_id: u32,
-        download_progress: &D,
-    ) -> Result {
-        let mut queue_item = queue.item(queue_id).await?;
-
-        loop {
-            match queue_item.status() {
-                QueueItemStatus::Available => break,
-                QueueItemStatus::Waiting | QueueItemStatus::Deciding => {
-                    sleep(Duration::from_millis(500)).await;
-                    queue_item.update().await?;
-                }
-                QueueItemStatus::Processing => {
-                    let mut progress = download_progress.transcode_started().await;
-
-                    loop {
-                        match queue_item.status() {
-                            QueueItemStatus::Waiting | QueueItemStatus::Deciding => {
-                                // This shouldn't happen.
-                                progress.failed(anyhow!(
-                                    "Transcode session unexpectedly went back to waiting/deciding"
-                                ));
-                                break;
-                            }
-                            QueueItemStatus::Available => {
-                                progress.finished();
-                                break;
-                            }
-                            QueueItemStatus::Processing => {
-                                let delay = if let Some(stats) = queue_item.stats() {
-                                    progress.progress(stats.progress as u64);
-                                    if let Some(remaining) = stats.remaining {
-                                        remaining.clamp(1, 5) as u64
-                                    } else {
-                                        5
-                                    }
-                                } else {
-                                    5
-                                };
-
-                                sleep(Duration::from_secs(delay)).await;
-                                queue_item.update().await?;
-                            }
-                            QueueItemStatus::Expired | QueueItemStatus::Error => {
-                                warn!("Transcode failed or expired");
-                                progress.failed(anyhow!("Transcode failed or expired"));
-
-                                self.update_state(|state| {
-                                    state.download = DownloadState::None;
-                                })
-                                .await?;
+    /// Returns the container of the original media if it can be downloaded
+    /// without transcoding.
+    async fn direct_play_container(&self, video: &library::Video) -> Option<ContainerFormat> {
+        let profile = self.with_state(|vs| vs.transcode_profile.clone()).await;
+        let server_profile = self.server().transcode_profile().await;
 
-                                return Ok(());
-                            }
-                        }
-                    }
-                }
-                QueueItemStatus::Expired | QueueItemStatus::Error => {
-                    self.update_state(|state| {
-                        state.download = DownloadState::None;
-                    })
-                    .await?;
+        let profile = self
+            .server()
+            .inner
+            .direct_play_profile(&profile.unwrap_or(server_profile))
+            .await?;
 
-                    bail!("Transcode failed or expired");
-                }
-            }
+        if self.subtitle_preferences().await.is_some_and(|p| p.burn) {
+            return None;
         }
 
-        let container = match queue_item.container().await {
-            Ok(c) => c,
-            Err(e) => {
-                let options = self.transcode_profile().await;
-                let container = options
-                    .containers
-                    .first()
-                    .cloned()
-                    .unwrap_or(ContainerFormat::Mp4);
+        let media = video.media();
+        let media = media.first()?;
 
-                warn!(
-                    error=?e,
-                    ?container,
-                    "No container specified by Plex. Guessing based on transcode profile"
-                );
-                container
-            }
-        };
+        if !profile.allows_direct_play(media.metadata()) {
+            return None;
+        }
 
-        let path = self
-            .file_path(FileType::Video, &container.to_string())
-            .await
-            .unwrap();
+        media.metadata().container
+    }
 
-        self.update_state(|state| {
-            state.download = DownloadState::Downloading { queue_id, path };
+    async fn plex_video(&self, plex_server: &PlexServer) -> Result<library::Video> {
+        let item = plex_server.item_by_id(self.id()).await?;
+        Ok(match item {
+            Item::Movie(m) => library::Video::Movie(m),
+            Item::Episode(e) => library::Video::Episode(e),
+            _ => panic!("Unexpected item type"),
         })
-        .await
     }
 
     #[instrument(level = "trace", skip_all, fields(video=self.id()))]
@@ -1657,31 +2922,21 @@ impl Video {
         self,
         plex_server: PlexServer,
         download_progress: D,
-    ) -> bool {
+    ) -> DownloadResult {
         let Ok(guard) = self.try_lock_write().await else {
             download_progress
                 .download_failed(anyhow!("Failed to lock item for writing"))
                 .await;
-            return false;
+            return DownloadResult::Incomplete;
         };
 
-        let mut download_state = self.download_state().await;
-        download_state
-            .verify(
-                &guard,
-                &plex_server,
-                &self,
-                &self.server().inner.path,
-                false,
-            )
-            .await;
+        let parts = self.parts().await;
 
-        if let Err(e) = self
-            .update_state(|state| state.download = download_state.clone())
-            .await
-        {
-            download_progress.download_failed(e).await;
-            return false;
+        for part in parts.iter() {
+            if let Err(e) = part.verify_download(&guard, &plex_server, false).await {
+                download_progress.download_failed(e).await;
+                return DownloadResult::Incomplete;
+            }
         }
 
         let queue = match plex_server.download_queue().await {
@@ -1689,71 +2944,58 @@ impl Video {
             Err(e) => {
                 warn!(error=?e);
                 download_progress.download_failed(e.into()).await;
-                return false;
+                return DownloadResult::Incomplete;
             }
         };
 
-        if download_state == DownloadState::None
-            && let Err(e) = self.queue_download(&plex_server, &queue).await
-        {
-            warn!(error=?e);
-            download_progress.download_failed(e).await;
-            return false;
-        }
-
-        loop {
-            download_state = self.download_state().await;
-
-            match download_state {
-                DownloadState::None => {
+        let mut plex_video = None;
+
+        for part in parts {
+            match part
+                .download(
+                    &plex_server,
+                    &queue,
+                    &mut plex_video,
+                    &guard,
+                    &download_progress,
+                )
+                .await
+            {
+                Ok(true) => {}
+                Ok(false) => {
                     download_progress.finished().await;
-                    return false;
+                    return DownloadResult::Incomplete;
                 }
-                DownloadState::Transcoding { queue_id } => {
-                    if let Err(e) = self
-                        .wait_for_available(&queue, queue_id, &download_progress)
-                        .await
-                    {
-                        warn!(error=?e);
-                        download_progress.download_failed(e).await;
-                        return false;
-                    }
+                Err(e) if OutsideDownloadWindow::is_cause(&e) => {
+                    info!("Download window closed, stopping download");
+                    download_progress.finished().await;
+                    return DownloadResult::Skipped;
                 }
-                DownloadState::Downloading { queue_id, path } => {
-                    let _permit = self
-                        .server()
-                        .inner
-                        .download_permits
-                        .clone()
-                        .acquire_owned()
-                        .await
-                        .unwrap();
+                Err(e) => {
+                    warn!(error=?e);
+                    download_progress.download_failed(e).await;
+                    return DownloadResult::Incomplete;
+                }
+            }
+        }
 
-                    let mut progress = download_progress.download_started().await;
+        download_progress.finished().await;
+        DownloadResult::Complete
+    }
 
-                    if let Err(e) = self
-                        .download_media(&queue, queue_id, &guard, &path, &mut progress)
-                        .await
-                    {
-                        warn!(error=?e);
-                        progress.failed(e);
-                        download_progress.finished().await;
-                        return false;
-                    }
+    /// Checks the integrity of this video's downloaded files, resetting any
+    /// that are corrupt. Returns the number of corrupt files found.
+    pub(crate) async fn verify_integrity(&self) -> Result<usize> {
+        let guard = self.try_lock_write().await?;
 
-                    progress.finished();
-                }
-                DownloadState::Downloaded { .. } | DownloadState::Transcoded { .. } => {
-                    download_progress.finished().await;
-                    return true;
-                }
+        let mut corrupt = 0;
+        for part in self.parts().await {
+            if !part.verify_integrity(&guard).await? {
+                corrupt += 1;
             }
         }
-    }
 
-    async fn download_state(&self) -> DownloadState {
-        self.with_state(|part_state| part_state.download.clone())
-            .await
+        Ok(corrupt)
     }
 
     pub(crate) async fn strip_metadata(&self) {
@@ -1761,15 +3003,481 @@ impl Video {
             return;
         };
 
-        let state = self.download_state().await;
+        for part in self.parts().await {
+            if let Err(e) = part.strip_metadata(&guard).await {
+                warn!(error=%e, "Unable to strip metadata from video file");
+            }
+        }
+    }
+
+    /// The tags to embed in this video's downloaded files.
+    async fn media_tags(&self) -> MediaTags {
+        let (title, air_date, cover) = self
+            .with_state(|vs| (vs.title.clone(), vs.air_date, vs.thumbnail.path()))
+            .await;
+
+        let mut tags = MediaTags {
+            title,
+            air_date,
+            cover: cover.map(|path| self.server().inner.path.join(path)),
+            ..Default::default()
+        };
+
+        if let Self::Episode(episode) = self {
+            tags.show = Some(episode.show().await.title().await);
+            tags.season = Some(episode.season().await.index().await);
+            tags.episode = episode.index().await;
+        }
+
+        tags
+    }
+
+    /// The subtitle preferences from this video's sync items, falling back to
+    /// those of its transcode profile.
+    async fn subtitle_preferences(&self) -> Option<SubtitlePreferences> {
+        let (preferences, profile) = self
+            .with_state(|vs| {
+                (
+                    vs.subtitle_preferences.clone(),
+                    vs.transcode_profile.clone(),
+                )
+            })
+            .await;
+
+        if preferences.is_some() {
+            return preferences;
+        }
 
-        if let Err(e) = state
-            .strip_metadata(&guard, &self.server().inner.path)
+        let server_profile = self.server().transcode_profile().await;
+        self.server()
+            .inner
+            .subtitle_preferences(&profile.unwrap_or(server_profile))
             .await
-        {
-            warn!(error=%e, "Unable to strip metadata from video file");
+    }
+
+    /// The preferred audio languages from this video's sync items, falling
+    /// back to those of its transcode profile.
+    async fn audio_languages(&self) -> Option<Vec<String>> {
+        let (languages, profile) = self
+            .with_state(|vs| (vs.audio_languages.clone(), vs.transcode_profile.clone()))
+            .await;
+
+        if languages.is_some() {
+            return languages;
+        }
+
+        let server_profile = self.server().transcode_profile().await;
+        self.server()
+            .inner
+            .audio_languages(&profile.unwrap_or(server_profile))
+            .await
+    }
+
+    /// Chooses the audio stream of a part in the preferred language for Plex
+    /// to include in the transcode. Returns the ID of the chosen stream.
+    async fn select_audio_stream(&self, video: &library::Video, index: usize) -> Option<String> {
+        let languages = self.audio_languages().await?;
+        let streams = audio_streams(video, index)?;
+
+        let Some(stream) = preferred_audio_stream(&languages, &streams) else {
+            debug!(video = self.id(), "No audio stream in a preferred language");
+            return None;
+        };
+
+        Some(stream.id.clone())
+    }
+
+    /// Chooses the first subtitle stream of a part matching the preferences
+    /// for Plex to burn into the transcoded part. Returns `None` if there was
+    /// no matching stream.
+    fn select_burned_subtitle(
+        &self,
+        video: &library::Video,
+        index: usize,
+        preferences: &SubtitlePreferences,
+    ) -> Option<String> {
+        let media = video.media();
+        let part = media.first()?.parts().into_iter().nth(index)?;
+
+        let Some(stream) = matching_subtitles(&part.metadata().streams, preferences).next() else {
+            debug!(
+                video = self.id(),
+                part = index,
+                "No matching subtitles to burn"
+            );
+            return None;
+        };
+
+        Some(stream.id.clone())
+    }
+
+    /// Downloads sidecar subtitle files matching this video's subtitle
+    /// preferences and removes any that are no longer wanted.
+    #[instrument(level = "trace", skip(self), fields(video=self.id()))]
+    pub(crate) async fn update_subtitles(&self) -> Result {
+        let Ok(guard) = self.try_lock_write().await else {
+            return Ok(());
+        };
+
+        let root = self.server().inner.path.clone();
+        let (mut subtitles, checked, last_updated, expired) = self
+            .with_state(|vs| {
+                (
+                    vs.subtitles.clone(),
+                    vs.subtitles_checked.clone(),
+                    vs.last_updated,
+                    vs.is_expired(),
+                )
+            })
+            .await;
+
+        let preferences = match self.subtitle_preferences().await {
+            Some(preferences) if !preferences.burn && !expired => preferences,
+            _ => {
+                if subtitles.is_empty() && checked.is_none() {
+                    return Ok(());
+                }
+
+                for subtitle in subtitles.iter_mut() {
+                    subtitle.file.delete(&guard, &root).await;
+                }
+
+                return self
+                    .update_state(|vs| {
+                        vs.subtitles.clear();
+                        vs.subtitles_checked = None;
+                    })
+                    .await;
+            }
+        };
+
+        // Only ask the server for the video's streams if it has changed since
+        // the subtitles were chosen or some are missing locally.
+        if checked.is_some_and(|checked| checked.is_current(&preferences, last_updated)) {
+            let mut moved = false;
+            let mut missing = false;
+
+            for subtitle in subtitles.iter_mut() {
+                let Some(path) = self.file_path(FileType::Video, &subtitle.extension()).await
+                else {
+                    continue;
+                };
+
+                let previous = subtitle.file.path();
+                subtitle.file.verify(&guard, &root, &path).await;
+
+                missing |= subtitle.file.needs_update(last_updated);
+                moved |= subtitle.file.path() != previous;
+            }
+
+            if !missing {
+                if moved {
+                    return self.update_state(|vs| vs.subtitles = subtitles).await;
+                }
+
+                return Ok(());
+            }
+        }
+
+        let plex_server = self.server().connect().await?;
+        let video = match plex_server.item_by_id(self.id()).await? {
+            Item::Movie(m) => library::Video::Movie(m),
+            Item::Episode(e) => library::Video::Episode(e),
+            _ => bail!("Unexpected item type"),
+        };
+
+        let mut updated = Vec::new();
+        for (_, stream) in subtitle_streams(&video, &preferences) {
+            let (Some(key), Some(format)) = (&stream.key, sidecar_format(&stream.codec)) else {
+                continue;
+            };
+
+            let mut subtitle = match subtitles.iter().position(|s| s.id == stream.id) {
+                Some(pos) => subtitles.remove(pos),
+                None => SubtitleState {
+                    id: stream.id.clone(),
+                    language: stream
+                        .language_tag
+                        .clone()
+                        .or_else(|| stream.language_code.clone())
+                        .unwrap_or_else(|| "und".to_string()),
+                    forced: stream.forced.unwrap_or_default(),
+                    format: format.to_owned(),
+                    file: RelatedFileState::None,
+                },
+            };
+
+            let Some(path) = self.file_path(FileType::Video, &subtitle.extension()).await else {
+                continue;
+            };
+
+            subtitle.file.verify(&guard, &root, &path).await;
+
+            if subtitle.file.needs_update(last_updated) {
+                match download_subtitle(plex_server.client(), key, &root.join(&path)).await {
+                    Ok(()) => {
+                        subtitle.file = RelatedFileState::Stored {
+                            path,
+                            updated: OffsetDateTime::now_utc(),
+                        };
+                    }
+                    Err(e) => {
+                        warn!(language = subtitle.language, error=?e, "Failed to download subtitles")
+                    }
+                }
+            }
+
+            updated.push(subtitle);
+        }
+
+        // Anything left over no longer matches the preferences.
+        for subtitle in subtitles.iter_mut() {
+            subtitle.file.delete(&guard, &root).await;
+        }
+
+        self.update_state(|vs| {
+            vs.subtitles = updated;
+            vs.subtitles_checked = Some(SubtitlesChecked {
+                preferences,
+                at: OffsetDateTime::now_utc(),
+            });
+        })
+        .await
+    }
+}
+
+#[derive(Deserialize)]
+struct QueueSpec {
+    id: u32,
+}
+
+#[derive(Deserialize)]
+struct QueueContainer {
+    #[serde(rename = "DownloadQueue", default)]
+    queues: Vec<QueueSpec>,
+}
+
+#[derive(Deserialize)]
+struct AddedQueueItem {
+    key: String,
+    id: u32,
+}
+
+#[derive(Deserialize)]
+struct AddedQueueItems {
+    #[serde(rename = "AddedQueueItems", default)]
+    items: Vec<AddedQueueItem>,
+}
+
+#[derive(Deserialize)]
+struct MediaContainer<T> {
+    #[serde(rename = "MediaContainer")]
+    media_container: T,
+}
+
+/// Adds a part of a video to the download queue, transcoding it with the
+/// given streams. The Plex API crate offers no way to choose streams for a
+/// single transcode so this builds the request itself rather than changing
+/// the part's selected streams for every client of the server.
+async fn queue_transcode(
+    plex_server: &PlexServer,
+    queue: &DownloadQueue,
+    video: &library::Video,
+    part_index: usize,
+    options: VideoTranscodeOptions,
+    audio_stream: Option<&str>,
+    subtitle_stream: Option<&str>,
+) -> Result<QueueItem> {
+    let client = plex_server.client();
+
+    // Fetches the same queue that `queue` refers to, the crate just doesn't
+    // expose its ID.
+    let Some(queue_id) = client
+        .post("/downloadQueue")
+        .json::<MediaContainer<QueueContainer>>()
+        .await?
+        .media_container
+        .queues
+        .first()
+        .map(|queue| queue.id)
+    else {
+        bail!("Plex server did not return a download queue");
+    };
+
+    let key = &video.metadata().key;
+    let session = Uuid::new_v4().as_simple().to_string();
+
+    let mut params: Vec<(String, String)> = [
+        ("session", session.as_str()),
+        ("transcodeSessionId", session.as_str()),
+        ("directPlay", "1"),
+        ("directStream", "1"),
+        ("directStreamAudio", "1"),
+        ("protocol", "http"),
+        ("context", "static"),
+        ("location", "lan"),
+        ("fastSeek", "1"),
+        ("mediaIndex", "0"),
+        ("partIndex", &part_index.to_string()),
+        ("keys", key),
+        ("path", key),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_owned(), value.to_owned()))
+    .collect();
+
+    params.extend(options.transcode_parameters(TranscodeContext::Static, Protocol::Http, None));
+
+    if let Some(stream) = audio_stream {
+        params.push(("audioStreamID".to_owned(), stream.to_owned()));
+    }
+
+    if let Some(stream) = subtitle_stream {
+        params.push(("subtitleStreamID".to_owned(), stream.to_owned()));
+    }
+
+    let query = params
+        .iter()
+        .map(|(name, value)| format!("{}={}", encode_param(name), encode_param(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let added = client
+        .post(format!("/downloadQueue/{queue_id}/add?{query}"))
+        .json::<MediaContainer<AddedQueueItems>>()
+        .await?;
+
+    let Some(item) = added.media_container.items.iter().find(|i| &i.key == key) else {
+        bail!("Plex server did not add the video to the download queue");
+    };
+
+    Ok(queue.item(item.id).await?)
+}
+
+/// Lists the audio streams of one of a video's parts.
+fn audio_streams<M: MediaItem>(item: &M, index: usize) -> Option<Vec<AudioStream>> {
+    let media = item.media();
+    let part = media.first()?.parts().into_iter().nth(index)?;
+
+    Some(part_audio_streams(&part))
+}
+
+/// Lists the audio streams of each of a video's parts.
+pub(crate) fn parts_audio_streams<M: MediaItem>(item: &M) -> Vec<Vec<AudioStream>> {
+    item.media()
+        .first()
+        .map(|media| media.parts().iter().map(part_audio_streams).collect())
+        .unwrap_or_default()
+}
+
+fn part_audio_streams<M: MediaItem>(part: &library::Part<'_, M>) -> Vec<AudioStream> {
+    part.metadata()
+        .streams
+        .iter()
+        .flatten()
+        .filter_map(|stream| match stream {
+            Stream::Audio(stream) => Some(stream.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Finds the subtitle streams of a video that match the preferences, at most
+/// one per language and forced combination, along with the ID of the part
+/// containing them.
+fn subtitle_streams(
+    video: &library::Video,
+    preferences: &SubtitlePreferences,
+) -> Vec<(String, SubtitleStream)> {
+    let mut seen = HashSet::new();
+    let mut streams = Vec::new();
+
+    for media in video.media().iter().take(1) {
+        for part in media.parts() {
+            let metadata = part.metadata();
+            let Some(ref part_id) = metadata.id else {
+                continue;
+            };
+
+            for stream in matching_subtitles(&metadata.streams, preferences) {
+                if seen.insert((
+                    stream.language_code.clone(),
+                    stream.forced.unwrap_or_default(),
+                )) {
+                    streams.push((part_id.clone(), stream.clone()));
+                }
+            }
         }
     }
+
+    streams
+}
+
+/// Filters a part's streams to the subtitle streams matching the preferences.
+fn matching_subtitles<'a>(
+    streams: &'a Option<Vec<Stream>>,
+    preferences: &'a SubtitlePreferences,
+) -> impl Iterator<Item = &'a SubtitleStream> {
+    streams.iter().flatten().filter_map(|stream| {
+        let Stream::Subtitle(stream) = stream else {
+            return None;
+        };
+
+        let languages = [
+            stream.language_code.as_deref(),
+            stream.language_tag.as_deref(),
+        ];
+
+        preferences
+            .matches(&languages, stream.forced.unwrap_or_default())
+            .then_some(stream)
+    })
+}
+
+/// The file extension to store a subtitle codec as, if it can be stored as a
+/// sidecar file.
+fn sidecar_format(codec: &SubtitleCodec) -> Option<&'static str> {
+    match codec {
+        SubtitleCodec::Srt | SubtitleCodec::Subrip => Some("srt"),
+        SubtitleCodec::Vtt => Some("vtt"),
+        SubtitleCodec::Ass => Some("ass"),
+        _ => None,
+    }
+}
+
+async fn download_subtitle(client: &HttpClient, key: &str, target: &Path) -> Result {
+    if let Some(parent) = target.parent() {
+        create_dir_all(parent).await?;
+    }
+
+    let mut response = client.get(key).send().await?;
+    if !response.status().is_success() {
+        bail!(
+            "Unexpected response from Plex server: {}",
+            response.status()
+        );
+    }
+
+    let mut temp = target.as_os_str().to_owned();
+    temp.push(".temp");
+
+    let result: Result = async {
+        let mut file = AsyncWriteAdapter::new(File::create(&temp).await?);
+        futures::io::copy(response.body_mut(), &mut file).await?;
+        file.close().await?;
+        File::open(&temp).await?.sync_all().await?;
+        Ok(())
+    }
+    .await;
+
+    if let Err(e) = result {
+        let _ = remove_file(&temp).await;
+        return Err(e);
+    }
+
+    rename(&temp, target).await?;
+
+    Ok(())
 }
 
 #[derive(Clone)]
@@ -1802,9 +3510,25 @@ impl Playlist {
     }
 
     pub(crate) async fn file_path(&self, file_type: FileType, extension: &str) -> Option<PathBuf> {
-        let output_style = self.server.inner.output_style().await;
+        let OutputLayout {
+            style: output_style,
+            templates,
+        } = self.server.inner.output_layout().await;
 
         self.with_state(|state| {
+            if output_style == OutputStyle::Template && matches!(file_type, FileType::Playlist) {
+                let template = templates.playlist.as_deref()?;
+
+                return Some(PathBuf::from(safe(&self.server.id)).join(render_template(
+                    template,
+                    &[
+                        ("title", &state.title),
+                        ("id", &self.id),
+                        ("ext", extension),
+                    ],
+                )));
+            }
+
             let name = match file_type {
                 FileType::Thumbnail => {
                     if output_style == OutputStyle::Standardized {
@@ -1886,11 +3610,29 @@ impl MovieCollection {
     }
 
     pub(crate) async fn file_path(&self, file_type: FileType, extension: &str) -> Option<PathBuf> {
-        let output_style = self.server.inner.output_style().await;
+        let OutputLayout {
+            style: output_style,
+            templates,
+        } = self.server.inner.output_layout().await;
 
         self.with_server_state(|ss| {
             let state = ss.collections.get(&self.id).unwrap();
 
+            if output_style == OutputStyle::Template && matches!(file_type, FileType::Playlist) {
+                let template = templates.playlist.as_deref()?;
+                let library_title = &ss.libraries.get(&state.library).unwrap().title;
+
+                return Some(PathBuf::from(safe(&self.server.id)).join(render_template(
+                    template,
+                    &[
+                        ("library", library_title),
+                        ("title", &state.title),
+                        ("id", &self.id),
+                        ("ext", extension),
+                    ],
+                )));
+            }
+
             let name = match file_type {
                 FileType::Thumbnail => {
                     if output_style == OutputStyle::Standardized {
@@ -1962,11 +3704,29 @@ impl ShowCollection {
     }
 
     pub(crate) async fn file_path(&self, file_type: FileType, extension: &str) -> Option<PathBuf> {
-        let output_style = self.server.inner.output_style().await;
+        let OutputLayout {
+            style: output_style,
+            templates,
+        } = self.server.inner.output_layout().await;
 
         self.with_server_state(|ss| {
             let state = ss.collections.get(&self.id).unwrap();
 
+            if output_style == OutputStyle::Template && matches!(file_type, FileType::Playlist) {
+                let template = templates.playlist.as_deref()?;
+                let library_title = &ss.libraries.get(&state.library).unwrap().title;
+
+                return Some(PathBuf::from(safe(&self.server.id)).join(render_template(
+                    template,
+                    &[
+                        ("library", library_title),
+                        ("title", &state.title),
+                        ("id", &self.id),
+                        ("ext", extension),
+                    ],
+                )));
+            }
+
             let name = match file_type {
                 FileType::Thumbnail => {
                     if output_style == OutputStyle::Standardized {
@@ -2200,3 +3960,24 @@ impl Library {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::wrappers::estimated_transcode_size;
+
+    #[test]
+    fn transcode_size() {
+        // 4000kbps for an hour.
+        assert_eq!(estimated_transcode_size(4000, 3_600_000, 0), 1_800_000_000);
+        assert_eq!(
+            estimated_transcode_size(4000, 3_600_000, 5_000_000_000),
+            1_800_000_000
+        );
+        // Never larger than the source.
+        assert_eq!(
+            estimated_transcode_size(4000, 3_600_000, 700_000_000),
+            700_000_000
+        );
+        assert_eq!(estimated_transcode_size(4000, 0, 0), 0);
+    }
+}
@@ -7,7 +7,8 @@ export type DownloadState =
   | { state: "downloading"; path: string }
   | { state: "transcoding" }
   | { state: "downloaded"; path: string }
-  | { state: "transcoded"; path: string };
+  | { state: "transcoded"; path: string }
+  | { state: "expired" };
 
 export type PlaybackState =
   | { state: "unplayed" }
@@ -30,6 +31,18 @@ export interface CollectionState {
   thumbnail: RelatedFileState;
 }
 
+export interface PlaybackEvent {
+  at: number;
+  state: PlaybackState;
+  synced?: boolean;
+}
+
+export interface PlaybackProgress {
+  playbackState: PlaybackState;
+  lastViewedAt?: number;
+  playbackHistory?: PlaybackEvent[];
+}
+
 export interface PlaylistState {
   id: string;
   title: string;
@@ -52,6 +65,25 @@ export interface SeasonState {
   show: string;
   index: number;
   title: string;
+  lastUpdated?: number;
+  thumbnail?: RelatedFileState;
+}
+
+export interface CastMember {
+  name: string;
+  role?: string;
+}
+
+export interface ItemDetails {
+  plot?: string;
+  tagline?: string;
+  studio?: string;
+  contentRating?: string;
+  genres?: string[];
+  cast?: CastMember[];
+  directors?: string[];
+  writers?: string[];
+  uniqueIds?: Record<string, string>;
 }
 
 export interface ShowState {
@@ -61,7 +93,10 @@ export interface ShowState {
   year: number;
   lastUpdated: number;
   thumbnail: RelatedFileState;
+  fanart?: RelatedFileState;
   metadata?: RelatedFileState;
+  airDate?: string;
+  details?: ItemDetails;
 }
 
 export interface MovieDetail {
@@ -79,6 +114,23 @@ export interface VideoPartState {
   key: string;
   size: number;
   duration: number;
+  download: DownloadState;
+  checksum?: string;
+  audioStream?: string;
+}
+
+export interface SubtitlePreferences {
+  languages: string[];
+  forcedOnly: boolean;
+  burn: boolean;
+}
+
+export interface SubtitleState {
+  id: string;
+  language: string;
+  forced: boolean;
+  format: string;
+  file: RelatedFileState;
 }
 
 export interface VideoState {
@@ -87,14 +139,20 @@ export interface VideoState {
   detail: VideoDetail;
   airDate?: string;
   thumbnail: RelatedFileState;
+  fanart?: RelatedFileState;
   mediaId: string;
   lastUpdated: number;
   parts: VideoPartState[];
   transcodeProfile?: string;
   playbackState: PlaybackState;
   lastViewedAt?: number;
+  playbackHistory?: PlaybackEvent[];
+  userPlayback?: Record<string, PlaybackProgress>;
   metadata?: RelatedFileState;
-  download: DownloadState;
+  subtitlePreferences?: SubtitlePreferences;
+  subtitles?: SubtitleState[];
+  audioLanguages?: string[];
+  details?: ItemDetails;
 }
 
 export interface ServerState {
@@ -106,6 +164,7 @@ export interface ServerState {
   shows?: Record<string, ShowState>;
   seasons?: Record<string, SeasonState>;
   videos?: Record<string, VideoState>;
+  resolved?: Record<string, string[]>;
 }
 
 export interface State {
@@ -114,6 +173,9 @@ export interface State {
   servers?: Record<string, ServerState>;
 }
 
+export type PlaybackUpdate = PlaybackState & { at?: number };
+
 export interface PlaybackUpdates {
-  servers: Record<string, Record<string, PlaybackState>>;
+  servers: Record<string, Record<string, PlaybackUpdate>>;
+  users?: Record<string, Record<string, Record<string, PlaybackUpdate>>>;
 }
@@ -1,17 +1,23 @@
 import { JsonDecoder } from "ts.data.json";
 import {
+  CastMember,
   CollectionState,
   DownloadState,
   EpisodeDetail,
+  ItemDetails,
   LibraryState,
   LibraryType,
   MovieDetail,
+  PlaybackEvent,
+  PlaybackProgress,
   PlaybackState,
   PlaylistState,
   SeasonState,
   ServerState,
   ShowState,
   State,
+  SubtitlePreferences,
+  SubtitleState,
   RelatedFileState,
   VideoDetail,
   VideoPartState,
@@ -73,6 +79,12 @@ const DownloadStateDecoder = JsonDecoder.oneOf<DownloadState>(
       },
       "transcoded",
     ),
+    JsonDecoder.object(
+      {
+        state: JsonDecoder.isExactly("expired"),
+      },
+      "expired",
+    ),
   ],
   "DownloadState",
 );
@@ -102,12 +114,35 @@ const PlaybackStateDecoder = JsonDecoder.oneOf<PlaybackState>(
   "PlaybackState",
 );
 
+const PlaybackEventDecoder = JsonDecoder.object<PlaybackEvent>(
+  {
+    at: JsonDecoder.number,
+    state: PlaybackStateDecoder,
+    synced: JsonDecoder.optional(JsonDecoder.boolean),
+  },
+  "PlaybackEvent",
+);
+
+const PlaybackProgressDecoder = JsonDecoder.object<PlaybackProgress>(
+  {
+    playbackState: PlaybackStateDecoder,
+    lastViewedAt: JsonDecoder.optional(JsonDecoder.number),
+    playbackHistory: JsonDecoder.optional(
+      JsonDecoder.array(PlaybackEventDecoder, "PlaybackEvent[]"),
+    ),
+  },
+  "PlaybackProgress",
+);
+
 const VideoPartStateDecoder = JsonDecoder.object<VideoPartState>(
   {
     id: JsonDecoder.string,
     key: JsonDecoder.string,
     size: JsonDecoder.number,
     duration: JsonDecoder.number,
+    download: DownloadStateDecoder,
+    checksum: JsonDecoder.optional(JsonDecoder.string),
+    audioStream: JsonDecoder.optional(JsonDecoder.string),
   },
   "VideoPart",
 );
@@ -126,6 +161,39 @@ const LibraryStateDecoder = JsonDecoder.object<LibraryState>(
   "ShowLibraryState",
 );
 
+const CastMemberDecoder = JsonDecoder.object<CastMember>(
+  {
+    name: JsonDecoder.string,
+    role: JsonDecoder.optional(JsonDecoder.string),
+  },
+  "CastMember",
+);
+
+const ItemDetailsDecoder = JsonDecoder.object<ItemDetails>(
+  {
+    plot: JsonDecoder.optional(JsonDecoder.string),
+    tagline: JsonDecoder.optional(JsonDecoder.string),
+    studio: JsonDecoder.optional(JsonDecoder.string),
+    contentRating: JsonDecoder.optional(JsonDecoder.string),
+    genres: JsonDecoder.optional(
+      JsonDecoder.array(JsonDecoder.string, "ItemDetails.genres"),
+    ),
+    cast: JsonDecoder.optional(
+      JsonDecoder.array(CastMemberDecoder, "ItemDetails.cast"),
+    ),
+    directors: JsonDecoder.optional(
+      JsonDecoder.array(JsonDecoder.string, "ItemDetails.directors"),
+    ),
+    writers: JsonDecoder.optional(
+      JsonDecoder.array(JsonDecoder.string, "ItemDetails.writers"),
+    ),
+    uniqueIds: JsonDecoder.optional(
+      JsonDecoder.dictionary(JsonDecoder.string, "ItemDetails.uniqueIds"),
+    ),
+  },
+  "ItemDetails",
+);
+
 const ShowStateDecoder = JsonDecoder.object<ShowState>(
   {
     id: JsonDecoder.string,
@@ -133,8 +201,11 @@ const ShowStateDecoder = JsonDecoder.object<ShowState>(
     title: JsonDecoder.string,
     year: JsonDecoder.number,
     thumbnail: RelatedFileStateDecoder,
+    fanart: JsonDecoder.optional(RelatedFileStateDecoder),
     lastUpdated: JsonDecoder.number,
     metadata: JsonDecoder.optional(RelatedFileStateDecoder),
+    airDate: JsonDecoder.optional(JsonDecoder.string),
+    details: JsonDecoder.optional(ItemDetailsDecoder),
   },
   "ShowState",
 );
@@ -145,6 +216,8 @@ const SeasonStateDecoder = JsonDecoder.object<SeasonState>(
     title: JsonDecoder.string,
     show: JsonDecoder.string,
     index: JsonDecoder.number,
+    lastUpdated: JsonDecoder.optional(JsonDecoder.number),
+    thumbnail: JsonDecoder.optional(RelatedFileStateDecoder),
   },
   "SeasonState",
 );
@@ -170,11 +243,35 @@ const VideoDetailDecoder = JsonDecoder.oneOf<VideoDetail>(
   "VideoState",
 );
 
+const SubtitlePreferencesDecoder = JsonDecoder.object<SubtitlePreferences>(
+  {
+    languages: JsonDecoder.array(
+      JsonDecoder.string,
+      "SubtitlePreferences.languages",
+    ),
+    forcedOnly: JsonDecoder.boolean,
+    burn: JsonDecoder.boolean,
+  },
+  "SubtitlePreferences",
+);
+
+const SubtitleStateDecoder = JsonDecoder.object<SubtitleState>(
+  {
+    id: JsonDecoder.string,
+    language: JsonDecoder.string,
+    forced: JsonDecoder.boolean,
+    format: JsonDecoder.string,
+    file: RelatedFileStateDecoder,
+  },
+  "SubtitleState",
+);
+
 const VideoStateDecoder = JsonDecoder.object<VideoState>(
   {
     id: JsonDecoder.string,
     title: JsonDecoder.string,
     thumbnail: RelatedFileStateDecoder,
+    fanart: JsonDecoder.optional(RelatedFileStateDecoder),
     airDate: JsonDecoder.optional(JsonDecoder.string),
     mediaId: JsonDecoder.string,
     parts: JsonDecoder.array(VideoPartStateDecoder, "VideoPart[]"),
@@ -183,8 +280,24 @@ const VideoStateDecoder = JsonDecoder.object<VideoState>(
     playbackState: PlaybackStateDecoder,
     lastUpdated: JsonDecoder.number,
     lastViewedAt: JsonDecoder.optional(JsonDecoder.number),
+    playbackHistory: JsonDecoder.optional(
+      JsonDecoder.array(PlaybackEventDecoder, "PlaybackEvent[]"),
+    ),
+    userPlayback: JsonDecoder.optional(
+      JsonDecoder.dictionary(
+        PlaybackProgressDecoder,
+        "VideoState.userPlayback",
+      ),
+    ),
     metadata: JsonDecoder.optional(RelatedFileStateDecoder),
-    download: DownloadStateDecoder,
+    subtitlePreferences: JsonDecoder.optional(SubtitlePreferencesDecoder),
+    subtitles: JsonDecoder.optional(
+      JsonDecoder.array(SubtitleStateDecoder, "SubtitleState[]"),
+    ),
+    audioLanguages: JsonDecoder.optional(
+      JsonDecoder.array(JsonDecoder.string, "VideoState.audioLanguages"),
+    ),
+    details: JsonDecoder.optional(ItemDetailsDecoder),
   },
   "VideoState",
 );
@@ -232,6 +345,12 @@ const ServerStateDecoder = JsonDecoder.object<ServerState>(
     videos: JsonDecoder.optional(
       JsonDecoder.dictionary(VideoStateDecoder, "ServerState.videos"),
     ),
+    resolved: JsonDecoder.optional(
+      JsonDecoder.dictionary(
+        JsonDecoder.array(JsonDecoder.string, "ServerState.resolved[]"),
+        "ServerState.resolved",
+      ),
+    ),
   },
   "ServerState",
 );
@@ -494,13 +494,14 @@ abstract class VideoWrapper<S extends Omit<VideoState, "detail">>
   }
 
   public get download(): DownloadState {
-    return this.state.download;
+    return this.state.parts[0]?.download ?? { state: "none" };
   }
 
   public get isDownloaded(): boolean {
-    return (
-      this.state.download.state == "downloaded" ||
-      this.state.download.state == "transcoded"
+    return this.state.parts.every(
+      (part) =>
+        part.download.state == "downloaded" ||
+        part.download.state == "transcoded",
     );
   }
 